@@ -1,6 +1,6 @@
 use std::{borrow::Cow, collections::HashSet};
 
-use candid::{CandidType, Decode, Encode};
+use candid::{CandidType, Decode, Encode, Principal};
 use ic_stable_structures::Storable;
 use serde::{Deserialize, Serialize};
 
@@ -14,6 +14,14 @@ pub struct Note {
     pub created_at: u64,
     pub updated_at: u64,
     pub author: String,
+    // None for notes stored before tags were added
+    pub tags: Option<Vec<String>>,
+}
+
+impl Note {
+    pub fn tags(&self) -> Vec<String> {
+        self.tags.clone().unwrap_or_default()
+    }
 }
 
 impl Storable for Note {
@@ -129,3 +137,207 @@ impl Storable for UserProfile {
         self.to_bytes().into_owned()
     }
 }
+
+#[derive(CandidType, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NoteSortField {
+    UpdatedAt,
+    CreatedAt,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(CandidType, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NoteVisibility {
+    Published,
+    Private,
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct ListNotesRequest {
+    // Opaque cursor returned as next_cursor by the previous page
+    pub cursor: Option<String>,
+    pub limit: Option<u32>,
+    pub sort_by: Option<NoteSortField>,
+    pub order: Option<SortOrder>,
+    pub visibility: Option<NoteVisibility>,
+    // Bodies are left out unless asked for, fetch them per note instead
+    pub include_content: bool,
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct NoteSummary {
+    pub id: String,
+    pub title: String,
+    pub author: String,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub tags: Vec<String>,
+    pub published: bool,
+    pub content_length: u64,
+    pub content: Option<String>,
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct ListNotesPage {
+    pub notes: Vec<NoteSummary>,
+    pub next_cursor: Option<String>,
+    pub total: u64,
+}
+
+const DEFAULT_NOTES_PAGE_SIZE: u32 = 20;
+const MAX_NOTES_PAGE_SIZE: u32 = 100;
+
+impl ListNotesRequest {
+    pub fn sort_field(&self) -> NoteSortField {
+        self.sort_by.unwrap_or(NoteSortField::UpdatedAt)
+    }
+
+    pub fn sort_order(&self) -> SortOrder {
+        self.order.unwrap_or(SortOrder::Desc)
+    }
+
+    /// The sort timestamp and note id of the last note on the previous page.
+    pub fn cursor_position(&self) -> Result<Option<(u64, String)>, String> {
+        let Some(cursor) = &self.cursor else {
+            return Ok(None);
+        };
+        let (timestamp, note_id) = cursor.split_once(':').ok_or("Invalid cursor".to_string())?;
+        let timestamp = timestamp.parse::<u64>().map_err(|_| "Invalid cursor".to_string())?;
+        Ok(Some((timestamp, note_id.to_string())))
+    }
+
+    /// Cut one page out of `summaries`, which must already be in the requested order and start after the cursor.
+    /// Stops reading after the page is full and loads content only for the returned notes, when asked for.
+    pub fn page(
+        &self,
+        summaries: impl Iterator<Item = NoteSummary>,
+        total: u64,
+        content: impl Fn(&NoteSummary) -> Option<String>,
+    ) -> ListNotesPage {
+        let sort_by = self.sort_field();
+        let limit = self.limit.unwrap_or(DEFAULT_NOTES_PAGE_SIZE).clamp(1, MAX_NOTES_PAGE_SIZE) as usize;
+
+        let mut notes: Vec<NoteSummary> = summaries
+            .filter(|summary| match self.visibility {
+                Some(NoteVisibility::Published) => summary.published,
+                Some(NoteVisibility::Private) => !summary.published,
+                None => true,
+            })
+            .take(limit + 1)
+            .collect();
+        let has_more = notes.len() > limit;
+        notes.truncate(limit);
+        let next_cursor = if has_more {
+            notes.last().map(|summary| format!("{}:{}", summary.sort_timestamp(sort_by), summary.id))
+        } else {
+            None
+        };
+
+        if self.include_content {
+            for summary in notes.iter_mut() {
+                summary.content = content(summary);
+            }
+        }
+
+        ListNotesPage { notes, next_cursor, total }
+    }
+}
+
+impl NoteSummary {
+    /// Metadata of `note`, without its content.
+    pub fn new(note: &Note, published: bool) -> Self {
+        NoteSummary {
+            id: note.id.clone(),
+            title: note.title.clone(),
+            author: note.author.clone(),
+            created_at: note.created_at,
+            updated_at: note.updated_at,
+            tags: note.tags(),
+            published,
+            content_length: note.content.len() as u64,
+            content: None,
+        }
+    }
+
+    pub fn sort_timestamp(&self, sort_by: NoteSortField) -> u64 {
+        match sort_by {
+            NoteSortField::UpdatedAt => self.updated_at,
+            NoteSortField::CreatedAt => self.created_at,
+        }
+    }
+}
+
+// A note's place in one sort order of a notes listing
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct NoteTimeKey {
+    pub timestamp: u64,
+    pub note_id: String,
+}
+
+impl Storable for NoteTimeKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, NoteTimeKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+impl Storable for NoteSummary {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, NoteSummary).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+// Token metadata of a note exported to the dotane_nft collection
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct NoteNftMetadata {
+    pub note_id: String,
+    pub title: String,
+    // hex sha256 of the note content at mint time
+    pub content_hash: String,
+    // principal text of the author who minted it, like Note::author
+    pub author: String,
+    // rendered note page
+    pub url: String,
+    pub minted_at: u64,
+}
+
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct MintNoteNftArgs {
+    pub owner: Principal,
+    pub metadata: NoteNftMetadata,
+}
+
+// Sent by the collection to dotane_ic_backend before a token changes owner.
+// The transfer only goes through if the backend hands the note over.
+#[derive(CandidType, Clone, Debug, Serialize, Deserialize)]
+pub struct NoteNftTransfer {
+    pub token_id: u64,
+    pub note_id: String,
+    pub from: Principal,
+    pub to: Principal,
+}
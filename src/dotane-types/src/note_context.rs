@@ -19,6 +19,9 @@ pub struct NoteTemplateContext {
     /// Optional newsletter configuration (currently commented out in template)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub newsletter: Option<Newsletter>,
+    /// Optional tips received by the note
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tips: Option<NoteTips>,
 }
 
 /// Article/note data structure
@@ -157,6 +160,51 @@ pub struct Newsletter {
     pub action_url: String,
 }
 
+/// Tips received by a note
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NoteTips {
+    /// Number of tips
+    pub count: u64,
+    /// Amount tipped per token
+    pub totals: Vec<TipTotal>,
+}
+
+/// Amount tipped in one token
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TipTotal {
+    /// Token symbol, e.g. ckUSDC
+    pub symbol: String,
+    /// Formatted decimal amount
+    pub amount: String,
+}
+
+/// Context data structure for the tag listing Handlebars template
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TagTemplateContext {
+    /// Tag being listed
+    pub tag: String,
+    /// Public notes carrying the tag, newest first
+    pub notes: Vec<TaggedNote>,
+    /// Site configuration and metadata
+    pub site: Site,
+}
+
+/// Note entry on a tag listing page
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TaggedNote {
+    /// Note ID
+    pub id: String,
+    /// Note title
+    pub title: String,
+    /// Note URL
+    pub url: String,
+    /// Publication date
+    pub published_at: u64,
+    /// Note excerpt/summary
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub excerpt: Option<String>,
+}
+
 /// Helper functions for creating template context
 impl NoteTemplateContext {
     /// Create a new template context with required fields
@@ -168,6 +216,7 @@ impl NoteTemplateContext {
             comments: None,
             related_articles: None,
             newsletter: None,
+            tips: None,
         }
     }
 
@@ -188,6 +237,12 @@ impl NoteTemplateContext {
         self.newsletter = Some(newsletter);
         self
     }
+
+    /// Add tip totals to the context
+    pub fn with_tips(mut self, tips: NoteTips) -> Self {
+        self.tips = Some(tips);
+        self
+    }
 }
 
 /// Helper functions for Article
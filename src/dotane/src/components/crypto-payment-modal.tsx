@@ -218,7 +218,9 @@ export function CryptoPaymentModal({ open, onOpenChange, planType, amount }: Cry
 
     dotaneActor.notify_deposit_premium_payment({
       token_type: tokenType,
-      payment_period: planType === 'monthly' ? { 'Monthly': null } : { 'Yearly': null }
+      payment_period: planType === 'monthly' ? { 'Monthly': null } : { 'Yearly': null },
+      promo_code: [],
+      tier: [],
     }).then((res) => {
         if(res.success) {
             toast.success('Payment processed successfully!')
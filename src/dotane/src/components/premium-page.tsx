@@ -31,6 +31,9 @@ interface PremiumPageProps {
   onBack: () => void
 }
 
+// Fusion Wallet payments get the plans' promo price
+const FUSION_WALLET_PROMO_CODE = 'FUSIONWALLET'
+
 export function PremiumPage({ onBack }: PremiumPageProps) {
   const [showCryptoModal, setShowCryptoModal] = useState(false)
   const [selectedPlan, setSelectedPlan] = useState<'monthly' | 'yearly'>('monthly')
@@ -67,7 +70,7 @@ export function PremiumPage({ onBack }: PremiumPageProps) {
         }
       })
 
-      let payment_result = await dotaneActor.notify_payment_approval(principal, planType === 'monthly' ? { 'Monthly': null } : { 'Yearly': null })
+      let payment_result = await dotaneActor.notify_payment_approval(principal, planType === 'monthly' ? { 'Monthly': null } : { 'Yearly': null }, [FUSION_WALLET_PROMO_CODE], [])
 
       if(payment_result.success) {
         toast.success('Payment processed successfully!')
@@ -23,6 +23,10 @@ fn main() {
         panic!("Error: CANISTER_ID_DOTANE_ASSET_STORAGE environment variable not set. Build cannot continue.");
     }
     println!("cargo:rustc-env=CANISTER_ID_DOTANE_ASSET_STORAGE={}", asset_storage_canister_id.unwrap());
+    // the note collection is optional, the backend builds before it is deployed
+    if let Ok(nft_canister_id) = env::var("CANISTER_ID_DOTANE_NFT") {
+        println!("cargo:rustc-env=CANISTER_ID_DOTANE_NFT={}", nft_canister_id);
+    }
 
     let network = std::env::var("DFX_NETWORK").unwrap_or("local".to_string());
 
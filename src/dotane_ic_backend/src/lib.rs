@@ -1,21 +1,35 @@
 use candid::{encode_args, Nat, Principal};
 // use canister_http_router::{CallType, CanisterRouter, CanisterRouterContext, HttpRequest, HttpResponse};
-use dotane_types::{note_context::{Article, Author, NoteTemplateContext, Site}, AccessType, ListNotesResponse, Note, PublishedNote, UserProfile};
-use handlebars::{ Handlebars};
-use ic_cdk::{api::{canister_self,time}, management_canister::{create_canister, install_code, raw_rand, CanisterSettings, CreateCanisterArgs, InstallCodeArgs}, pre_upgrade};
+use dotane_types::{note_context::{Article, Author, NoteTemplateContext, NoteTips, Site, TagTemplateContext, TaggedNote, TipTotal}, AccessType, ListNotesPage, ListNotesRequest, ListNotesResponse, MintNoteNftArgs, Note, NoteNftMetadata, NoteNftTransfer, NoteSortField, NoteSummary, NoteVisibility, PublishedNote, SortOrder, RestrictedAccessNotes, UserProfile};
+use handlebars::{ html_escape, Handlebars};
+use ic_cdk::{api::{canister_self,time}, management_canister::{canister_status, create_canister_with_extra_cycles, deposit_cycles, http_request as outcall_http_request, install_code, raw_rand, start_canister, stop_canister, transform_context_from_query, CanisterInstallMode, CanisterSettings, CanisterStatusArgs, CreateCanisterArgs, DepositCyclesArgs, HttpMethod, HttpRequestArgs, HttpRequestResult, InstallCodeArgs, StartCanisterArgs, StopCanisterArgs, TransformArgs}, pre_upgrade};
 use ic_http_certification::{HttpRequest, Method};
-use icrc_ledger_types::{icrc1::{account::{principal_to_subaccount, Account, Subaccount}, transfer::{TransferArg, TransferError}}, icrc2::transfer_from::{TransferFromArgs, TransferFromError}};
+use ic_cdk_timers::TimerId;
+use icrc_ledger_types::{icrc1::{account::{principal_to_subaccount, Account, Subaccount}, transfer::{Memo, TransferArg, TransferError}}, icrc2::{allowance::{Allowance, AllowanceArgs}, transfer_from::{TransferFromArgs, TransferFromError}}};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory}, BTreeSet, DefaultMemoryImpl, StableBTreeMap, StableCell
 };
 
+use sha2::{Digest, Sha256};
 use std::{
-    cell::RefCell, cmp::Reverse, collections::{BinaryHeap, HashMap}, time::Duration
+    cell::{Cell, RefCell}, collections::{HashMap, HashSet}, hash::Hash, ops::Bound, thread::LocalKey, time::Duration
 };
 
 use crate::types::{
     CreateUserProfileRequest, SessionData, UpdateUserProfileRequest, UserCanister, UserNotes, Workspace,
-    PremiumPaymentRequest, PremiumPaymentResponse, TokenType, PaymentPeriod
+    PremiumPaymentRequest, PremiumPaymentResponse, TokenType, PaymentPeriod, WorkspaceDeployment, WorkspaceDeploymentStage,
+    WorkspaceUpgradeRollout, WorkspaceUpgradeState, WorkspaceUpgradeStatus, WorkspaceWasm,
+    CyclesBudget, CyclesBudgetInfo, WorkspaceCyclesKey, WorkspaceCyclesSample, DomainStatus, WorkspaceDomain,
+    NoteRevision, NoteRevisionKey, NoteRevisionSummary, IndexedNote, SearchNotesResponse, SearchPostingKey, SearchResult,
+    TagNoteKey, NoteAccessLink, NoteGuests, OwnerNoteKey, OwnerNoteTimeKey, EntitlementExpiryKey, EntitlementSource, EntitlementTier,
+    PremiumEntitlement, PaymentKind, PaymentRecord, PaymentState, PayerPaymentKey, RevenueReport, RevenueTotal,
+    InvoiceTemplateContext, DepositWatch, PlanPrice, PricingConfig, TokenConfig, PriceQuote, PromoCode, PromoCodeConfig,
+    PromoDiscount, PromoRedemption, PromoRedemptionKey, PromoRedemptionReport, PromoUseKey, EntitlementTable, PlanUsage,
+    TierLimits, Subscription, SubscriptionStatus, GiftCode, GiftCodeStatus, PaymentPurchase, Seat, SeatKey, SeatPool,
+    SeatPoolView, Referral, ReferralConfig, ReferralReward, ReferralStats, ReferralStatus, ReferrerKey,
+    Tip, TipConfig, TipSummary, NoteTipKey, AuthorTipKey, PaidNoteConfig, NoteSale, NoteSalesSummary, BuyerNoteKey,
+    AuthorSaleKey, LedgerBalance, LedgerBalanceKey, LedgerEntry, LedgerEntryKind, LedgerInvariant, LedgerStatement,
+    OwnerLedgerEntryKey
 };
 
 mod types;
@@ -24,7 +38,11 @@ const WORKSPACE_WASM: &[u8] = include_bytes!("../../../bin/dotane_user_storage.w
 // const ASSET_STORAGE_WASM: &[u8] = include_bytes!("../../../bin/dotane_asset_storage.wasm");
 const NOTE_TEMPLATE: &str = include_str!("../../dotane_landing/out/note.hbs.html");
 const NOT_FOUND_TEMPLATE: &str = include_str!("../../dotane_landing/out/404.html");
+const TAG_TEMPLATE: &str = include_str!("../templates/tag.hbs.html");
+const INVOICE_TEMPLATE: &str = include_str!("../templates/invoice.hbs.html");
 const ASSET_STORAGE_CANISTER_ID: &str = env!("CANISTER_ID_DOTANE_ASSET_STORAGE");
+// Unset until the note collection is deployed, minting is refused until then
+const NFT_CANISTER_ID: Option<&str> = option_env!("CANISTER_ID_DOTANE_NFT");
 
 // Define memory type
 type Memory = VirtualMemory<DefaultMemoryImpl>;
@@ -62,7 +80,8 @@ thread_local! {
         )
     );
 
-    // Stable storage for user notes mapping (Principal -> UserNotes)
+    // Legacy per-user notebooks (Principal -> UserNotes), drained into PRIVATE_NOTES and
+    // USER_PUBLISHED_NOTES by migrate_user_notes
     static USER_NOTES: RefCell<StableBTreeMap<Principal, UserNotes, Memory>> = RefCell::new(
         StableBTreeMap::init(
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
@@ -92,15 +111,410 @@ thread_local! {
 
     static ASSET_STORAGE_CANISTER: RefCell<Principal> = RefCell::new(Principal::from_text(ASSET_STORAGE_CANISTER_ID).expect("Failed to parse asset storage canister ID"));
 
+    // Legacy expiry -> principal map, drained into PREMIUM_ENTITLEMENTS by migrate_premium_expirations
     static EXPIRATION_MAP: RefCell<StableBTreeMap<u64, Principal, Memory>> = RefCell::new(StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10)))));
 
     static AI_SESSIONS: RefCell<HashMap<String, SessionData>> = RefCell::new(HashMap::new());
 
     static SESSION_USERS: RefCell<HashMap<Principal, String>> = RefCell::new(HashMap::new());
 
-    static PREMIUM_EXPIRATION_HEAP: RefCell<BinaryHeap<Reverse<u64>>> = RefCell::new(BinaryHeap::new());
+    // Pending one-shot timer for the next entitlement expiry, re-armed by init after upgrades
+    static ENTITLEMENT_TIMER: Cell<Option<TimerId>> = Cell::new(None);
 
+    // In-flight or failed workspace deployments, resumed by calling deploy_workspace again
+    static WORKSPACE_DEPLOYMENTS: RefCell<StableBTreeMap<Principal, WorkspaceDeployment, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        )
+    );
+
+    // Callers with a deploy_workspace call currently awaiting, so two calls can't race on the same deployment
+    static DEPLOYING_USERS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new());
+
+    // Per-workspace upgrade status for the current (or last) rollout
+    static WORKSPACE_UPGRADES: RefCell<StableBTreeMap<Principal, WorkspaceUpgradeStatus, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+    );
+
+    static WORKSPACE_UPGRADE_ROLLOUT: RefCell<StableCell<WorkspaceUpgradeRollout, Memory>> = RefCell::new(
+        StableCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))), WorkspaceUpgradeRollout::default())
+    );
+
+    // Workspace wasm modules by sha256, kept so upgraded workspaces can be rolled back
+    static WORKSPACE_WASMS: RefCell<StableBTreeMap<String, WorkspaceWasm, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+        )
+    );
+
+    // Cycle balance samples per workspace, oldest first
+    static WORKSPACE_CYCLES_HISTORY: RefCell<StableBTreeMap<WorkspaceCyclesKey, WorkspaceCyclesSample, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+        )
+    );
+
+    // Cycles each owner has spent topping up their workspaces this period
+    static CYCLES_BUDGETS: RefCell<StableBTreeMap<Principal, CyclesBudget, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+        )
+    );
+
+    static MONITORING_CYCLES: Cell<bool> = Cell::new(false);
+
+    // Custom domains attached to workspaces, keyed by domain name
+    static WORKSPACE_DOMAINS: RefCell<StableBTreeMap<String, WorkspaceDomain, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+        )
+    );
+
+    // Previous versions of each note, bounded to MAX_NOTE_REVISIONS per note
+    static NOTE_REVISIONS: RefCell<StableBTreeMap<NoteRevisionKey, NoteRevision, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+        )
+    );
+
+    // Inverted index over public notes: (term, note_id) -> term weight
+    static SEARCH_POSTINGS: RefCell<StableBTreeMap<SearchPostingKey, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+        )
+    );
+
+    // Terms each indexed note was stored under, so its postings can be removed
+    static INDEXED_NOTES: RefCell<StableBTreeMap<String, IndexedNote, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+        )
+    );
+
+    // Public notes by tag: (tag, note_id) -> note created_at
+    static TAG_NOTES: RefCell<StableBTreeMap<TagNoteKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+        )
+    );
+
+    // Access links for restricted notes: token -> link
+    static NOTE_ACCESS_LINKS: RefCell<StableBTreeMap<String, NoteAccessLink, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+        )
+    );
+
+    // Private notes, one entry per (owner, note_id)
+    static PRIVATE_NOTES: RefCell<StableBTreeMap<OwnerNoteKey, Note, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+        )
+    );
+
+    // Owner index over published notes in NOTES
+    static USER_PUBLISHED_NOTES: RefCell<BTreeSet<OwnerNoteKey, Memory>> = RefCell::new(
+        BTreeSet::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(24))),
+        )
+    );
+
+    // Premium entitlement per principal, kept after expiry as the last known period
+    static PREMIUM_ENTITLEMENTS: RefCell<StableBTreeMap<Principal, PremiumEntitlement, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(25))),
+        )
+    );
+
+    // Active entitlements ordered by end time
+    static ENTITLEMENT_EXPIRIES: RefCell<BTreeSet<EntitlementExpiryKey, Memory>> = RefCell::new(
+        BTreeSet::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(26))),
+        )
+    );
+
+    // Payment journal, every premium payment attempt by id
+    static PAYMENTS: RefCell<StableBTreeMap<u64, PaymentRecord, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(27))),
+        )
+    );
+
+    static NEXT_PAYMENT_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(28))), 0)
+    );
+
+    // Ids of payments that are not granted, failed or refunded yet
+    static OPEN_PAYMENTS: RefCell<BTreeSet<u64, Memory>> = RefCell::new(
+        BTreeSet::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(29))),
+        )
+    );
+
+    static SETTLING_PAYMENTS: RefCell<HashSet<u64>> = RefCell::new(HashSet::new());
+
+    // Payment ids by payer
+    static PAYER_PAYMENTS: RefCell<BTreeSet<PayerPaymentKey, Memory>> = RefCell::new(
+        BTreeSet::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(30))),
+        )
+    );
+
+    // Deposit subaccounts polled by check_deposits, by payer
+    static DEPOSIT_WATCHES: RefCell<StableBTreeMap<Principal, DepositWatch, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(31))),
+        )
+    );
+
+    static CHECKING_DEPOSITS: Cell<bool> = Cell::new(false);
+
+    // Plans, prices and accepted tokens, changed by controllers at runtime
+    static PRICING_CONFIG: RefCell<StableCell<PricingConfig, Memory>> = RefCell::new(
+        StableCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(32))), default_pricing_config())
+    );
+
+    static PROMO_CODES: RefCell<StableBTreeMap<String, PromoCode, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(33))),
+        )
+    );
+
+    // Redemptions by code, dropped again when their payment fails or is refunded
+    static PROMO_REDEMPTIONS: RefCell<StableBTreeMap<PromoRedemptionKey, PromoRedemption, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(34))),
+        )
+    );
+
+    // Redemption count per code and principal
+    static PROMO_USES: RefCell<StableBTreeMap<PromoUseKey, u32, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(35))),
+        )
+    );
+
+    // What each tier may use, changed by controllers at runtime
+    static ENTITLEMENT_TABLE: RefCell<StableCell<EntitlementTable, Memory>> = RefCell::new(
+        StableCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(36))), default_entitlement_table())
+    );
+
+    // Auto-renewing subscriptions by beneficiary
+    static SUBSCRIPTIONS: RefCell<StableBTreeMap<Principal, Subscription, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(37))),
+        )
+    );
+
+    static RENEWING_SUBSCRIPTIONS: Cell<bool> = Cell::new(false);
+
+    static GIFT_CODES: RefCell<StableBTreeMap<String, GiftCode, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(38))),
+        )
+    );
+
+    static SEAT_POOLS: RefCell<StableBTreeMap<u64, SeatPool, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(39))),
+        )
+    );
+
+    static NEXT_SEAT_POOL_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(40))), 1)
+    );
+
+    static SEATS: RefCell<StableBTreeMap<SeatKey, Seat, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(41))),
+        )
+    );
+
+    // The seat each principal holds, at most one
+    static SEAT_ASSIGNMENTS: RefCell<StableBTreeMap<Principal, SeatKey, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(42))),
+        )
+    );
+
+    // Referral code to referrer
+    static REFERRAL_CODES: RefCell<StableBTreeMap<String, Principal, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(43))),
+        )
+    );
+
+    static REFERRER_CODES: RefCell<StableBTreeMap<Principal, String, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(44))),
+        )
+    );
+
+    // Referrals by referee, each referee can only be referred once
+    static REFERRALS: RefCell<StableBTreeMap<Principal, Referral, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(45))),
+        )
+    );
+
+    static REFERRER_REFERRALS: RefCell<BTreeSet<ReferrerKey, Memory>> = RefCell::new(
+        BTreeSet::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(46))),
+        )
+    );
+
+    static REFERRAL_CONFIG: RefCell<StableCell<ReferralConfig, Memory>> = RefCell::new(
+        StableCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(47))), default_referral_config())
+    );
+
+    static TIP_CONFIG: RefCell<StableCell<TipConfig, Memory>> = RefCell::new(
+        StableCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(48))), default_tip_config())
+    );
+
+    // tipper payment id -> tip
+    static TIPS: RefCell<StableBTreeMap<u64, Tip, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(49))),
+        )
+    );
+
+    static NOTE_TIPS: RefCell<BTreeSet<NoteTipKey, Memory>> = RefCell::new(
+        BTreeSet::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(50))),
+        )
+    );
+
+    static AUTHOR_TIPS: RefCell<BTreeSet<AuthorTipKey, Memory>> = RefCell::new(
+        BTreeSet::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(51))),
+        )
+    );
+
+    static PAID_NOTE_CONFIG: RefCell<StableCell<PaidNoteConfig, Memory>> = RefCell::new(
+        StableCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(52))), default_paid_note_config())
+    );
+
+    // note id -> price in plan price units
+    static NOTE_PRICES: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(53))),
+        )
+    );
+
+    // buyer payment id -> sale
+    static NOTE_SALES: RefCell<StableBTreeMap<u64, NoteSale, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(54))),
+        )
+    );
+
+    // notes a buyer has access to -> the sale granting it
+    static BUYER_NOTES: RefCell<StableBTreeMap<BuyerNoteKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(55))),
+        )
+    );
+
+    static AUTHOR_SALES: RefCell<BTreeSet<AuthorSaleKey, Memory>> = RefCell::new(
+        BTreeSet::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(56))),
+        )
+    );
+
+    // Creator ledger: balances owed to creators and the platform, held in the system account
+    static LEDGER_ENTRIES: RefCell<StableBTreeMap<u64, LedgerEntry, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(57))),
+        )
+    );
+
+    static NEXT_LEDGER_ENTRY_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(58))), 1)
+    );
+
+    static OWNER_LEDGER_ENTRIES: RefCell<BTreeSet<OwnerLedgerEntryKey, Memory>> = RefCell::new(
+        BTreeSet::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(59))),
+        )
+    );
+
+    static LEDGER_BALANCES: RefCell<StableBTreeMap<LedgerBalanceKey, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(60))),
+        )
+    );
+
+    // note id -> token id in the note collection
+    static NOTE_NFTS: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(61))),
+        )
+    );
+
+    // Metadata of each owner's published and private notes, so pages are listed without loading bodies
+    static NOTE_LISTINGS: RefCell<StableBTreeMap<OwnerNoteKey, NoteSummary, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(62))),
+        )
+    );
+
+    // The same notes in updated_at and created_at order
+    static NOTES_BY_UPDATED: RefCell<BTreeSet<OwnerNoteTimeKey, Memory>> = RefCell::new(
+        BTreeSet::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(63))),
+        )
+    );
+
+    static NOTES_BY_CREATED: RefCell<BTreeSet<OwnerNoteTimeKey, Memory>> = RefCell::new(
+        BTreeSet::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(64))),
+        )
+    );
+
+    static MINTING_NOTES: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+
+
+}
+
+// Marks a key in one of the heap lock sets until dropped. ic-cdk drops a suspended call's future
+// when its callback traps, so the key can't stay locked until the next upgrade.
+struct HeapLock<K: Eq + Hash + Clone + 'static> {
+    locks: &'static LocalKey<RefCell<HashSet<K>>>,
+    key: K,
+}
 
+impl<K: Eq + Hash + Clone + 'static> HeapLock<K> {
+    // None while another call holds the key
+    fn acquire(locks: &'static LocalKey<RefCell<HashSet<K>>>, key: K) -> Option<Self> {
+        let acquired = locks.with_borrow_mut(|held| held.insert(key.clone()));
+        acquired.then(|| HeapLock { locks, key })
+    }
+}
+
+impl<K: Eq + Hash + Clone + 'static> Drop for HeapLock<K> {
+    fn drop(&mut self) {
+        self.locks.with_borrow_mut(|held| {
+            held.remove(&self.key);
+        });
+    }
+}
+
+// Same for a single flag, used by the timer jobs that must not overlap
+struct FlagLock(&'static LocalKey<Cell<bool>>);
+
+impl FlagLock {
+    // None while the flag is already set
+    fn acquire(flag: &'static LocalKey<Cell<bool>>) -> Option<Self> {
+        (!flag.replace(true)).then(|| FlagLock(flag))
+    }
+}
+
+impl Drop for FlagLock {
+    fn drop(&mut self) {
+        self.0.set(false);
+    }
 }
 
 // Helper functions
@@ -126,17 +540,126 @@ fn is_authenticated() -> Result<(), String> {
     return Ok(());
 }
 
-fn is_premium_user() -> Result<(), String> {
-    is_authenticated()?;
-    let caller = ic_cdk::api::msg_caller();
-    let user_profile = USER_PROFILES.with(|profiles| profiles.borrow().get(&caller));
-    if let Some(user_profile) = user_profile {
-        if !user_profile.premium {
-            return Err("Unauthorized".to_string());
+const GIB: u64 = 1024 * 1024 * 1024;
+
+// Limits used until a controller changes them
+fn default_entitlement_table() -> EntitlementTable {
+    EntitlementTable {
+        tiers: vec![
+            TierLimits {
+                tier: EntitlementTier::Free,
+                private_notes: Some(0),
+                storage_bytes: Some(0),
+                ai_queries_per_session: Some(15),
+                workspaces: Some(1),
+            },
+            TierLimits {
+                tier: EntitlementTier::Pro,
+                private_notes: None,
+                storage_bytes: Some(5 * GIB),
+                ai_queries_per_session: None,
+                workspaces: Some(3),
+            },
+            TierLimits {
+                tier: EntitlementTier::Team,
+                private_notes: None,
+                storage_bytes: Some(50 * GIB),
+                ai_queries_per_session: None,
+                workspaces: Some(10),
+            },
+        ],
+    }
+}
+
+// The higher of the principal's own entitlement and any team seat they hold, Free without either
+fn user_tier(principal: &Principal) -> EntitlementTier {
+    let now = get_current_time_in_milli();
+    let own_tier = PREMIUM_ENTITLEMENTS
+        .with_borrow(|entitlements| entitlements.get(principal))
+        .filter(|entitlement| entitlement.is_active(now))
+        .map(|entitlement| entitlement.tier.normalized())
+        .unwrap_or(EntitlementTier::Free);
+    let seat_tier = SEAT_ASSIGNMENTS
+        .with_borrow(|assignments| assignments.get(principal))
+        .filter(|key| SEATS.with_borrow(|seats| seats.get(key)).is_some_and(|seat| seat.is_active(now)))
+        .and_then(|key| SEAT_POOLS.with_borrow(|pools| pools.get(&key.pool_id)))
+        .map(|pool| pool.tier.normalized())
+        .unwrap_or(EntitlementTier::Free);
+    if seat_tier.rank() > own_tier.rank() {
+        seat_tier
+    } else {
+        own_tier
+    }
+}
+
+fn tier_limits(tier: &EntitlementTier) -> TierLimits {
+    let tier = tier.normalized();
+    ENTITLEMENT_TABLE
+        .with_borrow(|table| table.get().tiers.iter().find(|limits| limits.tier == tier).cloned())
+        .or_else(|| default_entitlement_table().tiers.into_iter().find(|limits| limits.tier == tier))
+        .expect("Every tier has default limits")
+}
+
+fn user_limits(principal: &Principal) -> TierLimits {
+    tier_limits(&user_tier(principal))
+}
+
+fn private_note_count(owner: &Principal) -> u64 {
+    PRIVATE_NOTES.with_borrow(|private_notes| {
+        private_notes
+            .range(owner_note_key(owner, &String::new())..)
+            .take_while(|entry| entry.key().owner == *owner)
+            .count() as u64
+    })
+}
+
+fn workspace_count(owner: &Principal) -> u32 {
+    USER_CANISTERS
+        .with_borrow(|canisters| canisters.get(owner))
+        .map(|canisters| canisters.user_canisters.len() as u32)
+        .unwrap_or_default()
+}
+
+fn ensure_private_note_quota(owner: &Principal) -> Result<(), String> {
+    let limits = user_limits(owner);
+    match limits.private_notes {
+        Some(0) => Err(format!("Private notes are not included in the {:?} plan", limits.tier)),
+        Some(max) if private_note_count(owner) >= max => {
+            Err(format!("The {:?} plan allows up to {} private notes", limits.tier, max))
         }
-        return Ok(());
+        _ => Ok(()),
     }
-    return Err("Unauthorized".to_string());
+}
+
+fn ensure_workspace_quota(owner: &Principal) -> Result<(), String> {
+    let limits = user_limits(owner);
+    match limits.workspaces {
+        Some(max) if workspace_count(owner) >= max => {
+            Err(format!("The {:?} plan allows up to {} workspaces", limits.tier, max))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Grant or revoke asset storage access, and set the byte quota, to match the principal's storage entitlement
+async fn sync_asset_storage_access(principal: Principal) -> Result<(), String> {
+    let storage_bytes = user_limits(&principal).storage_bytes;
+    if storage_bytes == Some(0) {
+        return deauthorize_user_in_asset_storage(principal).await;
+    }
+    // the quota goes first so a new grant never starts out unlimited
+    set_asset_storage_quota(principal, storage_bytes).await?;
+    authorize_user_in_asset_storage(principal).await
+}
+
+async fn set_asset_storage_quota(user_principal: Principal, max_bytes: Option<u64>) -> Result<(), String> {
+    let asset_storage_canister = ASSET_STORAGE_CANISTER.with(|canister| *canister.borrow());
+    ic_cdk::call::Call::unbounded_wait(asset_storage_canister, "set_byte_quota")
+        .with_args(&(user_principal, max_bytes))
+        .await
+        .map_err(|e| format!("Failed to call set_byte_quota: {:?}", e))?
+        .candid::<()>()
+        .map_err(|e| format!("Failed to decode set_byte_quota response: {:?}", e))
 }
 
 // Helper function to authorize user in asset storage canister
@@ -147,7 +670,7 @@ async fn authorize_user_in_asset_storage(user_principal: Principal) -> Result<()
     let result: Result<(), String> = ic_cdk::call::Call::unbounded_wait(asset_storage_canister, "authorize")
         .with_arg(user_principal)
         .await
-        .expect("Failed to call authorize")
+        .map_err(|e| format!("Failed to call authorize: {:?}", e))?
         .candid::<()>()
         .map_err(|e| format!("Failed to decode authorize response: {:?}", e));
     
@@ -165,7 +688,7 @@ async fn deauthorize_user_in_asset_storage(user_principal: Principal) -> Result<
     let result: Result<(), String> = ic_cdk::call::Call::unbounded_wait(asset_storage_canister, "deauthorize")
         .with_arg(user_principal)
         .await
-        .expect("Failed to call deauthorize")
+        .map_err(|e| format!("Failed to call deauthorize: {:?}", e))?
         .candid::<()>()
         .map_err(|e| format!("Failed to decode deauthorize response: {:?}", e));
     
@@ -175,43 +698,190 @@ async fn deauthorize_user_in_asset_storage(user_principal: Principal) -> Result<
     }
 }
 
-fn check_premium_expiration() {
-    let current_time = get_current_time_in_milli();
-   PREMIUM_EXPIRATION_HEAP.with_borrow_mut(|heap| {
-    while let Some(Reverse(expiration_time)) = heap.pop() {
-        if expiration_time <= current_time {
-            let user = EXPIRATION_MAP.with_borrow_mut(|map| map.remove(&expiration_time));
-            if let Some(user) = user {
-
-                USER_PROFILES.with_borrow_mut(|profiles| {
-                    if let Some(mut profile) = profiles.get(&user) {
-                        profile.premium = false;
-                    }
-                });
-
-                PREMIUM_USERS_SET.with_borrow_mut(|set| {
-                    set.remove(&user);
-                });
-                
-                // Deauthorize user from asset storage canister
-                ic_cdk::futures::spawn(async move {
-                    if let Err(e) = deauthorize_user_in_asset_storage(user).await {
-                        ic_cdk::api::debug_print(&format!("Failed to deauthorize user {} from asset storage: {}", user, e));
-                    }
-                });
-            }
+// Apply a paid period of `tier` to `principal`. Active entitlements are extended from their
+// current end and keep the higher of the two tiers, lapsed ones start again from now.
+fn grant_premium_entitlement(principal: Principal, tier: EntitlementTier, duration: u64, source: EntitlementSource) -> PremiumEntitlement {
+    let now = get_current_time_in_milli();
+    let tier = tier.normalized();
+    let previous = PREMIUM_ENTITLEMENTS.with_borrow(|entitlements| entitlements.get(&principal));
+    let entitlement = match &previous {
+        Some(previous) if previous.is_active(now) => PremiumEntitlement {
+            tier: if previous.tier.rank() > tier.rank() { previous.tier.normalized() } else { tier },
+            start: previous.start,
+            end: previous.end.saturating_add(duration),
+            source,
+            updated_at: now,
+        },
+        _ => PremiumEntitlement {
+            tier,
+            start: now,
+            end: now.saturating_add(duration),
+            source,
+            updated_at: now,
+        },
+    };
+
+    ENTITLEMENT_EXPIRIES.with_borrow_mut(|expiries| {
+        if let Some(previous) = &previous {
+            expiries.remove(&EntitlementExpiryKey { end: previous.end, principal });
+        }
+        expiries.insert(EntitlementExpiryKey { end: entitlement.end, principal });
+    });
+    PREMIUM_ENTITLEMENTS.with_borrow_mut(|entitlements| entitlements.insert(principal, entitlement.clone()));
+    set_premium_status(principal, true);
+    schedule_entitlement_expiry();
+
+    entitlement
+}
+
+// Recompute PREMIUM_USERS_SET membership from entitlements and seats, returns whether the principal is premium
+fn refresh_premium_status(principal: Principal) -> bool {
+    let premium = user_tier(&principal) != EntitlementTier::Free;
+    set_premium_status(principal, premium);
+    premium
+}
+
+// Keep PREMIUM_USERS_SET and UserProfile.premium in step with the entitlement
+fn set_premium_status(principal: Principal, premium: bool) {
+    PREMIUM_USERS_SET.with_borrow_mut(|set| {
+        if premium {
+            set.insert(principal);
         } else {
-            heap.push(Reverse(expiration_time));
-            break;
+            set.remove(&principal);
+        }
+    });
+    USER_PROFILES.with_borrow_mut(|profiles| {
+        if let Some(mut profile) = profiles.get(&principal) {
+            if profile.premium != premium {
+                profile.premium = premium;
+                profile.updated_at = get_current_time_in_milli();
+                profiles.insert(principal, profile);
+            }
+        }
+    });
+}
+
+fn expire_premium_entitlements() {
+    let now = get_current_time_in_milli();
+    let expired: Vec<EntitlementExpiryKey> = ENTITLEMENT_EXPIRIES.with_borrow(|expiries| {
+        expiries.iter().take_while(|key| key.end <= now).collect()
+    });
+
+    for key in expired {
+        ENTITLEMENT_EXPIRIES.with_borrow_mut(|expiries| expiries.remove(&key));
+        // renewed, or still covered by another entitlement or a team seat
+        let user = key.principal;
+        if refresh_premium_status(user) {
+            continue;
+        }
+
+        // Back on the Free tier's storage entitlement
+        ic_cdk::futures::spawn(async move {
+            if let Err(e) = sync_asset_storage_access(user).await {
+                ic_cdk::api::debug_print(&format!("Failed to update asset storage access for {}: {}", user, e));
+            }
+        });
+    }
+
+    schedule_entitlement_expiry();
+}
+
+// Arm a one-shot timer for the earliest end in ENTITLEMENT_EXPIRIES, replacing any pending one
+fn schedule_entitlement_expiry() {
+    if let Some(timer) = ENTITLEMENT_TIMER.with(|timer| timer.take()) {
+        ic_cdk_timers::clear_timer(timer);
+    }
+    let next = ENTITLEMENT_EXPIRIES.with_borrow(|expiries| expiries.iter().next());
+    if let Some(next) = next {
+        let delay = next.end.saturating_sub(get_current_time_in_milli());
+        let timer = ic_cdk_timers::set_timer(Duration::from_millis(delay), expire_premium_entitlements);
+        ENTITLEMENT_TIMER.with(|timer_ref| timer_ref.set(Some(timer)));
+    }
+}
+
+// Turn the old EXPIRATION_MAP entries into entitlement records. No-op once it is empty.
+fn migrate_premium_expirations() {
+    let legacy: Vec<(u64, Principal)> = EXPIRATION_MAP.with_borrow(|map| {
+        map.iter().map(|entry| (*entry.key(), entry.value())).collect()
+    });
+    if legacy.is_empty() {
+        return;
+    }
+
+    let now = get_current_time_in_milli();
+    for (end, principal) in legacy {
+        if PREMIUM_ENTITLEMENTS.with_borrow(|entitlements| entitlements.contains_key(&principal)) {
+            continue;
         }
+        PREMIUM_ENTITLEMENTS.with_borrow_mut(|entitlements| {
+            entitlements.insert(principal, PremiumEntitlement {
+                tier: EntitlementTier::Premium,
+                start: end.min(now),
+                end,
+                source: EntitlementSource::Legacy,
+                updated_at: now,
+            });
+        });
+        ENTITLEMENT_EXPIRIES.with_borrow_mut(|expiries| expiries.insert(EntitlementExpiryKey { end, principal }));
+    }
+    EXPIRATION_MAP.with_borrow_mut(|map| map.clear_new());
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_entitlement() -> Option<PremiumEntitlement> {
+    let caller = ic_cdk::api::msg_caller();
+    PREMIUM_ENTITLEMENTS.with_borrow(|entitlements| entitlements.get(&caller))
+}
+
+/// The caller's tier, its limits and how much of them is in use.
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_plan() -> PlanUsage {
+    let caller = ic_cdk::api::msg_caller();
+    let tier = user_tier(&caller);
+    PlanUsage {
+        limits: tier_limits(&tier),
+        tier,
+        entitlement: PREMIUM_ENTITLEMENTS.with_borrow(|entitlements| entitlements.get(&caller)),
+        private_notes: private_note_count(&caller),
+        workspaces: workspace_count(&caller),
     }
-   });
 }
+
+#[ic_cdk::query]
+fn get_tier_limits() -> Vec<TierLimits> {
+    ENTITLEMENT_TABLE.with_borrow(|table| table.get().tiers.clone())
+}
+
+/// Replace one tier's limits. Lowering a limit does not remove anything already over it.
+#[ic_cdk::update(guard = "is_controller")]
+fn set_tier_limits(mut limits: TierLimits) -> Result<(), String> {
+    limits.tier = limits.tier.normalized();
+    ENTITLEMENT_TABLE.with_borrow_mut(|cell| {
+        let mut table = cell.get().clone();
+        match table.tiers.iter_mut().find(|existing| existing.tier == limits.tier) {
+            Some(existing) => *existing = limits,
+            None => table.tiers.push(limits),
+        }
+        cell.set(table);
+    });
+    Ok(())
+}
+
 #[ic_cdk::init]
-fn init() {
+fn init(pricing: Option<PricingConfig>) {
+    // staging passes its own ledgers and prices, otherwise the stored config is kept
+    if let Some(pricing) = pricing {
+        if let Err(e) = validate_pricing_config(&pricing) {
+            ic_cdk::trap(&format!("Invalid pricing config: {}", e));
+        }
+        PRICING_CONFIG.with_borrow_mut(|config| config.set(pricing));
+    }
+
     setup_asset_server();
     setup_handlebars();
     setup_assets();
+    setup_search_index();
+    setup_tag_pages();
     ic_asset_server::add_asset(ic_asset_server::types::Asset {
         path: "/.well-known/ic-domains".to_string(),
         content: r#"
@@ -228,15 +898,17 @@ fn init() {
     });
 
 
-    EXPIRATION_MAP.with_borrow_mut(|map| {
-        for key in map.keys(){
-            PREMIUM_EXPIRATION_HEAP.with_borrow_mut(|heap| {
-                heap.push(Reverse(key));
-            });
-        }
-    });
+    migrate_premium_expirations();
+    setup_default_promo_codes();
+    schedule_entitlement_expiry();
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(PAYMENT_RECONCILE_INTERVAL_SECS), reconcile_payments);
+    setup_payment_history();
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(DEPOSIT_CHECK_INTERVAL_SECS), check_deposits);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(RENEWAL_CHECK_INTERVAL_SECS), process_renewals);
 
-    ic_cdk_timers::set_timer_interval(Duration::from_secs(24 * 60 * 60), check_premium_expiration);
+    setup_workspace_wasms();
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(WORKSPACE_UPGRADE_INTERVAL_SECS), process_workspace_upgrades);
+    ic_cdk_timers::set_timer_interval(Duration::from_secs(WORKSPACE_CYCLES_CHECK_INTERVAL_SECS), check_workspace_cycles);
 }
 
 fn setup_asset_server() {
@@ -260,14 +932,23 @@ fn clear_premium_users() {
         map.clear_new();
     });
 
-    PREMIUM_EXPIRATION_HEAP.with_borrow_mut(|heap| {
-        heap.clear();
+    PREMIUM_ENTITLEMENTS.with_borrow_mut(|entitlements| {
+        entitlements.clear_new();
+    });
+
+    ENTITLEMENT_EXPIRIES.with_borrow_mut(|expiries| {
+        expiries.clear();
     });
+
+    schedule_entitlement_expiry();
 }
 
 #[ic_cdk::post_upgrade]
-fn post_upgrade() {
-    init();
+fn post_upgrade(pricing: Option<PricingConfig>) {
+    migrate_user_notes();
+    list_existing_notes();
+    init(pricing);
+    reset_interrupted_workspace_upgrades();
 
     #[cfg(network = "local")]
     {
@@ -302,8 +983,8 @@ async fn get_balance_tuple() -> (String, String) {
     let balance = check_user_balance(&TokenType::CKUSDT, acc).await.expect("Failed to get balance");
     let balance_usdc = check_user_balance(&TokenType::CKUSDC, acc).await.expect("Failed to get balance");
 
-    let balance_str = u64_to_decimal(balance, 6);
-    let balance_usdc_str = u64_to_decimal(balance_usdc, 6);
+    let balance_str = u64_to_decimal(balance, token_decimals(&TokenType::CKUSDT));
+    let balance_usdc_str = u64_to_decimal(balance_usdc, token_decimals(&TokenType::CKUSDC));
 
     (
         balance_str,
@@ -335,14 +1016,25 @@ fn setup_assets() {
     PUBLISHED_NOTES.with_borrow(|published| {
         for entry in published.iter() {
             let note_id = entry.key();
-            // let published_note = entry.value();
+            // only public notes are pre-rendered, the rest go through get_restricted_note.
+            // ASSET_STORE survives upgrades, so pages rendered before a note was restricted are dropped here.
+            if !matches!(entry.value().access_type, AccessType::Public) {
+                ic_asset_server::delete_asset(format!("/{}", note_id));
+                continue;
+            }
             let note = NOTES.with(|notes| notes.borrow().get(&note_id));
             if let Some(note) = note {
                 let rendered_content = HANDLEBARS.with_borrow_mut(|handlebars| {
                     let site = Site::new("Dotane".to_string(), "https://dotane.io".to_string());
+                    let tags = note.tags();
                     let author = Author::new(note.author, "".to_string(), "".to_string(), "https://dotane.io".to_string());
-                    let article = Article::new(note_id.clone(), note.title, note.content, note.created_at);
-                    let context = NoteTemplateContext::new(article, author, site);
+                    let article = Article::new(note_id.clone(), note.title, note.content, note.created_at)
+                        .with_tags(tags);
+                    let article = gate_paid_article(article, &note_id);
+                    let mut context = NoteTemplateContext::new(article, author, site);
+                    if let Some(tips) = note_tips_context(&note_id) {
+                        context = context.with_tips(tips);
+                    }
                     handlebars.render("note", &context)
                 }).expect("Failed to render note");
                 add_asset(format!("/{}", note_id), rendered_content.as_bytes().to_vec(), "text/html".to_string());
@@ -352,19 +1044,22 @@ fn setup_assets() {
 
 }
 
-// fn is_controller() -> Result<(), String> {
-//     let caller = ic_cdk::api::msg_caller();
-//     if caller == Principal::anonymous() {
-//         return Err("Unauthorized".to_string());
-//     }
-//     return Ok(());
-// }
+fn is_controller() -> Result<(), String> {
+    is_authenticated()?;
+    let caller = ic_cdk::api::msg_caller();
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err("Unauthorized".to_string());
+    }
+    return Ok(());
+}
 
 
 fn setup_handlebars() {
     HANDLEBARS.with_borrow_mut(|handlebars| {
         
         handlebars.register_template_string("note", NOTE_TEMPLATE).unwrap();
+        handlebars.register_template_string("tag", TAG_TEMPLATE).unwrap();
+        handlebars.register_template_string("invoice", INVOICE_TEMPLATE).unwrap();
     });
 }
 
@@ -399,9 +1094,10 @@ fn http_request(req : ic_http_certification::HttpRequest) -> ic_http_certificati
 }
 
 // this function is used to save a note to the user's private notes
-#[ic_cdk::update(guard = "is_premium_user")]
-fn save_note(title: String, content: String) -> Result<String, String> {
+#[ic_cdk::update(guard = "is_authenticated")]
+fn save_note(title: String, content: String, tags: Option<Vec<String>>) -> Result<String, String> {
     let caller = ic_cdk::api::msg_caller();
+    ensure_private_note_quota(&caller)?;
     // Validate input
     if title.trim().is_empty() {
         return Err("Title cannot be empty".to_string());
@@ -411,6 +1107,7 @@ fn save_note(title: String, content: String) -> Result<String, String> {
         return Err("Content cannot be empty".to_string());
     }
 
+    let tags = normalize_tags(tags.unwrap_or_default())?;
     let current_time = get_current_time_in_milli();
     let note_id = generate_note_id(&title);
 
@@ -421,20 +1118,14 @@ fn save_note(title: String, content: String) -> Result<String, String> {
         created_at: current_time,
         updated_at: current_time,
         author: caller.to_text(),
+        tags: Some(tags),
     };
 
-    // Also save note in USER_NOTES for the caller
-    USER_NOTES.with_borrow_mut(|user_notes| {
-        let private_notes = user_notes.get(&caller);
-        let mut user_data : UserNotes;
-        if let None = private_notes {
-            user_data = UserNotes::default();
-        } else {
-            user_data = private_notes.unwrap();
-        }
-        user_data.private_notes.insert(note_id.clone(), note);
-        user_notes.insert(caller, user_data);
+    // Also save note in PRIVATE_NOTES for the caller
+    PRIVATE_NOTES.with_borrow_mut(|private_notes| {
+        private_notes.insert(owner_note_key(&caller, &note_id), note);
     });
+    reindex_owner_note(&caller, &note_id);
 
     Ok(note_id)
 }
@@ -464,12 +1155,8 @@ async fn create_session() -> SessionData {
 
     let id_bytes = raw_rand().await.expect("Failed to generate random bytes");
     let id = hex::encode(id_bytes);
-    // Determine query_limit based on premium status
-    let query_limit = if PREMIUM_USERS_SET.with_borrow(|set| set.contains(&caller)) {
-        None
-    } else {
-        Some(15)
-    };
+    // Determine query_limit from the caller's tier
+    let query_limit = user_limits(&caller).ai_queries_per_session;
 
     let expires_at = get_current_time_in_milli() + 60 * 60 * 1000; // 1 hour from now
 
@@ -535,21 +1222,13 @@ fn list_notes() -> ListNotesResponse {
     let mut private_notes = Vec::new();
     let mut published_notes = Vec::new();
 
-    USER_NOTES.with_borrow(|user_notes| {
-        let user_notes_ref = user_notes.get(&caller);
-        if let Some(user_notes) = user_notes_ref {
-            for note_id in user_notes.published_note_ids {
-                // TODO: Get the note from the storage canister
-                if let Some(note) = NOTES.with(|notes| notes.borrow().get(&note_id)) {
-                    published_notes.push(note);
-                }
-            }
-            for (_, note) in user_notes.private_notes {
-                private_notes.push(note);
-            }
-           
+    for note_id in user_published_note_ids(&caller) {
+        // TODO: Get the note from the storage canister
+        if let Some(note) = NOTES.with(|notes| notes.borrow().get(&note_id)) {
+            published_notes.push(note);
         }
-    });
+    }
+    private_notes.extend(user_private_notes(&caller));
 
     ListNotesResponse {
         private_notes,
@@ -557,52 +1236,100 @@ fn list_notes() -> ListNotesResponse {
     }
 }
 
+// Paginated, metadata-first variant of list_notes
+#[ic_cdk::query(guard = "is_authenticated")]
+fn list_notes_page(request: ListNotesRequest) -> Result<ListNotesPage, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let first = OwnerNoteTimeKey { owner: caller, timestamp: 0, note_id: String::new() };
+    // no note is stamped u64::MAX, so this sorts after every note of the caller's
+    let end = OwnerNoteTimeKey { owner: caller, timestamp: u64::MAX, note_id: String::new() };
+    let order = request.sort_order();
+    let bounds = match request.cursor_position()? {
+        None => (Bound::Included(first), Bound::Excluded(end)),
+        Some((timestamp, note_id)) => {
+            let after = OwnerNoteTimeKey { owner: caller, timestamp, note_id };
+            match order {
+                SortOrder::Asc => (Bound::Excluded(after), Bound::Excluded(end)),
+                SortOrder::Desc => (Bound::Included(first), Bound::Excluded(after)),
+            }
+        }
+    };
+    let total = match request.visibility {
+        Some(NoteVisibility::Published) => published_note_count(&caller),
+        Some(NoteVisibility::Private) => private_note_count(&caller),
+        None => published_note_count(&caller) + private_note_count(&caller),
+    };
+
+    let page = note_order(request.sort_field()).with_borrow(|notes_in_order| {
+        let keys = notes_in_order.range(bounds);
+        let keys: Box<dyn Iterator<Item = OwnerNoteTimeKey> + '_> = match order {
+            SortOrder::Asc => Box::new(keys),
+            SortOrder::Desc => Box::new(keys.rev()),
+        };
+        let summaries = keys.filter_map(|key| NOTE_LISTINGS.with_borrow(|listings| listings.get(&owner_note_key(&caller, &key.note_id))));
+        request.page(summaries, total, |summary| get_owned_note(&caller, &summary.id).map(|(note, _)| note.content))
+    });
+    Ok(page)
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_note(note_id: String) -> Result<Note, String> {
+    let caller = ic_cdk::api::msg_caller();
+    get_owned_note(&caller, &note_id)
+        .map(|(note, _)| note)
+        .ok_or("Note not found".to_string())
+}
+
 // this function is used to publish a note to the user's published notes
-#[ic_cdk::update(guard = "is_premium_user")]
+#[ic_cdk::update(guard = "is_authenticated")]
 fn publish_saved_note(note_id: String, access_type: AccessType) -> Result<(), String> {
     let caller = ic_cdk::api::msg_caller();
+    let access_type = validate_access_type(access_type)?;
+    let key = owner_note_key(&caller, &note_id);
     // Check if the note is already published
-    USER_NOTES.with_borrow_mut(|user_notes_store| {
-        let user_notes_ref = user_notes_store.get(&caller);
-        if let Some(mut user_notes) = user_notes_ref {
-            if user_notes.published_note_ids.contains(&note_id) {
-                return Err("Note already published".to_string());
-            }
+    if USER_PUBLISHED_NOTES.with_borrow(|published_ids| published_ids.contains(&key)) {
+        return Err("Note already published".to_string());
+    }
 
-            let notes_exists = NOTES.with(|notes| notes.borrow().contains_key(&note_id));
+    let notes_exists = NOTES.with(|notes| notes.borrow().contains_key(&note_id));
 
-            // check the notes in NOTES if the note_id exists
-            if notes_exists {
-               
-                return Err("Note already exists".to_string());
-            }
+    // check the notes in NOTES if the note_id exists
+    if notes_exists {
+        return Err("Note already exists".to_string());
+    }
 
-            let note = user_notes.private_notes.remove(&note_id);
-            if let Some(note) = note {
-                PUBLISHED_NOTES.with_borrow_mut(|published| {
-                    let published_note = PublishedNote {
-                        note_id: note.id.clone(),
-                        author: caller.to_string(),
-                        created_at: note.created_at,
-                        updated_at: note.updated_at,
-                        storage_canister: None,
-                        access_type,
-                    };
-                    published.insert(note_id.clone(), published_note);
-                });
-                user_notes.published_note_ids.insert(note_id);
-                user_notes_store.insert(caller, user_notes);
-                Ok(())
-            } else {
-                Err("Note not found".to_string())
-            }
-        } else {
-            return Err("Note not found".to_string());
-        }
-    })
+    let note = PRIVATE_NOTES.with_borrow_mut(|private_notes| private_notes.remove(&key));
+    if let Some(note) = note {
+        PUBLISHED_NOTES.with_borrow_mut(|published| {
+            let published_note = PublishedNote {
+                note_id: note.id.clone(),
+                author: caller.to_string(),
+                created_at: note.created_at,
+                updated_at: note.updated_at,
+                storage_canister: None,
+                access_type,
+            };
+            published.insert(note_id.clone(), published_note);
+        });
+        // the note leaves PRIVATE_NOTES, so it has to live in NOTES from now on
+        NOTES.with_borrow_mut(|notes| notes.insert(note_id.clone(), note));
+        USER_PUBLISHED_NOTES.with_borrow_mut(|published_ids| published_ids.insert(key));
+        reindex_owner_note(&caller, &note_id);
+        reindex_published_note(&note_id);
+        sync_note_tags(&note_id, &[]);
+        render_and_save_note(note_id)
+    } else {
+        Err("Note not found".to_string())
+    }
 }
 
 fn render_and_save_note(note_id: String) -> Result<(), String> {
+    // Restricted and private notes must never end up in the public asset store
+    if !is_public_note(&note_id) {
+        ic_asset_server::delete_asset(format!("/{}", note_id));
+        return Ok(());
+    }
+
     // Retrieve the note by its id
     let note = NOTES.with_borrow(|notes| notes.get(&note_id));
     if let Some(note) = note {
@@ -625,8 +1352,13 @@ fn render_and_save_note(note_id: String) -> Result<(), String> {
             note.title.clone(),
             note.content.clone(),
             note.created_at,
-        );
-        let context = NoteTemplateContext::new(article, author, site);
+        )
+        .with_tags(note.tags());
+        let article = gate_paid_article(article, &note_id);
+        let mut context = NoteTemplateContext::new(article, author, site);
+        if let Some(tips) = note_tips_context(&note_id) {
+            context = context.with_tips(tips);
+        }
 
         // Render the note content using Handlebars
         let rendered_content = HANDLEBARS.with_borrow_mut(|handlebars| {
@@ -645,22 +1377,26 @@ fn render_and_save_note(note_id: String) -> Result<(), String> {
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
-fn publish_note(title: String, content: String, access_type: AccessType) -> Result<Note, String> {
+fn publish_note(title: String, content: String, access_type: AccessType, tags: Option<Vec<String>>) -> Result<Note, String> {
     let caller = ic_cdk::api::msg_caller();
+    let tags = normalize_tags(tags.unwrap_or_default())?;
+    let access_type = validate_access_type(access_type)?;
     let note_id = generate_note_id(&title);
     let current_time = get_current_time_in_milli();
 
-    let user_profile = USER_PROFILES.with_borrow(|profile| profile.get(&caller).unwrap_or(UserProfile::anonymous()));
+    if let AccessType::Public = access_type {
+        let user_profile = USER_PROFILES.with_borrow(|profile| profile.get(&caller).unwrap_or(UserProfile::anonymous()));
 
-    let rendered_content = HANDLEBARS.with_borrow_mut(|handlebars| {
-        let site = Site::new("Dotane".to_string(), "https://dotane.io".to_string());
-          let author = Author::new(user_profile.name, user_profile.bio, user_profile.avatar, "https://dotane.io".to_string());
-          let article = Article::new(note_id.clone(), title.clone(), content.clone(), current_time);
-          let context = NoteTemplateContext::new(article, author, site);
-        handlebars.render("note", &context)
-    }).expect("Failed to render note");
+        let rendered_content = HANDLEBARS.with_borrow_mut(|handlebars| {
+            let site = Site::new("Dotane".to_string(), "https://dotane.io".to_string());
+              let author = Author::new(user_profile.name, user_profile.bio, user_profile.avatar, "https://dotane.io".to_string());
+              let article = Article::new(note_id.clone(), title.clone(), content.clone(), current_time).with_tags(tags.clone());
+              let context = NoteTemplateContext::new(article, author, site);
+            handlebars.render("note", &context)
+        }).expect("Failed to render note");
 
-    add_asset(format!("/{}", note_id), rendered_content.as_bytes().to_vec(), "text/html".to_string());
+        add_asset(format!("/{}", note_id), rendered_content.as_bytes().to_vec(), "text/html".to_string());
+    }
 
     let note = Note {
         id: note_id.clone(),
@@ -669,6 +1405,7 @@ fn publish_note(title: String, content: String, access_type: AccessType) -> Resu
         created_at: current_time,
         updated_at: current_time,
         author: caller.to_string(),
+        tags: Some(tags),
     };
 
     PUBLISHED_NOTES.with_borrow_mut(|published| {
@@ -686,19 +1423,14 @@ fn publish_note(title: String, content: String, access_type: AccessType) -> Resu
     NOTES.with_borrow_mut(|notes| {
         notes.insert(note_id.clone(), note.clone());
     });
+    reindex_published_note(&note_id);
+    sync_note_tags(&note_id, &[]);
 
-    // Also save note in USER_NOTES for the caller
-    USER_NOTES.with_borrow_mut(|users_notes_store| {
-        let user_notes_ref = users_notes_store.get(&caller);
-        let mut user_data : UserNotes;
-        if let None = user_notes_ref {
-            user_data = UserNotes::default();
-        } else {
-            user_data = user_notes_ref.unwrap();
-        }
-        user_data.published_note_ids.insert(note_id.clone());
-        users_notes_store.insert(caller, user_data);
+    // Also index the note under the caller
+    USER_PUBLISHED_NOTES.with_borrow_mut(|published_ids| {
+        published_ids.insert(owner_note_key(&caller, &note_id));
     });
+    reindex_owner_note(&caller, &note_id);
 
     Ok(note)
 }
@@ -707,32 +1439,26 @@ fn publish_note(title: String, content: String, access_type: AccessType) -> Resu
 fn unpublish_note(note_id: String) -> Result<Note, String> {
     let caller = ic_cdk::api::msg_caller();
     // Check if note exists and belongs to author
-    PUBLISHED_NOTES.with_borrow_mut(|published| {
+    let unpublished = PUBLISHED_NOTES.with_borrow_mut(|published| {
         let published_note = published.get(&note_id);
         if let Some(published_note) = published_note {
-            if published_note.author == caller.to_string() {
+            if NOTE_NFTS.with_borrow(|note_nfts| note_nfts.contains_key(&note_id)) {
+                Err("Minted notes cannot be unpublished".to_string())
+            } else if published_note.author == caller.to_string() {
                 ic_asset_server::delete_asset(format!("/{}", note_id));
+                remove_note_from_index(&note_id);
                 let p_note = published.remove(&note_id).unwrap();
                 //TODO: Delete the note from the storage canister
                 let note = NOTES.with(|notes| notes.borrow_mut().remove(&note_id));
-                //TODO: Remove the note from the USER_NOTES for the caller
-                
-                USER_NOTES.with_borrow_mut(|user_notes_store| {
-                    let user_notes_ref = user_notes_store.get(&caller);
-                    let mut user_data : UserNotes;
-                    if let None = user_notes_ref {
-                        user_data = UserNotes::default();
-                    } else {
-                        user_data = user_notes_ref.unwrap();
-                    }
-                    user_data.published_note_ids.remove(&note_id);
-
-                    // if user is premium, add the note to the private notes
-                    if is_premium_user().is_ok() && note.is_some() {
-                        user_data.private_notes.insert(note_id, note.clone().unwrap());
-                    }
-                    user_notes_store.insert(caller, user_data);
-                });
+                let key = owner_note_key(&caller, &note_id);
+                USER_PUBLISHED_NOTES.with_borrow_mut(|published_ids| published_ids.remove(&key));
+
+                // keep it as a private note if the caller's plan has room for one
+                if ensure_private_note_quota(&caller).is_ok() && note.is_some() {
+                    PRIVATE_NOTES.with_borrow_mut(|private_notes| {
+                        private_notes.insert(key, note.clone().unwrap());
+                    });
+                }
                 Ok(note.unwrap())
             } else {
                 Err("Not authorized to unpublish this note".to_string())
@@ -740,483 +1466,4809 @@ fn unpublish_note(note_id: String) -> Result<Note, String> {
         } else {
             Err("Note not found".to_string())
         }
-    })
+    });
+
+    // tag pages read PUBLISHED_NOTES, so they can only be refreshed once it is released
+    if let Ok(note) = &unpublished {
+        reindex_owner_note(&caller, &note_id);
+        sync_note_tags(&note_id, &note.tags());
+        remove_note_access_links(&note_id);
+        NOTE_PRICES.with_borrow_mut(|prices| prices.remove(&note_id));
+    }
+
+    unpublished
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
 fn delete_saved_note(note_id: String) -> Result<Note, String> {
     let caller = ic_cdk::api::msg_caller();
     // if the note is private, delete it from the private notes
-    USER_NOTES.with_borrow_mut(|user_notes_store| {
-        let user_notes_ref = user_notes_store.get(&caller);
-        if let Some(mut user_notes) = user_notes_ref {
-            let note = user_notes.private_notes.remove(&note_id);
-            if let Some(note) = note {
-                Ok(note)
-            } else {
-                Err("Note not found".to_string())
-            }
-        } else {
-            Err("Note not found".to_string())
-        }
-    })
+    let note = PRIVATE_NOTES.with_borrow_mut(|private_notes| {
+        private_notes
+            .remove(&owner_note_key(&caller, &note_id))
+            .ok_or("Note not found".to_string())
+    })?;
+    remove_note_revisions(&note_id);
+    reindex_owner_note(&caller, &note_id);
+    Ok(note)
 }
 
 #[ic_cdk::update(guard = "is_authenticated")]
 fn update_note(
     note_id: String,
     content: String,
+    tags: Option<Vec<String>>,
 ) -> Result<(), String> {
     if content.trim().is_empty() {
         return Err("Content cannot be empty".to_string());
     }
+    let tags = tags.map(normalize_tags).transpose()?;
 
     let caller = ic_cdk::api::msg_caller();
 
-    // Get the user's notes
-    let update_result = USER_NOTES.with_borrow_mut(|user_notes_store| {
-        let user_notes_ref = user_notes_store.get(&caller);
-        if let Some(mut user_notes) = user_notes_ref {
-            // Check if the note is in published_note_ids
-            if user_notes.published_note_ids.contains(&note_id) {
-                // Check for authorization: only the author can update
-                let note_opt = NOTES.with(|notes| notes.borrow().get(&note_id));
-                if let Some(mut note) = note_opt {
-                    if note.author != caller.to_text() {
-                        return Err("Not authorized to update this note".to_string());
-                    }
-                    // Update the note content and updated_at
-                    note.content = content.trim().to_string();
-                    note.updated_at = get_current_time_in_milli();
-                    // Save the updated note
-                    NOTES.with(|notes| notes.borrow_mut().insert(note_id.clone(), note));
-                    Ok(())
-                } else {
-                    Err("Note not found".to_string())
-                }
-            } else {
-                // Not a published note, check if it's a private note
-                if let Some(mut note) = user_notes.private_notes.get_mut(&note_id) {
-                    // Only the owner can update their private note
-                    note.content = content.trim().to_string();
-                    note.updated_at = get_current_time_in_milli();
-                    // Save the updated user notes
-                    user_notes_store.insert(caller, user_notes);
-                    Ok(())
-                } else {
-                    Err("Note not found".to_string())
-                }
+    let key = owner_note_key(&caller, &note_id);
+    // Check if the note is one of the caller's published notes
+    let update_result = if USER_PUBLISHED_NOTES.with_borrow(|published_ids| published_ids.contains(&key)) {
+        // Check for authorization: only the author can update
+        let note_opt = NOTES.with(|notes| notes.borrow().get(&note_id));
+        if let Some(mut note) = note_opt {
+            if note.author != caller.to_text() {
+                return Err("Not authorized to update this note".to_string());
             }
+            push_note_revision(&note);
+            let previous_tags = note.tags();
+            // Update the note content and updated_at
+            note.content = content.trim().to_string();
+            note.updated_at = get_current_time_in_milli();
+            if tags.is_some() {
+                note.tags = tags.clone();
+            }
+            // Save the updated note
+            NOTES.with(|notes| notes.borrow_mut().insert(note_id.clone(), note));
+            Ok(Some(previous_tags))
         } else {
-            Err("User notes not found".to_string())
+            Err("Note not found".to_string())
         }
-    });
+    } else {
+        // Not a published note, check if it's a private note
+        PRIVATE_NOTES.with_borrow_mut(|private_notes| {
+            if let Some(mut note) = private_notes.get(&key) {
+                // Only the owner can update their private note
+                push_note_revision(&note);
+                note.content = content.trim().to_string();
+                note.updated_at = get_current_time_in_milli();
+                if tags.is_some() {
+                    note.tags = tags.clone();
+                }
+                private_notes.insert(key, note);
+                Ok(None)
+            } else {
+                Err("Note not found".to_string())
+            }
+        })
+    };
 
     // After update and note is saved, call render_and_save_function if update was successful
-    if update_result.is_ok() {
+    let previous_tags = update_result?;
+    reindex_owner_note(&caller, &note_id);
+    if let Some(previous_tags) = previous_tags {
         // You may want to handle the result of render_and_save_function, but here we just call it
         // and ignore its result for now.
+        reindex_published_note(&note_id);
+        sync_note_tags(&note_id, &previous_tags);
         let _ = render_and_save_note(note_id);
     }
 
-    update_result
+    Ok(())
 }
 
-#[ic_cdk::query(guard = "is_authenticated")]
-fn get_workspaces() -> Vec<Workspace> {
-    let caller = ic_cdk::api::msg_caller();
-    let user_canisters = USER_CANISTERS.with(|canisters| canisters.borrow().get(&caller));
-    if let Some(user_canisters) = user_canisters {
-        let mut workspaces = Vec::new();
-        for (canister_id, domain) in user_canisters.user_canisters {
-            workspaces.push(Workspace {
-                canister_id: canister_id,
-                domain: domain,
-            });
-        }
-        return workspaces;
-    }
-    return Vec::new();
+fn owner_note_key(owner: &Principal, note_id: &String) -> OwnerNoteKey {
+    OwnerNoteKey { owner: *owner, note_id: note_id.clone() }
 }
 
-#[ic_cdk::update]
-fn create_user_profile(
-    req: CreateUserProfileRequest
-) -> Result<(), String> {
-    let current_time = get_current_time_in_milli();
-    let user_id = ic_cdk::api::msg_caller();
-
-    let profile = UserProfile {
-        name: req.name.trim().to_string(),
-        email: req.email.trim().to_string(),
-        bio: req.bio.trim().to_string(),
-        avatar: req.avatar.trim().to_string(),
-        created_at: current_time,
-        updated_at: current_time,
-        premium: false,
-        marked_public: req.marked_public,
-    };
+fn note_order(sort_by: NoteSortField) -> &'static LocalKey<RefCell<BTreeSet<OwnerNoteTimeKey, Memory>>> {
+    match sort_by {
+        NoteSortField::UpdatedAt => &NOTES_BY_UPDATED,
+        NoteSortField::CreatedAt => &NOTES_BY_CREATED,
+    }
+}
 
-    USER_PROFILES.with(|profiles| {
-        profiles.borrow_mut().insert(user_id, profile);
+// Bring the owner's listing of a note in line with what is stored, dropping it once the note is gone
+fn reindex_owner_note(owner: &Principal, note_id: &String) {
+    let key = owner_note_key(owner, note_id);
+    let current = get_owned_note(owner, note_id).map(|(note, published)| NoteSummary::new(&note, published));
+    let previous = NOTE_LISTINGS.with_borrow_mut(|listings| match &current {
+        Some(summary) => listings.insert(key, summary.clone()),
+        None => listings.remove(&key),
     });
+    for sort_by in [NoteSortField::UpdatedAt, NoteSortField::CreatedAt] {
+        note_order(sort_by).with_borrow_mut(|notes_in_order| {
+            if let Some(previous) = &previous {
+                notes_in_order.remove(&OwnerNoteTimeKey { owner: *owner, timestamp: previous.sort_timestamp(sort_by), note_id: note_id.clone() });
+            }
+            if let Some(current) = &current {
+                notes_in_order.insert(OwnerNoteTimeKey { owner: *owner, timestamp: current.sort_timestamp(sort_by), note_id: note_id.clone() });
+            }
+        });
+    }
+}
 
-    Ok(())
+// List notes stored before the listings existed. A no-op once any note is listed.
+fn list_existing_notes() {
+    if !NOTE_LISTINGS.with_borrow(|listings| listings.is_empty()) {
+        return;
+    }
+    let mut keys: Vec<OwnerNoteKey> = PRIVATE_NOTES.with_borrow(|private_notes| private_notes.iter().map(|entry| entry.key().clone()).collect());
+    keys.extend(USER_PUBLISHED_NOTES.with_borrow(|published_ids| published_ids.iter().collect::<Vec<_>>()));
+    for key in keys {
+        reindex_owner_note(&key.owner, &key.note_id);
+    }
 }
 
-#[ic_cdk::query(guard = "is_authenticated")]
-fn get_my_profile() -> Result<UserProfile, String> {
-    let user_id = ic_cdk::api::msg_caller();
-    USER_PROFILES.with(|profiles| {
-        let profile = profiles.borrow().get(&user_id);
-        if let Some(profile) = profile {
-            Ok(profile)
-        } else {    
-            Ok(UserProfile::anonymous())
-        }
+fn published_note_count(owner: &Principal) -> u64 {
+    USER_PUBLISHED_NOTES.with_borrow(|published_ids| {
+        published_ids
+            .range(owner_note_key(owner, &String::new())..)
+            .take_while(|key| key.owner == *owner)
+            .count() as u64
     })
 }
 
-#[ic_cdk::query]
-fn get_user_profile(user_id: String) -> Result<UserProfile, String> {
-    let user_id = Principal::from_text(&user_id).unwrap();
-    USER_PROFILES.with(|profiles| {
-        let profile = profiles.borrow().get(&user_id);
-        if let Some(profile) = profile {
-            if !profile.marked_public {
-                return Err("User profile is not marked public".to_string());
-            }
-            Ok(profile)
-        } else {
-            Err("User profile not found".to_string())
-        }
+fn user_published_note_ids(owner: &Principal) -> Vec<String> {
+    USER_PUBLISHED_NOTES.with_borrow(|published_ids| {
+        published_ids
+            .range(owner_note_key(owner, &String::new())..)
+            .take_while(|key| key.owner == *owner)
+            .map(|key| key.note_id)
+            .collect()
     })
 }
 
-#[ic_cdk::query(guard = "is_authenticated")]
-fn is_workspace_premium_user() -> bool {
-    let caller = ic_cdk::api::msg_caller();
-    let premium_users_set = PREMIUM_USERS_SET.with_borrow(|set| set.contains(&caller));
-    return premium_users_set;
+fn user_private_notes(owner: &Principal) -> Vec<Note> {
+    PRIVATE_NOTES.with_borrow(|private_notes| {
+        private_notes
+            .range(owner_note_key(owner, &String::new())..)
+            .take_while(|entry| entry.key().owner == *owner)
+            .map(|entry| entry.value())
+            .collect()
+    })
 }
 
-// #[ic_cdk::query]
-// fn http_request(req : HttpRe)
-
-#[ic_cdk::update]
-fn update_user_profile(
-    req: UpdateUserProfileRequest
-) -> Result<(), String> {
-    let user_id = ic_cdk::api::msg_caller();
-    let current_time = get_current_time_in_milli();
+// Move notebooks out of the legacy USER_NOTES blobs into per-note entries.
+// Runs on every upgrade and is a no-op once USER_NOTES is empty.
+fn migrate_user_notes() {
+    let owners: Vec<Principal> = USER_NOTES.with_borrow(|user_notes| user_notes.iter().map(|entry| *entry.key()).collect());
+    for owner in owners {
+        let Some(user_notes) = USER_NOTES.with_borrow(|user_notes| user_notes.get(&owner)) else {
+            continue;
+        };
+        PRIVATE_NOTES.with_borrow_mut(|private_notes| {
+            for (note_id, note) in user_notes.private_notes {
+                private_notes.insert(owner_note_key(&owner, &note_id), note);
+            }
+        });
+        USER_PUBLISHED_NOTES.with_borrow_mut(|published_ids| {
+            for note_id in user_notes.published_note_ids.iter() {
+                published_ids.insert(owner_note_key(&owner, note_id));
+            }
+        });
+        USER_NOTES.with_borrow_mut(|user_notes| user_notes.remove(&owner));
+    }
+}
 
-    USER_PROFILES.with(|profiles| {
-        let mut profiles_ref = profiles.borrow_mut();
-        if let Some(mut profile) = profiles_ref.get(&user_id) {
-            profile.name = req.name.unwrap_or(profile.name);
-            profile.email = req.email.unwrap_or(profile.email);
-            profile.bio = req.bio.unwrap_or(profile.bio);
-            profile.avatar = req.avatar.unwrap_or(profile.avatar);
-            profile.updated_at = current_time;
-            profile.marked_public = req.marked_public.unwrap_or(profile.marked_public);
-            profiles_ref.insert(user_id, profile);
+const MAX_NOTE_REVISIONS: usize = 20;
+
+fn note_revision_range(note_id: &String) -> std::ops::RangeInclusive<NoteRevisionKey> {
+    NoteRevisionKey { note_id: note_id.clone(), revision: 0 }..=NoteRevisionKey { note_id: note_id.clone(), revision: u64::MAX }
+}
+
+fn remove_note_revisions(note_id: &String) {
+    NOTE_REVISIONS.with_borrow_mut(|revisions| {
+        let keys: Vec<NoteRevisionKey> = revisions
+            .range(note_revision_range(note_id))
+            .map(|entry| entry.key().clone())
+            .collect();
+        for key in keys {
+            revisions.remove(&key);
+        }
+    });
+}
+
+// Snapshot a note's current content before it is overwritten
+fn push_note_revision(note: &Note) {
+    NOTE_REVISIONS.with_borrow_mut(|revisions| {
+        let keys: Vec<NoteRevisionKey> = revisions
+            .range(note_revision_range(&note.id))
+            .map(|entry| entry.key().clone())
+            .collect();
+        let revision = keys.last().map(|key| key.revision + 1).unwrap_or(1);
+
+        revisions.insert(
+            NoteRevisionKey { note_id: note.id.clone(), revision },
+            NoteRevision {
+                revision,
+                title: note.title.clone(),
+                content: note.content.clone(),
+                updated_at: note.updated_at,
+                saved_at: get_current_time_in_milli(),
+            },
+        );
+
+        // keys doesn't include the revision just added
+        if keys.len() + 1 > MAX_NOTE_REVISIONS {
+            for key in &keys[..keys.len() + 1 - MAX_NOTE_REVISIONS] {
+                revisions.remove(key);
+            }
+        }
+    });
+}
+
+// The caller's note and whether it is published
+fn get_owned_note(caller: &Principal, note_id: &String) -> Option<(Note, bool)> {
+    let key = owner_note_key(caller, note_id);
+    if USER_PUBLISHED_NOTES.with_borrow(|published_ids| published_ids.contains(&key)) {
+        NOTES.with_borrow(|notes| notes.get(note_id))
+            .filter(|note| note.author == caller.to_text())
+            .map(|note| (note, true))
+    } else {
+        PRIVATE_NOTES.with_borrow(|private_notes| private_notes.get(&key)).map(|note| (note, false))
+    }
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn list_revisions(note_id: String) -> Result<Vec<NoteRevisionSummary>, String> {
+    let caller = ic_cdk::api::msg_caller();
+    if get_owned_note(&caller, &note_id).is_none() {
+        return Err("Note not found".to_string());
+    }
+
+    let mut summaries: Vec<NoteRevisionSummary> = NOTE_REVISIONS.with_borrow(|revisions| {
+        revisions
+            .range(note_revision_range(&note_id))
+            .map(|entry| {
+                let revision = entry.value();
+                NoteRevisionSummary {
+                    revision: revision.revision,
+                    title: revision.title,
+                    content_length: revision.content.len() as u64,
+                    updated_at: revision.updated_at,
+                    saved_at: revision.saved_at,
+                }
+            })
+            .collect()
+    });
+    // newest first
+    summaries.reverse();
+    Ok(summaries)
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_revision(note_id: String, revision: u64) -> Result<NoteRevision, String> {
+    let caller = ic_cdk::api::msg_caller();
+    if get_owned_note(&caller, &note_id).is_none() {
+        return Err("Note not found".to_string());
+    }
+
+    NOTE_REVISIONS
+        .with_borrow(|revisions| revisions.get(&NoteRevisionKey { note_id, revision }))
+        .ok_or("Revision not found".to_string())
+}
+
+/// Restores a note to an earlier revision. The current content is kept as a new revision,
+/// so a restore can itself be undone.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn restore_revision(note_id: String, revision: u64) -> Result<Note, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let (mut note, is_published) = get_owned_note(&caller, &note_id).ok_or("Note not found".to_string())?;
+    let restored = NOTE_REVISIONS
+        .with_borrow(|revisions| revisions.get(&NoteRevisionKey { note_id: note_id.clone(), revision }))
+        .ok_or("Revision not found".to_string())?;
+
+    push_note_revision(&note);
+    note.title = restored.title;
+    note.content = restored.content;
+    note.updated_at = get_current_time_in_milli();
+
+    if is_published {
+        NOTES.with_borrow_mut(|notes| notes.insert(note_id.clone(), note.clone()));
+        PUBLISHED_NOTES.with_borrow_mut(|published| {
+            if let Some(mut published_note) = published.get(&note_id) {
+                published_note.updated_at = note.updated_at;
+                published.insert(note_id.clone(), published_note);
+            }
+        });
+        reindex_published_note(&note_id);
+        sync_note_tags(&note_id, &note.tags());
+        // the restore is saved either way, the page catches up on the next render
+        if let Err(e) = render_and_save_note(note_id.clone()) {
+            ic_cdk::api::debug_print(&format!("Failed to render restored note {}: {}", note_id, e));
+        }
+    } else {
+        PRIVATE_NOTES.with_borrow_mut(|private_notes| {
+            private_notes.insert(owner_note_key(&caller, &note_id), note.clone());
+        });
+    }
+    reindex_owner_note(&caller, &note_id);
+
+    Ok(note)
+}
+
+// Search index constants
+const SEARCH_TITLE_WEIGHT: u32 = 3;
+const SEARCH_EXCERPT_CHARS: usize = 200;
+const MAX_SEARCH_PAGE_SIZE: u32 = 50;
+const MIN_TERM_CHARS: usize = 2;
+const MAX_TERM_CHARS: usize = 32;
+const STOP_WORDS: &[&str] = &[
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "from", "has", "have", "in", "is", "it", "its",
+    "of", "on", "or", "that", "the", "this", "to", "was", "were", "will", "with",
+];
+
+// Drop tags and decode the few entities BlockNote emits, leaving plain text
+fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => {
+                in_tag = true;
+                text.push(' ');
+            }
+            '>' if in_tag => in_tag = false,
+            _ if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .map(|term| term.to_lowercase())
+        .filter(|term| {
+            let len = term.chars().count();
+            len >= MIN_TERM_CHARS && len <= MAX_TERM_CHARS && !STOP_WORDS.contains(&term.as_str())
+        })
+        .collect()
+}
+
+fn make_excerpt(text: &str) -> String {
+    let collapsed = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if collapsed.chars().count() <= SEARCH_EXCERPT_CHARS {
+        return collapsed;
+    }
+    let mut excerpt: String = collapsed.chars().take(SEARCH_EXCERPT_CHARS).collect();
+    excerpt.push('…');
+    excerpt
+}
+
+fn index_note(note: &Note) {
+    remove_note_from_index(&note.id);
+
+    let text = strip_html(&note.content);
+    let mut weights: HashMap<String, u32> = HashMap::new();
+    for term in tokenize(&note.title) {
+        *weights.entry(term).or_insert(0) += SEARCH_TITLE_WEIGHT;
+    }
+    for term in tokenize(&text) {
+        *weights.entry(term).or_insert(0) += 1;
+    }
+
+    SEARCH_POSTINGS.with_borrow_mut(|postings| {
+        for (term, weight) in &weights {
+            postings.insert(SearchPostingKey { term: term.clone(), note_id: note.id.clone() }, *weight);
+        }
+    });
+    // search results must not show more of a paid note than its page does
+    let excerpt = if NOTE_PRICES.with_borrow(|prices| prices.contains_key(&note.id)) {
+        make_excerpt(&paid_note_preview(&note.content))
+    } else {
+        make_excerpt(&text)
+    };
+    INDEXED_NOTES.with_borrow_mut(|indexed| {
+        indexed.insert(note.id.clone(), IndexedNote {
+            terms: weights.into_keys().collect(),
+            excerpt,
+        });
+    });
+}
+
+fn remove_note_from_index(note_id: &String) {
+    let indexed = INDEXED_NOTES.with_borrow_mut(|indexed| indexed.remove(note_id));
+    if let Some(indexed) = indexed {
+        SEARCH_POSTINGS.with_borrow_mut(|postings| {
+            for term in indexed.terms {
+                postings.remove(&SearchPostingKey { term, note_id: note_id.clone() });
+            }
+        });
+    }
+}
+
+fn is_public_note(note_id: &String) -> bool {
+    PUBLISHED_NOTES.with_borrow(|published| {
+        published
+            .get(note_id)
+            .map(|published_note| matches!(published_note.access_type, AccessType::Public))
+            .unwrap_or(false)
+    })
+}
+
+// Keep a note in the index only while it is published with public access
+fn reindex_published_note(note_id: &String) {
+    let note = NOTES.with_borrow(|notes| notes.get(note_id));
+    match note {
+        Some(note) if is_public_note(note_id) => index_note(&note),
+        _ => remove_note_from_index(note_id),
+    }
+}
+
+// Index public notes published before search existed
+fn setup_search_index() {
+    if !INDEXED_NOTES.with_borrow(|indexed| indexed.is_empty()) {
+        return;
+    }
+    let note_ids: Vec<String> = PUBLISHED_NOTES.with_borrow(|published| published.iter().map(|entry| entry.key().clone()).collect());
+    for note_id in note_ids {
+        reindex_published_note(&note_id);
+    }
+}
+
+/// Ranked full-text search over public notes, `offset`/`limit` page through the ranking.
+#[ic_cdk::query]
+fn search_notes(query: String, offset: u32, limit: u32) -> SearchNotesResponse {
+    let mut terms = tokenize(&query);
+    terms.sort();
+    terms.dedup();
+
+    let total_notes = INDEXED_NOTES.with_borrow(|indexed| indexed.len()) as f64;
+    let mut scores: HashMap<String, f64> = HashMap::new();
+    SEARCH_POSTINGS.with_borrow(|postings| {
+        for term in terms {
+            let matches: Vec<(String, u32)> = postings
+                .range(SearchPostingKey { term: term.clone(), note_id: String::new() }..)
+                .take_while(|entry| entry.key().term == term)
+                .map(|entry| (entry.key().note_id.clone(), entry.value()))
+                .collect();
+            if matches.is_empty() {
+                continue;
+            }
+            let idf = (1.0 + total_notes / matches.len() as f64).ln();
+            for (note_id, weight) in matches {
+                *scores.entry(note_id).or_insert(0.0) += weight as f64 * idf;
+            }
+        }
+    });
+
+    let mut results: Vec<SearchResult> = scores
+        .into_iter()
+        .filter(|(note_id, _)| is_public_note(note_id))
+        .filter_map(|(note_id, score)| {
+            let note = NOTES.with_borrow(|notes| notes.get(&note_id))?;
+            let excerpt = INDEXED_NOTES.with_borrow(|indexed| indexed.get(&note_id)).unwrap_or_default().excerpt;
+            Some(SearchResult {
+                note_id,
+                title: note.title,
+                author: note.author,
+                excerpt,
+                score,
+                updated_at: note.updated_at,
+            })
+        })
+        .collect();
+    results.sort_by(|a, b| b.score.total_cmp(&a.score).then(b.updated_at.cmp(&a.updated_at)));
+
+    let total = results.len() as u64;
+    let results = results
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit.min(MAX_SEARCH_PAGE_SIZE) as usize)
+        .collect();
+
+    SearchNotesResponse { results, total }
+}
+
+// Access link lifetime when the note does not set access_link_expiry: 7 days
+const DEFAULT_ACCESS_LINK_EXPIRY: u64 = 7 * 24 * 60 * 60 * 1000;
+
+// Guests are stored as principal text, reject anything that would never match a caller
+fn validate_access_type(access_type: AccessType) -> Result<AccessType, String> {
+    if let AccessType::RestrictedAccess(mut restricted) = access_type {
+        let mut guests = HashSet::new();
+        for guest in restricted.guests.iter() {
+            let principal = Principal::from_text(guest.trim()).map_err(|_| format!("Invalid guest principal: {}", guest))?;
+            if principal == Principal::anonymous() {
+                return Err("Anonymous principal cannot be a guest".to_string());
+            }
+            guests.insert(principal.to_text());
+        }
+        if guests.len() > restricted.num_of_guests as usize {
+            return Err(format!("Note allows at most {} guests", restricted.num_of_guests));
+        }
+        if restricted.access_link_expiry == Some(0) {
+            return Err("Access link expiry must be greater than zero".to_string());
+        }
+        restricted.guests = guests;
+        return Ok(AccessType::RestrictedAccess(restricted));
+    }
+    Ok(access_type)
+}
+
+// Apply `update` to the guest settings of a restricted note owned by `caller`
+fn update_restricted_note<R>(
+    caller: &Principal,
+    note_id: &String,
+    update: impl FnOnce(&mut RestrictedAccessNotes) -> Result<R, String>,
+) -> Result<R, String> {
+    PUBLISHED_NOTES.with_borrow_mut(|published| {
+        let mut published_note = published.get(note_id).ok_or("Note not found".to_string())?;
+        if published_note.author != caller.to_text() {
+            return Err("Not authorized to manage this note".to_string());
+        }
+        let result = match &mut published_note.access_type {
+            AccessType::RestrictedAccess(restricted) => update(restricted)?,
+            _ => return Err("Note is not restricted".to_string()),
+        };
+        published.insert(note_id.clone(), published_note);
+        Ok(result)
+    })
+}
+
+fn remove_note_access_links(note_id: &String) {
+    NOTE_ACCESS_LINKS.with_borrow_mut(|links| {
+        let tokens: Vec<String> = links
+            .iter()
+            .filter(|entry| entry.value().note_id == *note_id)
+            .map(|entry| entry.key().clone())
+            .collect();
+        for token in tokens {
+            links.remove(&token);
+        }
+    });
+}
+
+fn note_access_links(note_id: &String) -> Vec<NoteAccessLink> {
+    let now = get_current_time_in_milli();
+    NOTE_ACCESS_LINKS.with_borrow(|links| {
+        links
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|link| link.note_id == *note_id && link.expires_at > now)
+            .collect()
+    })
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn invite_note_guest(note_id: String, guest: Principal) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    if guest == Principal::anonymous() || guest == caller {
+        return Err("Invalid guest".to_string());
+    }
+    update_restricted_note(&caller, &note_id, |restricted| {
+        if restricted.guests.contains(&guest.to_text()) {
+            return Ok(());
+        }
+        if restricted.guests.len() >= restricted.num_of_guests as usize {
+            return Err(format!("Note allows at most {} guests", restricted.num_of_guests));
+        }
+        restricted.guests.insert(guest.to_text());
+        Ok(())
+    })
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn revoke_note_guest(note_id: String, guest: Principal) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    update_restricted_note(&caller, &note_id, |restricted| {
+        if restricted.guests.remove(&guest.to_text()) {
             Ok(())
         } else {
-            Err("User profile not found".to_string())
+            Err("Guest not found".to_string())
+        }
+    })
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn list_note_guests(note_id: String) -> Result<NoteGuests, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let published_note = PUBLISHED_NOTES
+        .with_borrow(|published| published.get(&note_id))
+        .ok_or("Note not found".to_string())?;
+    if published_note.author != caller.to_text() {
+        return Err("Not authorized to manage this note".to_string());
+    }
+    match published_note.access_type {
+        AccessType::RestrictedAccess(restricted) => {
+            let mut guests: Vec<String> = restricted.guests.into_iter().collect();
+            guests.sort();
+            Ok(NoteGuests {
+                guests,
+                num_of_guests: restricted.num_of_guests,
+                access_link_expiry: restricted.access_link_expiry,
+                access_links: note_access_links(&note_id),
+            })
+        }
+        _ => Err("Note is not restricted".to_string()),
+    }
+}
+
+/// Create a link token for a restricted note. Anyone holding the token can read
+/// the note through `get_restricted_note` until it expires or is revoked.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn create_note_access_link(note_id: String) -> Result<NoteAccessLink, String> {
+    let caller = ic_cdk::api::msg_caller();
+    update_restricted_note(&caller, &note_id, |_| Ok(()))?;
+
+    let token_bytes = raw_rand().await.map_err(|e| format!("Failed to generate access token: {:?}", e))?;
+    // the note may have changed while we were waiting for randomness
+    let lifetime = update_restricted_note(&caller, &note_id, |restricted| {
+        Ok(restricted.access_link_expiry.unwrap_or(DEFAULT_ACCESS_LINK_EXPIRY))
+    })?;
+
+    let now = get_current_time_in_milli();
+    let link = NoteAccessLink {
+        token: hex::encode(token_bytes),
+        note_id: note_id.clone(),
+        created_at: now,
+        expires_at: now.saturating_add(lifetime),
+    };
+    NOTE_ACCESS_LINKS.with_borrow_mut(|links| {
+        // drop this note's expired links while we are here
+        let expired: Vec<String> = links
+            .iter()
+            .filter(|entry| entry.value().note_id == note_id && entry.value().expires_at <= now)
+            .map(|entry| entry.key().clone())
+            .collect();
+        for token in expired {
+            links.remove(&token);
+        }
+        links.insert(link.token.clone(), link.clone());
+    });
+
+    Ok(link)
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn revoke_note_access_link(note_id: String, token: String) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    update_restricted_note(&caller, &note_id, |_| Ok(()))?;
+    NOTE_ACCESS_LINKS.with_borrow_mut(|links| match links.get(&token) {
+        Some(link) if link.note_id == note_id => {
+            links.remove(&token);
+            Ok(())
+        }
+        _ => Err("Access link not found".to_string()),
+    })
+}
+
+/// Read a published note that is not served as a public asset. Restricted notes
+/// are returned to their author, invited guests and holders of a valid access link.
+/// Paid notes are only returned in full to their author and buyers.
+#[ic_cdk::query]
+fn get_restricted_note(note_id: String, token: Option<String>) -> Result<Note, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let published_note = PUBLISHED_NOTES
+        .with_borrow(|published| published.get(&note_id))
+        .ok_or("Note not found".to_string())?;
+
+    let allowed = published_note.author == caller.to_text()
+        || match &published_note.access_type {
+            AccessType::Public => true,
+            AccessType::Private => false,
+            AccessType::RestrictedAccess(restricted) => {
+                (caller != Principal::anonymous() && restricted.guests.contains(&caller.to_text()))
+                    || token.is_some_and(|token| {
+                        NOTE_ACCESS_LINKS
+                            .with_borrow(|links| links.get(&token))
+                            .is_some_and(|link| link.note_id == note_id && link.expires_at > get_current_time_in_milli())
+                    })
+            }
+        };
+    if !allowed {
+        return Err("Not authorized to read this note".to_string());
+    }
+    if published_note.author != caller.to_text() && !has_note_access(caller, &note_id) {
+        return Err("Buy this note to read it".to_string());
+    }
+
+    NOTES.with_borrow(|notes| notes.get(&note_id)).ok_or("Note not found".to_string())
+}
+
+// Tag constants
+const MAX_NOTE_TAGS: usize = 10;
+const MAX_TAG_CHARS: usize = 32;
+const MAX_TAG_PAGE_NOTES: usize = 100;
+
+fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>, String> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().trim_start_matches('#').to_lowercase().split_whitespace().collect::<Vec<_>>().join("-");
+        if tag.is_empty() {
+            continue;
+        }
+        // tags end up in /tag/{name} paths, so keep them URL safe
+        if tag.len() > MAX_TAG_CHARS || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(format!("Invalid tag: {}", tag));
+        }
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    if normalized.len() > MAX_NOTE_TAGS {
+        return Err(format!("A note can have at most {} tags", MAX_NOTE_TAGS));
+    }
+    Ok(normalized)
+}
+
+fn tag_note_ids(tag: &String) -> Vec<String> {
+    TAG_NOTES.with_borrow(|tag_notes| {
+        tag_notes
+            .range(TagNoteKey { tag: tag.clone(), note_id: String::new() }..)
+            .take_while(|entry| entry.key().tag == *tag)
+            .map(|entry| entry.key().note_id.clone())
+            .collect()
+    })
+}
+
+// Render the certified /tag/{name} listing, or remove it once no public note has the tag
+fn render_tag_page(tag: &String) {
+    let path = format!("/tag/{}", tag);
+    let note_ids = tag_note_ids(tag);
+    if note_ids.is_empty() {
+        ic_asset_server::delete_asset(path);
+        return;
+    }
+
+    let mut notes: Vec<TaggedNote> = note_ids
+        .into_iter()
+        .filter_map(|note_id| {
+            let note = NOTES.with_borrow(|notes| notes.get(&note_id))?;
+            let excerpt = INDEXED_NOTES.with_borrow(|indexed| indexed.get(&note_id)).map(|indexed| indexed.excerpt);
+            Some(TaggedNote {
+                url: format!("/{}", note_id),
+                id: note_id,
+                title: note.title,
+                published_at: note.created_at,
+                excerpt,
+            })
+        })
+        .collect();
+    notes.sort_by(|a, b| b.published_at.cmp(&a.published_at));
+    notes.truncate(MAX_TAG_PAGE_NOTES);
+
+    let context = TagTemplateContext {
+        tag: tag.clone(),
+        notes,
+        site: Site::new("Dotane".to_string(), "https://dotane.io".to_string()),
+    };
+    match HANDLEBARS.with_borrow(|handlebars| handlebars.render("tag", &context)) {
+        Ok(rendered_content) => add_asset(path, rendered_content.as_bytes().to_vec(), "text/html".to_string()),
+        Err(e) => ic_cdk::api::debug_print(&format!("Failed to render tag page {}: {}", tag, e)),
+    }
+}
+
+// Move a note between tag listings after its tags, title or visibility changed
+fn sync_note_tags(note_id: &String, previous_tags: &[String]) {
+    let note = NOTES.with_borrow(|notes| notes.get(note_id)).filter(|_| is_public_note(note_id));
+    let current_tags = note.as_ref().map(|note| note.tags()).unwrap_or_default();
+
+    TAG_NOTES.with_borrow_mut(|tag_notes| {
+        for tag in previous_tags {
+            tag_notes.remove(&TagNoteKey { tag: tag.clone(), note_id: note_id.clone() });
+        }
+        if let Some(note) = &note {
+            for tag in &current_tags {
+                tag_notes.insert(TagNoteKey { tag: tag.clone(), note_id: note_id.clone() }, note.created_at);
+            }
+        }
+    });
+
+    let mut affected_tags: Vec<String> = previous_tags.to_vec();
+    affected_tags.extend(current_tags);
+    affected_tags.sort();
+    affected_tags.dedup();
+    for tag in affected_tags {
+        render_tag_page(&tag);
+    }
+}
+
+// Tag pages live in the asset store, re-add them so they are certified again after an upgrade
+fn setup_tag_pages() {
+    let mut tags: Vec<String> = TAG_NOTES.with_borrow(|tag_notes| tag_notes.iter().map(|entry| entry.key().tag.clone()).collect());
+    tags.dedup();
+    for tag in tags {
+        render_tag_page(&tag);
+    }
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_workspaces() -> Vec<Workspace> {
+    let caller = ic_cdk::api::msg_caller();
+    let user_canisters = USER_CANISTERS.with(|canisters| canisters.borrow().get(&caller));
+    if let Some(user_canisters) = user_canisters {
+        let mut workspaces = Vec::new();
+        for (canister_id, domain) in user_canisters.user_canisters {
+            workspaces.push(Workspace {
+                canister_id: canister_id,
+                domain: domain,
+            });
+        }
+        return workspaces;
+    }
+    return Vec::new();
+}
+
+// Workspace deployment constants
+const DEFAULT_WORKSPACE_DEPLOYMENT_FEE: u64 = 10_000_000; // 10 USD, in plan price units
+const WORKSPACE_CANISTER_CYCLES: u128 = 1_000_000_000_000; // 1T cycles on top of the creation cost
+
+fn save_workspace_deployment(caller: Principal, mut deployment: WorkspaceDeployment) -> WorkspaceDeployment {
+    deployment.updated_at = time();
+    WORKSPACE_DEPLOYMENTS.with_borrow_mut(|deployments| {
+        deployments.insert(caller, deployment.clone());
+    });
+    deployment
+}
+
+fn fail_workspace_deployment(caller: Principal, mut deployment: WorkspaceDeployment, error: String) -> Result<Workspace, String> {
+    deployment.last_error = Some(error.clone());
+    save_workspace_deployment(caller, deployment);
+    Err(error)
+}
+
+// Journal the workspace fee as a payment out of the caller's deposit subaccount, so it is settled,
+// reconciled and reported like every other payment
+fn open_workspace_payment(caller: Principal, deployment: &WorkspaceDeployment) -> Result<PaymentRecord, String> {
+    // the deposit subaccount can only fund one payment at a time
+    if has_open_payment(caller) {
+        return Err("Another payment from this account is still being settled".to_string());
+    }
+    let token_type = deployment.token_type.clone();
+    let deposit_account = Account {
+        owner: canister_self(),
+        subaccount: Some(principal_to_subaccount(caller)),
+    };
+    let amount = price_to_token_units(deployment.fee.unwrap_or_else(workspace_fee), &token_type);
+    let mut payment = open_payment(caller, PaymentKind::Deposit, token_type.clone(), get_ledger_canister_id(&token_type), deposit_account, amount, None, None);
+    payment.purchase = Some(PaymentPurchase::Workspace);
+    save_payment(&payment);
+    Ok(payment)
+}
+
+async fn create_workspace_canister(caller: Principal) -> Result<Principal, String> {
+    let settings = CanisterSettings {
+        controllers: Some(vec![canister_self(), caller]),
+        ..Default::default()
+    };
+
+    let result = create_canister_with_extra_cycles(
+        &CreateCanisterArgs { settings: Some(settings) },
+        WORKSPACE_CANISTER_CYCLES,
+    )
+    .await
+    .map_err(|e| format!("Failed to create workspace canister: {:?}", e))?;
+
+    Ok(result.canister_id)
+}
+
+async fn install_workspace_code(canister_id: Principal, mode: CanisterInstallMode) -> Result<(), String> {
+    install_code(&InstallCodeArgs {
+        mode,
+        canister_id,
+        wasm_module: WORKSPACE_WASM.to_vec(),
+        arg: encode_args(()).unwrap(),
+    })
+    .await
+    .map_err(|e| format!("Failed to install workspace code: {:?}", e))
+}
+
+// Best effort, the owner can always set their profile directly on the workspace
+async fn sync_workspace_owner_profile(canister_id: Principal, caller: Principal) {
+    let user_profile = USER_PROFILES.with_borrow(|profiles| profiles.get(&caller).unwrap_or(UserProfile::anonymous()));
+    let result = ic_cdk::call::Call::unbounded_wait(canister_id, "set_owner_profile")
+        .with_arg(user_profile)
+        .await;
+    if let Err(e) = result {
+        ic_cdk::api::debug_print(&format!("Failed to set owner profile on workspace {}: {:?}", canister_id, e));
+    }
+}
+
+fn record_user_canister(caller: Principal, canister_id: Principal) {
+    USER_CANISTERS.with_borrow_mut(|canisters| {
+        let mut user_canisters = canisters.get(&caller).unwrap_or(UserCanister {
+            user_canisters: HashMap::new(),
+        });
+        user_canisters.user_canisters.entry(canister_id.to_text()).or_insert(None);
+        canisters.insert(caller, user_canisters);
+    });
+}
+
+async fn run_workspace_deployment(caller: Principal, token_type: TokenType) -> Result<Workspace, String> {
+    let mut deployment = match WORKSPACE_DEPLOYMENTS.with_borrow(|deployments| deployments.get(&caller)) {
+        Some(deployment) => deployment,
+        None => {
+            // a deployment already under way keeps the token it started with
+            ensure_token_accepted(&token_type)?;
+            ensure_workspace_quota(&caller)?;
+            let now = time();
+            WorkspaceDeployment {
+                token_type,
+                stage: WorkspaceDeploymentStage::AwaitingPayment,
+                fee_block_index: None,
+                fee: Some(workspace_fee()),
+                payment_id: None,
+                canister_id: None,
+                created_at: now,
+                updated_at: now,
+                last_error: None,
+            }
+        }
+    };
+
+    loop {
+        match deployment.stage {
+            WorkspaceDeploymentStage::AwaitingPayment => {
+                let payment = match deployment.payment_id.and_then(|payment_id| PAYMENTS.with_borrow(|payments| payments.get(&payment_id))) {
+                    Some(payment) => payment,
+                    None => {
+                        let payment = match open_workspace_payment(caller, &deployment) {
+                            Ok(payment) => payment,
+                            Err(e) => {
+                                // nothing was journaled, so there is nothing to resume
+                                WORKSPACE_DEPLOYMENTS.with_borrow_mut(|deployments| deployments.remove(&caller));
+                                return Err(e);
+                            }
+                        };
+                        deployment.payment_id = Some(payment.id);
+                        deployment = save_workspace_deployment(caller, deployment);
+                        payment
+                    }
+                };
+                let payment = match settle_payment(payment.id).await {
+                    Ok(payment) => payment,
+                    Err(e) => return fail_workspace_deployment(caller, deployment, e),
+                };
+                match payment.state {
+                    PaymentState::Granted => {
+                        deployment.fee_block_index = payment.block_index.clone();
+                        deployment.stage = WorkspaceDeploymentStage::FeePaid;
+                        deployment.last_error = None;
+                        deployment = save_workspace_deployment(caller, deployment);
+                    }
+                    // nothing was charged, or it is being returned
+                    PaymentState::Failed(_) | PaymentState::RefundPending | PaymentState::Refunded => {
+                        WORKSPACE_DEPLOYMENTS.with_borrow_mut(|deployments| deployments.remove(&caller));
+                        return Err(payment_response(&payment, String::new()).message);
+                    }
+                    // still settling or held for a controller, the next call picks the payment up again
+                    _ => return fail_workspace_deployment(caller, deployment, payment_response(&payment, String::new()).message),
+                }
+            }
+            WorkspaceDeploymentStage::FeePaid => {
+                match create_workspace_canister(caller).await {
+                    Ok(canister_id) => {
+                        deployment.canister_id = Some(canister_id);
+                        deployment.stage = WorkspaceDeploymentStage::CanisterCreated;
+                        deployment.last_error = None;
+                        deployment = save_workspace_deployment(caller, deployment);
+                    }
+                    Err(e) => return fail_workspace_deployment(caller, deployment, e),
+                }
+            }
+            WorkspaceDeploymentStage::CanisterCreated => {
+                let canister_id = deployment.canister_id.expect("Created workspace has no canister id");
+                // a failed attempt may still have left a module behind
+                let mode = if deployment.last_error.is_some() {
+                    CanisterInstallMode::Reinstall
+                } else {
+                    CanisterInstallMode::Install
+                };
+                match install_workspace_code(canister_id, mode).await {
+                    Ok(()) => {
+                        deployment.stage = WorkspaceDeploymentStage::CodeInstalled;
+                        deployment.last_error = None;
+                        deployment = save_workspace_deployment(caller, deployment);
+                    }
+                    Err(e) => return fail_workspace_deployment(caller, deployment, e),
+                }
+            }
+            WorkspaceDeploymentStage::CodeInstalled => {
+                let canister_id = deployment.canister_id.expect("Installed workspace has no canister id");
+                sync_workspace_owner_profile(canister_id, caller).await;
+                record_user_canister(caller, canister_id);
+                WORKSPACE_DEPLOYMENTS.with_borrow_mut(|deployments| deployments.remove(&caller));
+                return Ok(Workspace {
+                    canister_id: canister_id.to_text(),
+                    domain: None,
+                });
+            }
+        }
+    }
+}
+
+/// Deploys a new dotane_user_storage workspace for the caller.
+/// If a previous deployment failed part way, calling this again resumes it without charging twice.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn deploy_workspace(token_type: TokenType) -> Result<Workspace, String> {
+    let caller = ic_cdk::api::msg_caller();
+
+    let Some(_lock) = HeapLock::acquire(&DEPLOYING_USERS, caller) else {
+        return Err("Workspace deployment already in progress".to_string());
+    };
+
+    run_workspace_deployment(caller, token_type).await
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_workspace_deployment() -> Option<WorkspaceDeployment> {
+    let caller = ic_cdk::api::msg_caller();
+    WORKSPACE_DEPLOYMENTS.with_borrow(|deployments| deployments.get(&caller))
+}
+
+// Workspace upgrade constants
+const WORKSPACE_UPGRADE_INTERVAL_SECS: u64 = 60;
+const DEFAULT_WORKSPACE_UPGRADE_BATCH_SIZE: u32 = 10;
+const DEFAULT_WORKSPACE_UPGRADE_MAX_ATTEMPTS: u32 = 3;
+const MAX_WORKSPACE_WASMS: usize = 3;
+
+fn wasm_hash(wasm: &[u8]) -> String {
+    hex::encode(Sha256::digest(wasm))
+}
+
+// Keep the embedded workspace wasm (and the last few before it) in stable memory
+fn setup_workspace_wasms() {
+    let current_hash = wasm_hash(WORKSPACE_WASM);
+    WORKSPACE_WASMS.with_borrow_mut(|wasms| {
+        if !wasms.contains_key(&current_hash) {
+            wasms.insert(current_hash.clone(), WorkspaceWasm {
+                wasm: WORKSPACE_WASM.to_vec(),
+                added_at: time(),
+            });
+        }
+
+        let mut by_age: Vec<(u64, String)> = wasms.iter().map(|entry| (entry.value().added_at, entry.key().clone())).collect();
+        by_age.sort();
+        while by_age.len() > MAX_WORKSPACE_WASMS {
+            let (_, hash) = by_age.remove(0);
+            wasms.remove(&hash);
+        }
+    });
+}
+
+// Futures don't survive an upgrade, so anything left InProgress is retried
+fn reset_interrupted_workspace_upgrades() {
+    WORKSPACE_UPGRADES.with_borrow_mut(|upgrades| {
+        let interrupted: Vec<(Principal, WorkspaceUpgradeStatus)> = upgrades
+            .iter()
+            .filter(|entry| entry.value().state == WorkspaceUpgradeState::InProgress)
+            .map(|entry| (*entry.key(), entry.value()))
+            .collect();
+        for (canister_id, mut status) in interrupted {
+            status.state = WorkspaceUpgradeState::Pending;
+            status.updated_at = time();
+            upgrades.insert(canister_id, status);
+        }
+    });
+}
+
+fn set_workspace_upgrade_status(canister_id: Principal, mut status: WorkspaceUpgradeStatus) {
+    status.updated_at = time();
+    WORKSPACE_UPGRADES.with_borrow_mut(|upgrades| {
+        upgrades.insert(canister_id, status);
+    });
+}
+
+async fn upgrade_workspace(canister_id: Principal, wasm: Vec<u8>) -> Result<(), String> {
+    stop_canister(&StopCanisterArgs { canister_id })
+        .await
+        .map_err(|e| format!("Failed to stop workspace: {:?}", e))?;
+
+    let install_result = install_code(&InstallCodeArgs {
+        mode: CanisterInstallMode::Upgrade(None),
+        canister_id,
+        wasm_module: wasm,
+        arg: encode_args(()).unwrap(),
+    })
+    .await
+    .map_err(|e| format!("Failed to upgrade workspace: {:?}", e));
+
+    // restart even if the upgrade failed, the old module is still installed
+    let start_result = start_canister(&StartCanisterArgs { canister_id })
+        .await
+        .map_err(|e| format!("Failed to start workspace: {:?}", e));
+
+    install_result?;
+    start_result
+}
+
+async fn run_workspace_upgrade(canister_id: Principal, mut status: WorkspaceUpgradeStatus) {
+    let wasm = WORKSPACE_WASMS.with_borrow(|wasms| wasms.get(&status.target_hash));
+    let Some(wasm) = wasm else {
+        status.state = WorkspaceUpgradeState::Failed;
+        status.last_error = Some(format!("Wasm {} is no longer available", status.target_hash));
+        set_workspace_upgrade_status(canister_id, status);
+        return;
+    };
+
+    let module_hash = match canister_status(&CanisterStatusArgs { canister_id }).await {
+        Ok(canister_status) => canister_status.module_hash.map(hex::encode),
+        Err(e) => {
+            status.state = WorkspaceUpgradeState::Failed;
+            status.last_error = Some(format!("Failed to get workspace status: {:?}", e));
+            set_workspace_upgrade_status(canister_id, status);
+            return;
+        }
+    };
+
+    let result = if module_hash.as_deref() == Some(status.target_hash.as_str()) {
+        Ok(())
+    } else {
+        if !status.rollback {
+            status.previous_hash = module_hash;
+        }
+        upgrade_workspace(canister_id, wasm.wasm).await
+    };
+
+    match result {
+        Ok(()) => {
+            status.state = if status.rollback {
+                WorkspaceUpgradeState::RolledBack
+            } else {
+                WorkspaceUpgradeState::Upgraded
+            };
+            status.last_error = None;
+        }
+        Err(e) => {
+            ic_cdk::api::debug_print(&format!("Failed to upgrade workspace {}: {}", canister_id, e));
+            status.state = WorkspaceUpgradeState::Failed;
+            status.last_error = Some(e);
+        }
+    }
+    set_workspace_upgrade_status(canister_id, status);
+}
+
+fn process_workspace_upgrades() {
+    let rollout = WORKSPACE_UPGRADE_ROLLOUT.with_borrow(|rollout| rollout.get().clone());
+    if !rollout.active || rollout.paused {
+        return;
+    }
+
+    let mut in_progress = 0u32;
+    let mut remaining = 0u32;
+    let mut batch = Vec::new();
+    WORKSPACE_UPGRADES.with_borrow(|upgrades| {
+        for entry in upgrades.iter() {
+            let status = entry.value();
+            let retryable = status.state == WorkspaceUpgradeState::Failed && status.attempts < rollout.max_attempts;
+            match status.state {
+                WorkspaceUpgradeState::InProgress => in_progress += 1,
+                WorkspaceUpgradeState::Pending => remaining += 1,
+                _ if retryable => remaining += 1,
+                _ => continue,
+            }
+            if status.state != WorkspaceUpgradeState::InProgress {
+                batch.push((*entry.key(), status));
+            }
+        }
+    });
+
+    if in_progress == 0 && remaining == 0 {
+        WORKSPACE_UPGRADE_ROLLOUT.with_borrow_mut(|cell| {
+            let mut rollout = cell.get().clone();
+            rollout.active = false;
+            cell.set(rollout);
+        });
+        return;
+    }
+
+    let free_slots = rollout.batch_size.saturating_sub(in_progress) as usize;
+    for (canister_id, mut status) in batch.into_iter().take(free_slots) {
+        status.state = WorkspaceUpgradeState::InProgress;
+        status.attempts += 1;
+        set_workspace_upgrade_status(canister_id, status.clone());
+        ic_cdk::futures::spawn(run_workspace_upgrade(canister_id, status));
+    }
+}
+
+/// Starts rolling the embedded workspace wasm out to every canister in USER_CANISTERS.
+/// Returns the sha256 of the wasm being shipped.
+#[ic_cdk::update(guard = "is_controller")]
+fn start_workspace_upgrade(batch_size: Option<u32>, max_attempts: Option<u32>) -> Result<String, String> {
+    let rollout = WORKSPACE_UPGRADE_ROLLOUT.with_borrow(|rollout| rollout.get().clone());
+    if rollout.active {
+        return Err("A workspace upgrade is already in progress".to_string());
+    }
+
+    let target_hash = wasm_hash(WORKSPACE_WASM);
+    let now = time();
+
+    WORKSPACE_UPGRADES.with_borrow_mut(|upgrades| {
+        upgrades.clear_new();
+        USER_CANISTERS.with_borrow(|canisters| {
+            for entry in canisters.iter() {
+                let owner = *entry.key();
+                for canister_id in entry.value().user_canisters.keys() {
+                    let Ok(canister_id) = Principal::from_text(canister_id) else {
+                        continue;
+                    };
+                    upgrades.insert(canister_id, WorkspaceUpgradeStatus {
+                        owner,
+                        target_hash: target_hash.clone(),
+                        previous_hash: None,
+                        state: WorkspaceUpgradeState::Pending,
+                        rollback: false,
+                        attempts: 0,
+                        last_error: None,
+                        updated_at: now,
+                    });
+                }
+            }
+        });
+    });
+
+    WORKSPACE_UPGRADE_ROLLOUT.with_borrow_mut(|cell| {
+        cell.set(WorkspaceUpgradeRollout {
+            active: true,
+            paused: false,
+            wasm_hash: target_hash.clone(),
+            batch_size: batch_size.unwrap_or(DEFAULT_WORKSPACE_UPGRADE_BATCH_SIZE).max(1),
+            max_attempts: max_attempts.unwrap_or(DEFAULT_WORKSPACE_UPGRADE_MAX_ATTEMPTS).max(1),
+            started_at: now,
+        });
+    });
+
+    Ok(target_hash)
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn set_workspace_upgrade_paused(paused: bool) -> Result<(), String> {
+    WORKSPACE_UPGRADE_ROLLOUT.with_borrow_mut(|cell| {
+        let mut rollout = cell.get().clone();
+        if !rollout.active {
+            return Err("No workspace upgrade in progress".to_string());
+        }
+        rollout.paused = paused;
+        cell.set(rollout);
+        Ok(())
+    })
+}
+
+/// Queues every upgraded workspace to go back to the module it had before this rollout.
+/// Returns the number of workspaces queued.
+#[ic_cdk::update(guard = "is_controller")]
+fn rollback_workspace_upgrade() -> Result<u32, String> {
+    let mut queued = 0u32;
+    WORKSPACE_UPGRADES.with_borrow_mut(|upgrades| {
+        let upgraded: Vec<(Principal, WorkspaceUpgradeStatus)> = upgrades
+            .iter()
+            .filter(|entry| entry.value().state == WorkspaceUpgradeState::Upgraded && !entry.value().rollback)
+            .map(|entry| (*entry.key(), entry.value()))
+            .collect();
+        for (canister_id, mut status) in upgraded {
+            let Some(previous_hash) = status.previous_hash.clone() else {
+                continue;
+            };
+            if !WORKSPACE_WASMS.with_borrow(|wasms| wasms.contains_key(&previous_hash)) {
+                continue;
+            }
+            status.previous_hash = Some(status.target_hash);
+            status.target_hash = previous_hash;
+            status.state = WorkspaceUpgradeState::Pending;
+            status.rollback = true;
+            status.attempts = 0;
+            status.last_error = None;
+            status.updated_at = time();
+            upgrades.insert(canister_id, status);
+            queued += 1;
+        }
+    });
+
+    if queued == 0 {
+        return Err("No upgraded workspaces can be rolled back".to_string());
+    }
+
+    WORKSPACE_UPGRADE_ROLLOUT.with_borrow_mut(|cell| {
+        let mut rollout = cell.get().clone();
+        rollout.active = true;
+        rollout.paused = false;
+        if rollout.batch_size == 0 {
+            rollout.batch_size = DEFAULT_WORKSPACE_UPGRADE_BATCH_SIZE;
+            rollout.max_attempts = DEFAULT_WORKSPACE_UPGRADE_MAX_ATTEMPTS;
+        }
+        cell.set(rollout);
+    });
+
+    Ok(queued)
+}
+
+#[ic_cdk::query(guard = "is_controller")]
+fn get_workspace_upgrade_rollout() -> WorkspaceUpgradeRollout {
+    WORKSPACE_UPGRADE_ROLLOUT.with_borrow(|rollout| rollout.get().clone())
+}
+
+#[ic_cdk::query(guard = "is_controller")]
+fn list_workspace_upgrades(start_after: Option<Principal>, limit: u32) -> Vec<(Principal, WorkspaceUpgradeStatus)> {
+    let start = start_after.map(Bound::Excluded).unwrap_or(Bound::Unbounded);
+    WORKSPACE_UPGRADES.with_borrow(|upgrades| {
+        upgrades
+            .range((start, Bound::Unbounded))
+            .take(limit as usize)
+            .map(|entry| (*entry.key(), entry.value()))
+            .collect()
+    })
+}
+
+// Workspace cycles constants
+const WORKSPACE_CYCLES_CHECK_INTERVAL_SECS: u64 = 6 * 60 * 60;
+const WORKSPACE_CYCLES_THRESHOLD: u128 = 500_000_000_000; // top up below 0.5T cycles
+const WORKSPACE_TOP_UP_AMOUNT: u128 = 1_000_000_000_000; // 1T cycles per top up
+const PREMIUM_CYCLES_ALLOWANCE: u128 = 5_000_000_000_000; // 5T cycles per period
+const FREE_CYCLES_ALLOWANCE: u128 = 0;
+const CYCLES_BUDGET_PERIOD_MILLIS: u64 = 30 * 24 * 60 * 60 * 1000;
+// never let top ups take the backend itself below this
+const BACKEND_CYCLES_RESERVE: u128 = 10_000_000_000_000;
+const MAX_CYCLES_SAMPLES_PER_WORKSPACE: usize = 120;
+
+fn cycles_allowance(owner: &Principal) -> u128 {
+    if PREMIUM_USERS_SET.with_borrow(|set| set.contains(owner)) {
+        PREMIUM_CYCLES_ALLOWANCE
+    } else {
+        FREE_CYCLES_ALLOWANCE
+    }
+}
+
+// The owner's budget, rolled over into a fresh period once the current one has ended
+fn current_cycles_budget(owner: &Principal) -> CyclesBudget {
+    let now = get_current_time_in_milli();
+    let budget = CYCLES_BUDGETS.with_borrow(|budgets| budgets.get(owner)).unwrap_or_default();
+    if now >= budget.period_start + CYCLES_BUDGET_PERIOD_MILLIS {
+        CyclesBudget {
+            period_start: now,
+            spent: 0,
+        }
+    } else {
+        budget
+    }
+}
+
+fn record_cycles_sample(canister_id: Principal, sample: WorkspaceCyclesSample) {
+    WORKSPACE_CYCLES_HISTORY.with_borrow_mut(|history| {
+        history.insert(WorkspaceCyclesKey { canister_id, timestamp: sample.timestamp }, sample);
+
+        let keys: Vec<WorkspaceCyclesKey> = history
+            .range(WorkspaceCyclesKey { canister_id, timestamp: 0 }..=WorkspaceCyclesKey { canister_id, timestamp: u64::MAX })
+            .map(|entry| *entry.key())
+            .collect();
+        if keys.len() > MAX_CYCLES_SAMPLES_PER_WORKSPACE {
+            for key in &keys[..keys.len() - MAX_CYCLES_SAMPLES_PER_WORKSPACE] {
+                history.remove(key);
+            }
+        }
+    });
+}
+
+async fn top_up_workspace(owner: Principal, canister_id: Principal) -> Result<u128, String> {
+    let mut budget = current_cycles_budget(&owner);
+    let allowance = cycles_allowance(&owner);
+    let amount = WORKSPACE_TOP_UP_AMOUNT.min(allowance.saturating_sub(budget.spent));
+    if amount == 0 {
+        return Err("Cycles budget exhausted".to_string());
+    }
+
+    if ic_cdk::api::canister_cycle_balance() < BACKEND_CYCLES_RESERVE + amount {
+        return Err("Backend cycles reserve reached".to_string());
+    }
+
+    // reserve the cycles before awaiting so concurrent checks can't overspend the budget
+    budget.spent += amount;
+    CYCLES_BUDGETS.with_borrow_mut(|budgets| budgets.insert(owner, budget.clone()));
+
+    match deposit_cycles(&DepositCyclesArgs { canister_id }, amount).await {
+        Ok(()) => Ok(amount),
+        Err(e) => {
+            CYCLES_BUDGETS.with_borrow_mut(|budgets| {
+                let mut budget = budgets.get(&owner).unwrap_or_default();
+                budget.spent = budget.spent.saturating_sub(amount);
+                budgets.insert(owner, budget);
+            });
+            Err(format!("Failed to deposit cycles: {:?}", e))
+        }
+    }
+}
+
+async fn monitor_workspace_cycles(owner: Principal, canister_id: Principal) {
+    let timestamp = get_current_time_in_milli();
+    let cycles = match canister_status(&CanisterStatusArgs { canister_id }).await {
+        Ok(status) => u128::try_from(status.cycles.0).unwrap_or(u128::MAX),
+        Err(e) => {
+            // no sample, a zero balance would read as a drained workspace
+            ic_cdk::api::debug_print(&format!("Failed to get status of workspace {}: {:?}", canister_id, e));
+            return;
+        }
+    };
+
+    let mut sample = WorkspaceCyclesSample {
+        timestamp,
+        cycles,
+        topped_up: None,
+        note: None,
+    };
+
+    if cycles < WORKSPACE_CYCLES_THRESHOLD {
+        match top_up_workspace(owner, canister_id).await {
+            Ok(amount) => sample.topped_up = Some(amount),
+            Err(e) => {
+                ic_cdk::api::debug_print(&format!("Failed to top up workspace {}: {}", canister_id, e));
+                sample.note = Some(e);
+            }
+        }
+    }
+
+    record_cycles_sample(canister_id, sample);
+}
+
+fn check_workspace_cycles() {
+    let Some(lock) = FlagLock::acquire(&MONITORING_CYCLES) else {
+        return;
+    };
+
+    let workspaces: Vec<(Principal, Principal)> = USER_CANISTERS.with_borrow(|canisters| {
+        canisters
+            .iter()
+            .flat_map(|entry| {
+                let owner = *entry.key();
+                entry
+                    .value()
+                    .user_canisters
+                    .keys()
+                    .filter_map(|canister_id| Principal::from_text(canister_id).ok())
+                    .map(|canister_id| (owner, canister_id))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    });
+
+    ic_cdk::futures::spawn(async move {
+        let _lock = lock;
+        for (owner, canister_id) in workspaces {
+            monitor_workspace_cycles(owner, canister_id).await;
+        }
+    });
+}
+
+fn is_workspace_owner(owner: &Principal, canister_id: &Principal) -> bool {
+    USER_CANISTERS.with_borrow(|canisters| {
+        canisters
+            .get(owner)
+            .map(|user_canisters| user_canisters.user_canisters.contains_key(&canister_id.to_text()))
+            .unwrap_or(false)
+    })
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_workspace_cycles_history(canister_id: Principal) -> Result<Vec<WorkspaceCyclesSample>, String> {
+    let caller = ic_cdk::api::msg_caller();
+    if !is_workspace_owner(&caller, &canister_id) {
+        return Err("Workspace not found".to_string());
+    }
+
+    Ok(WORKSPACE_CYCLES_HISTORY.with_borrow(|history| {
+        history
+            .range(WorkspaceCyclesKey { canister_id, timestamp: 0 }..=WorkspaceCyclesKey { canister_id, timestamp: u64::MAX })
+            .map(|entry| entry.value())
+            .collect()
+    }))
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_cycles_budget() -> CyclesBudgetInfo {
+    let caller = ic_cdk::api::msg_caller();
+    let budget = current_cycles_budget(&caller);
+    CyclesBudgetInfo {
+        allowance: cycles_allowance(&caller),
+        spent: budget.spent,
+        period_start: budget.period_start,
+        period_end: budget.period_start + CYCLES_BUDGET_PERIOD_MILLIS,
+    }
+}
+
+// Custom domain constants
+const CUSTOM_DOMAINS_API: &str = "https://icp0.io/custom-domains/v1";
+const DOMAIN_STATUS_MAX_RESPONSE_BYTES: u64 = 4 * 1024;
+// Each refresh pays for an HTTPS outcall, so a domain is checked at most once a minute
+const DOMAIN_STATUS_REFRESH_INTERVAL: u64 = 60 * 1000;
+
+fn normalize_domain(domain: &str) -> Result<String, String> {
+    let domain = domain.trim().trim_end_matches('.').to_lowercase();
+    let valid_labels = domain.split('.').all(|label| {
+        !label.is_empty()
+            && label.len() <= 63
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    });
+    if domain.len() > 253 || !domain.contains('.') || !valid_labels {
+        return Err("Invalid domain".to_string());
+    }
+    Ok(domain)
+}
+
+fn workspace_domain_names(canister_id: &Principal) -> Vec<String> {
+    WORKSPACE_DOMAINS.with_borrow(|domains| {
+        domains
+            .iter()
+            .filter(|entry| entry.value().canister_id == *canister_id)
+            .map(|entry| entry.key().clone())
+            .collect()
+    })
+}
+
+// Rewrite the workspace's /.well-known/ic-domains from the domains attached to it
+async fn sync_workspace_ic_domains(canister_id: Principal) -> Result<(), String> {
+    let domains = workspace_domain_names(&canister_id);
+    ic_cdk::call::Call::unbounded_wait(canister_id, "set_ic_domains")
+        .with_arg(domains)
+        .await
+        .map_err(|e| format!("Failed to call set_ic_domains: {:?}", e))?
+        .candid::<Result<(), String>>()
+        .map_err(|e| format!("Failed to decode set_ic_domains response: {:?}", e))?
+}
+
+// Workspace.domain shows the first domain still attached, if any
+fn set_workspace_primary_domain(owner: Principal, canister_id: Principal) {
+    let primary = workspace_domain_names(&canister_id).into_iter().next();
+    USER_CANISTERS.with_borrow_mut(|canisters| {
+        if let Some(mut user_canisters) = canisters.get(&owner) {
+            user_canisters.user_canisters.insert(canister_id.to_text(), primary);
+            canisters.insert(owner, user_canisters);
+        }
+    });
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn attach_workspace_domain(canister_id: Principal, domain: String) -> Result<WorkspaceDomain, String> {
+    let caller = ic_cdk::api::msg_caller();
+    if !is_workspace_owner(&caller, &canister_id) {
+        return Err("Workspace not found".to_string());
+    }
+
+    let domain = normalize_domain(&domain)?;
+    if let Some(existing) = WORKSPACE_DOMAINS.with_borrow(|domains| domains.get(&domain)) {
+        if existing.canister_id != canister_id {
+            return Err("Domain is already attached to another workspace".to_string());
+        }
+        return Ok(existing);
+    }
+
+    let now = get_current_time_in_milli();
+    let workspace_domain = WorkspaceDomain {
+        domain: domain.clone(),
+        canister_id,
+        owner: caller,
+        status: DomainStatus::PendingDns,
+        created_at: now,
+        updated_at: now,
+        last_checked_at: None,
+    };
+    WORKSPACE_DOMAINS.with_borrow_mut(|domains| {
+        domains.insert(domain.clone(), workspace_domain.clone());
+    });
+
+    if let Err(e) = sync_workspace_ic_domains(canister_id).await {
+        WORKSPACE_DOMAINS.with_borrow_mut(|domains| domains.remove(&domain));
+        return Err(e);
+    }
+
+    set_workspace_primary_domain(caller, canister_id);
+    Ok(workspace_domain)
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn detach_workspace_domain(canister_id: Principal, domain: String) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let domain = normalize_domain(&domain)?;
+    let workspace_domain = WORKSPACE_DOMAINS.with_borrow(|domains| domains.get(&domain));
+    let Some(workspace_domain) = workspace_domain.filter(|d| d.canister_id == canister_id && d.owner == caller) else {
+        return Err("Domain not found".to_string());
+    };
+
+    WORKSPACE_DOMAINS.with_borrow_mut(|domains| domains.remove(&domain));
+
+    if let Err(e) = sync_workspace_ic_domains(canister_id).await {
+        WORKSPACE_DOMAINS.with_borrow_mut(|domains| domains.insert(domain, workspace_domain));
+        return Err(e);
+    }
+
+    set_workspace_primary_domain(caller, canister_id);
+    Ok(())
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn list_workspace_domains(canister_id: Principal) -> Result<Vec<WorkspaceDomain>, String> {
+    let caller = ic_cdk::api::msg_caller();
+    if !is_workspace_owner(&caller, &canister_id) {
+        return Err("Workspace not found".to_string());
+    }
+
+    Ok(WORKSPACE_DOMAINS.with_borrow(|domains| {
+        domains
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|d| d.canister_id == canister_id)
+            .collect()
+    }))
+}
+
+#[ic_cdk::query]
+fn transform_domain_status(args: TransformArgs) -> HttpRequestResult {
+    // drop headers so every replica sees the same response
+    HttpRequestResult {
+        status: args.response.status,
+        headers: vec![],
+        body: args.response.body,
+    }
+}
+
+fn parse_domain_status(canister_id: &Principal, response: &HttpRequestResult) -> Result<DomainStatus, String> {
+    if response.status == Nat::from(404u32) {
+        return Ok(DomainStatus::PendingDns);
+    }
+
+    let value: serde_json::Value = serde_json::from_slice(&response.body)
+        .map_err(|e| format!("Failed to parse domain status: {}", e))?;
+    let data = value.get("data").ok_or("Domain status response has no data")?;
+
+    if let Some(registered_canister) = data.get("canister_id").and_then(|v| v.as_str()) {
+        if registered_canister != canister_id.to_text() {
+            return Ok(DomainStatus::Failed(format!("Domain is registered to canister {}", registered_canister)));
+        }
+    }
+
+    let status = match data.get("registration_status").and_then(|v| v.as_str()) {
+        Some("registered") => DomainStatus::Verified,
+        Some("failed") | Some("expired") => DomainStatus::Failed(
+            value.get("message").and_then(|v| v.as_str()).unwrap_or("Registration failed").to_string(),
+        ),
+        _ => DomainStatus::Registering,
+    };
+    Ok(status)
+}
+
+/// Checks the boundary node registration for a domain and records its verification state.
+/// Refreshes within DOMAIN_STATUS_REFRESH_INTERVAL of the last check are refused.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn refresh_workspace_domain_status(domain: String) -> Result<WorkspaceDomain, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let domain = normalize_domain(&domain)?;
+    let workspace_domain = WORKSPACE_DOMAINS.with_borrow(|domains| domains.get(&domain));
+    let Some(mut workspace_domain) = workspace_domain.filter(|d| d.owner == caller) else {
+        return Err("Domain not found".to_string());
+    };
+    let now = get_current_time_in_milli();
+    if let Some(last_checked_at) = workspace_domain.last_checked_at {
+        let next_check_at = last_checked_at.saturating_add(DOMAIN_STATUS_REFRESH_INTERVAL);
+        if now < next_check_at {
+            return Err(format!("Domain status was checked recently, try again in {} seconds", (next_check_at - now).div_ceil(1000)));
+        }
+    }
+    // stamped before the outcall so concurrent refreshes are refused too
+    workspace_domain.last_checked_at = Some(now);
+    WORKSPACE_DOMAINS.with_borrow_mut(|domains| domains.insert(domain.clone(), workspace_domain.clone()));
+
+    let request = HttpRequestArgs {
+        url: format!("{}/{}", CUSTOM_DOMAINS_API, domain),
+        max_response_bytes: Some(DOMAIN_STATUS_MAX_RESPONSE_BYTES),
+        method: HttpMethod::GET,
+        headers: vec![],
+        body: None,
+        transform: Some(transform_context_from_query("transform_domain_status".to_string(), vec![])),
+        ..Default::default()
+    };
+    let response = outcall_http_request(&request)
+        .await
+        .map_err(|e| format!("Failed to fetch domain status: {:?}", e))?;
+    let status = parse_domain_status(&workspace_domain.canister_id, &response)?;
+
+    // the domain may have been detached while the outcall was in flight
+    WORKSPACE_DOMAINS.with_borrow_mut(|domains| {
+        let mut workspace_domain = domains.get(&domain).ok_or("Domain not found".to_string())?;
+        workspace_domain.status = status;
+        workspace_domain.updated_at = get_current_time_in_milli();
+        domains.insert(domain, workspace_domain.clone());
+        Ok(workspace_domain)
+    })
+}
+
+#[ic_cdk::update]
+fn create_user_profile(
+    req: CreateUserProfileRequest
+) -> Result<(), String> {
+    let current_time = get_current_time_in_milli();
+    let user_id = ic_cdk::api::msg_caller();
+
+    let profile = UserProfile {
+        name: req.name.trim().to_string(),
+        email: req.email.trim().to_string(),
+        bio: req.bio.trim().to_string(),
+        avatar: req.avatar.trim().to_string(),
+        created_at: current_time,
+        updated_at: current_time,
+        // payment may have landed before the profile was created
+        premium: PREMIUM_USERS_SET.with_borrow(|set| set.contains(&user_id)),
+        marked_public: req.marked_public,
+    };
+
+    USER_PROFILES.with(|profiles| {
+        profiles.borrow_mut().insert(user_id, profile);
+    });
+
+    Ok(())
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_profile() -> Result<UserProfile, String> {
+    let user_id = ic_cdk::api::msg_caller();
+    USER_PROFILES.with(|profiles| {
+        let profile = profiles.borrow().get(&user_id);
+        if let Some(profile) = profile {
+            Ok(profile)
+        } else {    
+            Ok(UserProfile::anonymous())
+        }
+    })
+}
+
+#[ic_cdk::query]
+fn get_user_profile(user_id: String) -> Result<UserProfile, String> {
+    let user_id = Principal::from_text(&user_id).unwrap();
+    USER_PROFILES.with(|profiles| {
+        let profile = profiles.borrow().get(&user_id);
+        if let Some(profile) = profile {
+            if !profile.marked_public {
+                return Err("User profile is not marked public".to_string());
+            }
+            Ok(profile)
+        } else {
+            Err("User profile not found".to_string())
+        }
+    })
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn is_workspace_premium_user() -> bool {
+    let caller = ic_cdk::api::msg_caller();
+    let premium_users_set = PREMIUM_USERS_SET.with_borrow(|set| set.contains(&caller));
+    return premium_users_set;
+}
+
+// #[ic_cdk::query]
+// fn http_request(req : HttpRe)
+
+#[ic_cdk::update]
+fn update_user_profile(
+    req: UpdateUserProfileRequest
+) -> Result<(), String> {
+    let user_id = ic_cdk::api::msg_caller();
+    let current_time = get_current_time_in_milli();
+
+    USER_PROFILES.with(|profiles| {
+        let mut profiles_ref = profiles.borrow_mut();
+        if let Some(mut profile) = profiles_ref.get(&user_id) {
+            profile.name = req.name.unwrap_or(profile.name);
+            profile.email = req.email.unwrap_or(profile.email);
+            profile.bio = req.bio.unwrap_or(profile.bio);
+            profile.avatar = req.avatar.unwrap_or(profile.avatar);
+            profile.updated_at = current_time;
+            profile.marked_public = req.marked_public.unwrap_or(profile.marked_public);
+            profiles_ref.insert(user_id, profile);
+            Ok(())
+        } else {
+            Err("User profile not found".to_string())
+        }
+    })
+}
+
+// Plan prices are kept in millionths of a dollar
+const PRICE_DECIMALS: u32 = 6;
+
+// Pricing used until a controller changes it
+fn default_pricing_config() -> PricingConfig {
+    PricingConfig {
+        tokens: vec![
+            TokenConfig {
+                token_type: TokenType::CKUSDC,
+                symbol: "ckUSDC".to_string(),
+                ledger: Principal::from_text("xevnm-gaaaa-aaaar-qafnq-cai").unwrap(),
+                decimals: 6,
+                enabled: true,
+            },
+            TokenConfig {
+                token_type: TokenType::CKUSDT,
+                symbol: "ckUSDT".to_string(),
+                ledger: Principal::from_text("cngnf-vqaaa-aaaar-qag4q-cai").unwrap(),
+                decimals: 6,
+                enabled: true,
+            },
+        ],
+        plans: vec![
+            PlanPrice {
+                tier: Some(EntitlementTier::Pro),
+                period: PaymentPeriod::Monthly,
+                amount: 6_000_000, // 6 USD
+                promo_amount: Some(3_990_000), // 3.99 USD
+            },
+            PlanPrice {
+                tier: Some(EntitlementTier::Pro),
+                period: PaymentPeriod::Yearly,
+                amount: 60_000_000, // 60 USD
+                promo_amount: Some(55_000_000), // 55 USD
+            },
+        ],
+        workspace_fee: Some(DEFAULT_WORKSPACE_DEPLOYMENT_FEE),
+    }
+}
+
+fn get_pricing() -> PricingConfig {
+    PRICING_CONFIG.with_borrow(|config| config.get().clone())
+}
+
+fn workspace_fee() -> u64 {
+    get_pricing().workspace_fee.unwrap_or(DEFAULT_WORKSPACE_DEPLOYMENT_FEE)
+}
+
+fn get_token_config(token_type: &TokenType) -> TokenConfig {
+    get_pricing()
+        .tokens
+        .into_iter()
+        .find(|token| token.token_type == *token_type)
+        .or_else(|| default_pricing_config().tokens.into_iter().find(|token| token.token_type == *token_type))
+        .expect("Every token type has a default config")
+}
+
+fn ensure_token_accepted(token_type: &TokenType) -> Result<(), String> {
+    let token = get_token_config(token_type);
+    if !token.enabled {
+        return Err(format!("{} payments are not accepted", token.symbol));
+    }
+    Ok(())
+}
+
+fn token_decimals(token_type: &TokenType) -> u32 {
+    get_token_config(token_type).decimals as u32
+}
+
+// Convert a plan price into the token's smallest unit
+fn price_to_token_units(price: u64, token_type: &TokenType) -> u64 {
+    let decimals = token_decimals(token_type);
+    if decimals >= PRICE_DECIMALS {
+        price.saturating_mul(10u64.saturating_pow(decimals - PRICE_DECIMALS))
+    } else {
+        price / 10u64.pow(PRICE_DECIMALS - decimals)
+    }
+}
+
+// System account for receiving payments
+fn get_system_account() -> Account {
+    // This should be the account where payments are received
+    // For now, using a placeholder - you'll need to set this to your actual system account
+    Account {
+        owner: canister_self(),
+        subaccount: None,
+    }
+}
+
+// Get the appropriate ledger canister ID based on token type
+fn get_ledger_canister_id(token_type: &TokenType) -> Principal {
+    get_token_config(token_type).ledger
+}
+
+fn get_plan_price(tier: &EntitlementTier, payment_period: &PaymentPeriod) -> Result<PlanPrice, String> {
+    let tier = tier.normalized();
+    get_pricing()
+        .plans
+        .into_iter()
+        .find(|plan| plan.plan_tier() == tier && plan.period == *payment_period)
+        .ok_or(format!("No price configured for {:?} {:?}", tier, payment_period))
+}
+
+// Promo code constants
+const MIN_PROMO_CODE_LEN: usize = 3;
+const MAX_PROMO_CODE_LEN: usize = 32;
+
+// Code the Fusion Wallet checkout sends to get the plans' promo price
+const FUSION_WALLET_PROMO_CODE: &str = "FUSIONWALLET";
+
+// The Fusion Wallet promo price is for its ICRC-2 checkout only, deposits pay the regular price
+fn ensure_promo_code_allowed(code: &str, kind: &PaymentKind) -> Result<(), String> {
+    if code == FUSION_WALLET_PROMO_CODE && !matches!(kind, PaymentKind::Approval) {
+        return Err("This promo code only applies to Fusion Wallet payments".to_string());
+    }
+    Ok(())
+}
+
+// Codes are matched case-insensitively and stored upper case
+fn normalize_promo_code(code: &str) -> Result<String, String> {
+    let code = code.trim().to_ascii_uppercase();
+    if code.len() < MIN_PROMO_CODE_LEN || code.len() > MAX_PROMO_CODE_LEN {
+        return Err(format!(
+            "Promo codes must be {} to {} characters long",
+            MIN_PROMO_CODE_LEN, MAX_PROMO_CODE_LEN
+        ));
+    }
+    if !code.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err("Promo codes may only contain letters, digits, '-' and '_'".to_string());
+    }
+    Ok(code)
+}
+
+// Fusion Wallet payments used to get promo pricing unconditionally, keep that as a code controllers can change
+fn setup_default_promo_codes() {
+    PROMO_CODES.with_borrow_mut(|codes| {
+        if codes.contains_key(&FUSION_WALLET_PROMO_CODE.to_string()) {
+            return;
+        }
+        let now = get_current_time_in_milli();
+        codes.insert(FUSION_WALLET_PROMO_CODE.to_string(), PromoCode {
+            config: PromoCodeConfig {
+                code: FUSION_WALLET_PROMO_CODE.to_string(),
+                discount: PromoDiscount::PlanPromo,
+                plans: Vec::new(),
+                starts_at: None,
+                ends_at: None,
+                max_redemptions: None,
+                max_redemptions_per_principal: None,
+                enabled: true,
+            },
+            redemptions: 0,
+            created_at: now,
+            updated_at: now,
+        });
+    });
+}
+
+fn promo_uses(code: &str, principal: Principal) -> u32 {
+    PROMO_USES.with_borrow(|uses| {
+        uses.get(&PromoUseKey { code: code.to_string(), principal }).unwrap_or_default()
+    })
+}
+
+// Check a promo code for this payer and plan, returning the normalized code and the discounted plan price
+fn apply_promo_code(code: &str, payer: Principal, plan: &PlanPrice, now: u64) -> Result<(String, u64), String> {
+    let code = normalize_promo_code(code)?;
+    let promo = PROMO_CODES
+        .with_borrow(|codes| codes.get(&code))
+        .filter(|promo| promo.config.enabled)
+        .ok_or("Invalid promo code".to_string())?;
+
+    if promo.config.starts_at.is_some_and(|starts_at| now < starts_at) {
+        return Err("This promo code is not active yet".to_string());
+    }
+    if promo.config.ends_at.is_some_and(|ends_at| now > ends_at) {
+        return Err("This promo code has expired".to_string());
+    }
+    if !promo.config.plans.is_empty() && !promo.config.plans.iter().any(|promo_plan| promo_plan.matches(plan)) {
+        return Err(format!("This promo code does not apply to the {:?} {:?} plan", plan.plan_tier(), plan.period));
+    }
+    if promo.config.max_redemptions.is_some_and(|max| promo.redemptions >= max) {
+        return Err("This promo code has been fully redeemed".to_string());
+    }
+    if promo.config.max_redemptions_per_principal.is_some_and(|max| promo_uses(&code, payer) >= max) {
+        return Err("You have already used this promo code".to_string());
+    }
+
+    let price = match promo.config.discount {
+        PromoDiscount::Percentage(percent) => plan.amount - plan.amount * percent as u64 / 100,
+        PromoDiscount::Fixed(discount) => plan.amount.saturating_sub(discount),
+        PromoDiscount::PlanPromo => plan.promo_amount.ok_or(format!("The {:?} plan has no promo price", plan.period))?,
+    };
+    if price == 0 {
+        return Err("This promo code does not apply to the plan price".to_string());
+    }
+    Ok((code, price))
+}
+
+// Price of a premium plan in token units, after an optional promo code
+fn quote_premium(
+    payer: Principal,
+    tier: &EntitlementTier,
+    token_type: &TokenType,
+    payment_period: &PaymentPeriod,
+    promo_code: Option<&str>,
+) -> Result<PriceQuote, String> {
+    ensure_token_accepted(token_type)?;
+    let current_tier = user_tier(&payer);
+    if current_tier.rank() > tier.rank() {
+        return Err(format!("You already have an active {:?} plan", current_tier));
+    }
+    let plan = get_plan_price(tier, payment_period)?;
+    quote_plan(payer, &plan, token_type, promo_code, get_current_time_in_milli())
+}
+
+fn quote_plan(payer: Principal, plan: &PlanPrice, token_type: &TokenType, promo_code: Option<&str>, now: u64) -> Result<PriceQuote, String> {
+    let price = price_to_token_units(plan.amount, token_type);
+    let (promo_code, amount) = match promo_code {
+        Some(code) => {
+            let (code, discounted) = apply_promo_code(code, payer, plan, now)?;
+            (Some(code), price_to_token_units(discounted, token_type))
+        }
+        None => (None, price),
+    };
+    if amount == 0 {
+        return Err("Plan price is too small for this token".to_string());
+    }
+    Ok(PriceQuote {
+        tier: plan.plan_tier(),
+        period: plan.period.clone(),
+        token_type: token_type.clone(),
+        price,
+        discount: price - amount,
+        amount,
+        promo_code,
+    })
+}
+
+// Journal a premium payment and redeem its promo code in the same message.
+// The code is checked again because its caps may have been reached while we awaited the ledger.
+fn open_premium_payment(payer: Principal, kind: PaymentKind, from: Account, quote: &PriceQuote) -> Result<PaymentRecord, String> {
+    if let Some(code) = &quote.promo_code {
+        ensure_promo_code_allowed(code, &kind)?;
+        let current = quote_premium(payer, &quote.tier, &quote.token_type, &quote.period, quote.promo_code.as_deref())?;
+        if current.amount != quote.amount {
+            return Err("The price changed while processing, please try again".to_string());
+        }
+    }
+
+    let mut payment = open_payment(
+        payer,
+        kind,
+        quote.token_type.clone(),
+        get_ledger_canister_id(&quote.token_type),
+        from,
+        quote.amount,
+        None,
+        Some(quote.period.clone()),
+    );
+    payment.tier = Some(quote.tier.clone());
+    payment.promo_code = quote.promo_code.clone();
+    payment.discount = quote.promo_code.as_ref().map(|_| quote.discount);
+    save_payment(&payment);
+    let Some(code) = quote.promo_code.clone() else {
+        return Ok(payment);
+    };
+
+    PROMO_REDEMPTIONS.with_borrow_mut(|redemptions| {
+        redemptions.insert(
+            PromoRedemptionKey { code: code.clone(), payment_id: payment.id },
+            PromoRedemption {
+                code: code.clone(),
+                payment_id: payment.id,
+                payer,
+                period: quote.period.clone(),
+                token_type: quote.token_type.clone(),
+                price: quote.price,
+                discount: quote.discount,
+                amount: quote.amount,
+                redeemed_at: payment.created_at,
+            },
+        )
+    });
+    PROMO_CODES.with_borrow_mut(|codes| {
+        if let Some(mut promo) = codes.get(&code) {
+            promo.redemptions += 1;
+            promo.updated_at = payment.created_at;
+            codes.insert(code.clone(), promo);
+        }
+    });
+    PROMO_USES.with_borrow_mut(|uses| {
+        let key = PromoUseKey { code, principal: payer };
+        let count = uses.get(&key).unwrap_or_default();
+        uses.insert(key, count + 1);
+    });
+
+    Ok(payment)
+}
+
+// Give the redemption back when its payment never went through
+fn release_promo_redemption(payment: &PaymentRecord) {
+    let Some(code) = payment.promo_code.clone() else {
+        return;
+    };
+    let removed = PROMO_REDEMPTIONS.with_borrow_mut(|redemptions| {
+        redemptions.remove(&PromoRedemptionKey { code: code.clone(), payment_id: payment.id })
+    });
+    if removed.is_none() {
+        return;
+    }
+
+    PROMO_CODES.with_borrow_mut(|codes| {
+        if let Some(mut promo) = codes.get(&code) {
+            promo.redemptions = promo.redemptions.saturating_sub(1);
+            promo.updated_at = payment.updated_at;
+            codes.insert(code.clone(), promo);
+        }
+    });
+    PROMO_USES.with_borrow_mut(|uses| {
+        let key = PromoUseKey { code, principal: payment.payer };
+        match uses.get(&key).unwrap_or_default() {
+            0 | 1 => uses.remove(&key),
+            count => uses.insert(key, count - 1),
+        };
+    });
+}
+
+// Check user's balance for a specific token
+async fn check_user_balance(token_type: &TokenType, user_account: Account) -> Result<u64, String> {
+    let ledger_canister_id = get_ledger_canister_id(token_type);
+    
+    let response: Nat = 
+        ic_cdk::call::Call::unbounded_wait(ledger_canister_id, "icrc1_balance_of")
+            .with_arg(user_account)
+            .await
+            .map_err(|e| format!("Failed to get balance: {:?}", e))?
+            .candid::<Nat>()
+            .map_err(|e| format!("Failed to decode balance: {:?}", e))?;
+    
+    response.0.try_into().map_err(|_| "Balance does not fit in u64".to_string())
+}
+
+async fn get_ledger_fee(ledger_canister_id: Principal) -> Result<u64, String> {
+    let fee: Nat = ic_cdk::call::Call::unbounded_wait(ledger_canister_id, "icrc1_fee")
+        .await
+        .map_err(|e| format!("Failed to get ledger fee: {:?}", e))?
+        .candid::<Nat>()
+        .map_err(|e| format!("Failed to decode ledger fee: {:?}", e))?;
+
+    fee.0.try_into().map_err(|_| "Ledger fee does not fit in u64".to_string())
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn notify_deposit_premium_payment(
+    request: PremiumPaymentRequest
+) -> PremiumPaymentResponse {
+    let caller = ic_cdk::api::msg_caller();
+
+    // Paying again while premium extends the current entitlement
+    let tier = request.tier.clone().unwrap_or(EntitlementTier::Pro);
+    let quote = match quote_premium(caller, &tier, &request.token_type, &request.payment_period, request.promo_code.as_deref()) {
+        Ok(quote) => quote,
+        Err(error) => {
+            return PremiumPaymentResponse {
+                success: false,
+                message: error,
+                transaction_id: None,
+            };
+        }
+    };
+    
+    // Get user's account identifier
+    let user_account = Account {
+        owner: canister_self(),
+        subaccount: Some(principal_to_subaccount(caller)),
+    };
+    
+    // Check user's balance
+    let balance_result = check_user_balance(&request.token_type, user_account.clone()).await;
+    match balance_result {
+        Ok(balance) => {
+            if balance < quote.amount {
+                return PremiumPaymentResponse {
+                    success: false,
+                    message: format!("Insufficient balance. Required: {}, Available: {}", 
+                                   quote.amount, balance),
+                    transaction_id: None,
+                };
+            }
+        },
+        Err(error) => {
+            return PremiumPaymentResponse {
+                success: false,
+                message: format!("Failed to check balance: {}", error),
+                transaction_id: None,
+            };
+        }
+    }
+    
+    // Journal the payment before touching the ledger so a trap can't lose it
+    let payment = match open_premium_payment(caller, PaymentKind::Deposit, user_account, &quote) {
+        Ok(payment) => payment,
+        Err(error) => {
+            return PremiumPaymentResponse {
+                success: false,
+                message: error,
+                transaction_id: None,
+            };
+        }
+    };
+
+    match settle_payment(payment.id).await {
+        Ok(payment) => payment_response(
+            &payment,
+            format!("{:?} payment successful for {:?} {:?}", quote.tier, request.payment_period, request.token_type),
+        ),
+        Err(error) => PremiumPaymentResponse {
+            success: false,
+            message: error,
+            transaction_id: None,
+        },
+    }
+}
+
+// ICRC-2 payments may only draw on the caller's own allowances. An approval to this canister
+// is not proof that whoever names the account is allowed to spend it.
+fn ensure_own_account(caller: Principal, account: &Account) -> Result<(), String> {
+    if account.owner != caller {
+        return Err("Payments can only be taken from the caller's own account".to_string());
+    }
+    Ok(())
+}
+
+fn approval_payer_account(caller: Principal, principal_string: &str) -> Result<Account, String> {
+    let owner = Principal::from_text(principal_string).map_err(|_| "Invalid principal string".to_string())?;
+    let account = Account { owner, subaccount: None };
+    ensure_own_account(caller, &account)?;
+    Ok(account)
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn notify_payment_approval(
+    principal_string: String,
+    payment_period: PaymentPeriod,
+    promo_code: Option<String>,
+    tier: Option<EntitlementTier>,
+) -> PremiumPaymentResponse {
+    let caller = ic_cdk::api::msg_caller();
+    
+    let user_account = match approval_payer_account(caller, &principal_string) {
+        Ok(account) => account,
+        Err(error) => {
+            return PremiumPaymentResponse {
+                success: false,
+                message: error,
+                transaction_id: None,
+            };
+        }
+    };
+    
+    // Paying again while premium extends the current entitlement
+    let tier = tier.unwrap_or(EntitlementTier::Pro);
+    let quote = match quote_premium(caller, &tier, &TokenType::CKUSDC, &payment_period, promo_code.as_deref()) {
+        Ok(quote) => quote,
+        Err(error) => {
+            return PremiumPaymentResponse {
+                success: false,
+                message: error,
+                transaction_id: None,
+            };
+        }
+    };
+    
+    // Check user's balance for USDC
+    let balance_result = check_user_balance(&TokenType::CKUSDC, user_account.clone()).await;
+    match balance_result {
+        Ok(balance) => {
+            if balance < quote.amount {
+                return PremiumPaymentResponse {
+                    success: false,
+                    message: format!("Insufficient USDC balance. Required: {}, Available: {}", 
+                                   quote.amount, balance),
+                    transaction_id: None,
+                };
+            }
+        },
+        Err(error) => {
+            return PremiumPaymentResponse {
+                success: false,
+                message: format!("Failed to check USDC balance: {}", error),
+                transaction_id: None,
+            };
+        }
+    }
+    
+    // Journal the payment before touching the ledger so a trap can't lose it
+    let payment = match open_premium_payment(caller, PaymentKind::Approval, user_account, &quote) {
+        Ok(payment) => payment,
+        Err(error) => {
+            return PremiumPaymentResponse {
+                success: false,
+                message: error,
+                transaction_id: None,
+            };
+        }
+    };
+
+    match settle_payment(payment.id).await {
+        Ok(payment) => payment_response(
+            &payment,
+            format!("Premium payment approval successful for {:?} period", payment_period),
+        ),
+        Err(error) => PremiumPaymentResponse {
+            success: false,
+            message: error,
+            transaction_id: None,
+        },
+    }
+}
+
+// Payment journal constants
+const PAYMENT_RECONCILE_INTERVAL_SECS: u64 = 10 * 60;
+// Payments the ledger confirms later than this are refunded instead of granted
+const PAYMENT_GRANT_WINDOW: u64 = 60 * 60 * 1000;
+
+// Why the ledger refused a journaled transfer
+enum TransferRejection {
+    // created_at_time is outside the dedup window, so an earlier attempt can no longer be detected
+    Expired(String),
+    Refused(String),
+}
+
+// Payments that send funds out rather than buy something
+fn is_outgoing_payment(kind: &PaymentKind) -> bool {
+    matches!(kind, PaymentKind::Withdrawal { .. } | PaymentKind::Payout { .. } | PaymentKind::Earnings { .. })
+}
+
+fn open_payment(
+    payer: Principal,
+    kind: PaymentKind,
+    token_type: TokenType,
+    ledger: Principal,
+    from: Account,
+    amount: u64,
+    fee: Option<u64>,
+    period: Option<PaymentPeriod>,
+) -> PaymentRecord {
+    let id = NEXT_PAYMENT_ID.with_borrow_mut(|cell| {
+        let id = *cell.get();
+        cell.set(id + 1);
+        id
+    });
+    let now = get_current_time_in_milli();
+    let payment = PaymentRecord {
+        id,
+        payer,
+        kind,
+        token_type,
+        ledger,
+        from,
+        amount,
+        fee,
+        period,
+        created_at_time: time(),
+        refund_created_at_time: None,
+        state: PaymentState::Pending,
+        block_index: None,
+        refund_block_index: None,
+        attempts: 0,
+        last_error: None,
+        created_at: now,
+        updated_at: now,
+        settled_at: None,
+        tier: None,
+        purchase: None,
+        promo_code: None,
+        discount: None,
+        invoice_path: None,
+    };
+    save_payment(&payment);
+    payment
+}
+
+fn save_payment(payment: &PaymentRecord) {
+    PAYMENTS.with_borrow_mut(|payments| payments.insert(payment.id, payment.clone()));
+    OPEN_PAYMENTS.with_borrow_mut(|open| {
+        if payment.is_open() {
+            open.insert(payment.id);
+        } else {
+            open.remove(&payment.id);
+        }
+    });
+    PAYER_PAYMENTS.with_borrow_mut(|payer_payments| {
+        payer_payments.insert(PayerPaymentKey { payer: payment.payer, payment_id: payment.id });
+    });
+    if matches!(payment.state, PaymentState::Failed(_) | PaymentState::Refunded) {
+        release_promo_redemption(payment);
+        void_gift_code(payment);
+        reverse_earnings_withdrawal(payment);
+    }
+}
+
+fn transfer_outcome(transfer_result: Result<Nat, TransferError>) -> Result<String, TransferRejection> {
+    match transfer_result {
+        Ok(block_index) => Ok(block_index.to_string()),
+        // an earlier attempt went through but its response was lost
+        Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of.to_string()),
+        Err(transfer_error @ (TransferError::TooOld | TransferError::CreatedInFuture { .. })) => {
+            Err(TransferRejection::Expired(format!("{:?}", transfer_error)))
+        }
+        Err(transfer_error) => Err(TransferRejection::Refused(format!("{:?}", transfer_error))),
+    }
+}
+
+fn transfer_from_outcome(transfer_result: Result<Nat, TransferFromError>) -> Result<String, TransferRejection> {
+    match transfer_result {
+        Ok(block_index) => Ok(block_index.to_string()),
+        Err(TransferFromError::Duplicate { duplicate_of }) => Ok(duplicate_of.to_string()),
+        Err(transfer_error @ (TransferFromError::TooOld | TransferFromError::CreatedInFuture { .. })) => {
+            Err(TransferRejection::Expired(format!("{:?}", transfer_error)))
+        }
+        Err(transfer_error) => Err(TransferRejection::Refused(format!("{:?}", transfer_error))),
+    }
+}
+
+// Outer Err: the ledger outcome is unknown. Inner Err: the ledger rejected the transfer.
+async fn submit_payment_transfer(payment: &PaymentRecord) -> Result<Result<String, TransferRejection>, String> {
+    let memo = Some(Memo::from(payment.id));
+    match payment.kind {
+        PaymentKind::Deposit | PaymentKind::Withdrawal { .. } | PaymentKind::Payout { .. } | PaymentKind::Earnings { .. } => {
+            let to = match &payment.kind {
+                PaymentKind::Withdrawal { to } | PaymentKind::Payout { to } | PaymentKind::Earnings { to } => *to,
+                _ => get_system_account(),
+            };
+            let transfer_args = TransferArg {
+                from_subaccount: payment.from.subaccount,
+                to,
+                amount: Nat::from(payment.amount),
+                fee: payment.fee.map(Nat::from),
+                memo,
+                created_at_time: Some(payment.created_at_time),
+            };
+            let transfer_result: Result<Nat, TransferError> =
+                ic_cdk::call::Call::unbounded_wait(payment.ledger, "icrc1_transfer")
+                    .with_arg(transfer_args)
+                    .await
+                    .map_err(|e| format!("Failed to call ledger: {:?}", e))?
+                    .candid::<Result<Nat, TransferError>>()
+                    .map_err(|e| format!("Failed to decode transfer result: {:?}", e))?;
+            Ok(transfer_outcome(transfer_result))
+        }
+        PaymentKind::Approval => {
+            let transfer_args = TransferFromArgs {
+                spender_subaccount: None,
+                from: payment.from,
+                to: get_system_account(),
+                amount: Nat::from(payment.amount),
+                fee: None,
+                memo,
+                created_at_time: Some(payment.created_at_time),
+            };
+            let transfer_result: Result<Nat, TransferFromError> =
+                ic_cdk::call::Call::unbounded_wait(payment.ledger, "icrc2_transfer_from")
+                    .with_arg(transfer_args)
+                    .await
+                    .map_err(|e| format!("Failed to call ledger: {:?}", e))?
+                    .candid::<Result<Nat, TransferFromError>>()
+                    .map_err(|e| format!("Failed to decode transfer result: {:?}", e))?;
+            Ok(transfer_from_outcome(transfer_result))
+        }
+    }
+}
+
+// Send the payment back to the account it came from, the system account covers the fee
+async fn submit_payment_refund(payment: &PaymentRecord) -> Result<Result<String, String>, String> {
+    let transfer_args = TransferArg {
+        from_subaccount: None,
+        to: payment.from,
+        amount: Nat::from(payment.amount),
+        fee: None,
+        memo: Some(Memo::from(payment.id)),
+        created_at_time: payment.refund_created_at_time,
+    };
+    let transfer_result: Result<Nat, TransferError> =
+        ic_cdk::call::Call::unbounded_wait(payment.ledger, "icrc1_transfer")
+            .with_arg(transfer_args)
+            .await
+            .map_err(|e| format!("Failed to call ledger: {:?}", e))?
+            .candid::<Result<Nat, TransferError>>()
+            .map_err(|e| format!("Failed to decode transfer result: {:?}", e))?;
+    Ok(match transfer_result {
+        Ok(block_index) => Ok(block_index.to_string()),
+        Err(TransferError::Duplicate { duplicate_of }) => Ok(duplicate_of.to_string()),
+        Err(transfer_error) => Err(format!("{:?}", transfer_error)),
+    })
+}
+
+// Drive a journaled payment as far as it can go. Safe to call repeatedly: ledger
+// calls reuse the recorded created_at_time, so retries are deduplicated.
+async fn settle_payment(payment_id: u64) -> Result<PaymentRecord, String> {
+    let Some(_lock) = HeapLock::acquire(&SETTLING_PAYMENTS, payment_id) else {
+        return Err("Payment is already being processed".to_string());
+    };
+
+    run_payment_settlement(payment_id).await
+}
+
+async fn run_payment_settlement(payment_id: u64) -> Result<PaymentRecord, String> {
+    let mut payment = PAYMENTS
+        .with_borrow(|payments| payments.get(&payment_id))
+        .ok_or("Payment not found".to_string())?;
+
+    if payment.state == PaymentState::Pending {
+        payment.attempts += 1;
+        let outcome = submit_payment_transfer(&payment).await;
+        record_transfer_outcome(&mut payment, outcome, get_current_time_in_milli());
+        save_payment(&payment);
+    }
+
+    if payment.state == PaymentState::Transferred {
+        let beneficiaries = fulfil_payment(&payment);
+        record_grant(&mut payment, get_current_time_in_milli());
+        save_payment(&payment);
+
+        // Invoice paths are random so invoices can't be enumerated. Tips don't buy anything, so they get none.
+        if !matches!(payment.purchase, Some(PaymentPurchase::Tip { .. })) {
+            match raw_rand().await {
+                Ok(random_bytes) => {
+                    payment.invoice_path = Some(format!("/invoices/{}", hex::encode(&random_bytes[..16])));
+                    save_payment(&payment);
+                    render_invoice_page(&payment);
+                }
+                Err(e) => ic_cdk::api::debug_print(&format!("Failed to create invoice for payment {}: {:?}", payment.id, e)),
+            }
+        }
+
+        for principal in beneficiaries {
+            if let Err(e) = sync_asset_storage_access(principal).await {
+                ic_cdk::api::debug_print(&format!("Failed to update asset storage access for {}: {}", principal, e));
+            }
+        }
+    }
+
+    if payment.state == PaymentState::RefundPending {
+        if payment.refund_created_at_time.is_none() {
+            payment.refund_created_at_time = Some(time());
+            save_payment(&payment);
+        }
+        let outcome = submit_payment_refund(&payment).await;
+        payment.updated_at = get_current_time_in_milli();
+        match outcome {
+            Ok(Ok(block_index)) => {
+                payment.refund_block_index = Some(block_index);
+                payment.last_error = None;
+                payment.state = PaymentState::Refunded;
+            }
+            Ok(Err(e)) | Err(e) => payment.last_error = Some(e),
+        }
+        save_payment(&payment);
+    }
+
+    Ok(payment)
+}
+
+// Move a pending payment on from what the ledger answered
+fn record_transfer_outcome(payment: &mut PaymentRecord, outcome: Result<Result<String, TransferRejection>, String>, now: u64) {
+    payment.updated_at = now;
+    match outcome {
+        Ok(Ok(block_index)) => {
+            payment.block_index = Some(block_index);
+            payment.last_error = None;
+            payment.state = if is_outgoing_payment(&payment.kind) {
+                payment.settled_at = Some(now);
+                PaymentState::Completed
+            } else if now.saturating_sub(payment.created_at) > PAYMENT_GRANT_WINDOW {
+                PaymentState::RefundPending
+            } else {
+                PaymentState::Transferred
+            };
+        }
+        Ok(Err(rejection)) => {
+            // last_error is only left set by an earlier attempt whose outcome was never learned
+            let earlier_outcome_unknown = payment.attempts > 1 && payment.last_error.is_some();
+            payment.state = match rejection {
+                TransferRejection::Expired(transfer_error) if earlier_outcome_unknown => {
+                    payment.last_error = Some(transfer_error);
+                    PaymentState::Unconfirmed
+                }
+                TransferRejection::Expired(transfer_error) | TransferRejection::Refused(transfer_error) => {
+                    payment.last_error = Some(transfer_error.clone());
+                    PaymentState::Failed(transfer_error)
+                }
+            };
+        }
+        // leave it pending, reconcile_payments retries with the same dedup key
+        Err(e) => payment.last_error = Some(e),
+    }
+}
+
+fn record_grant(payment: &mut PaymentRecord, now: u64) {
+    payment.state = PaymentState::Granted;
+    payment.updated_at = now;
+    payment.settled_at = Some(now);
+}
+
+// Hand out what a transferred payment bought. Returns the principals whose storage access may have changed.
+fn fulfil_payment(payment: &PaymentRecord) -> Vec<Principal> {
+    let now = get_current_time_in_milli();
+    let duration = payment.period.as_ref().map(|period| period.to_millis()).unwrap_or_default();
+    match &payment.purchase {
+        None => {
+            let tier = payment.tier.clone().unwrap_or(EntitlementTier::Pro);
+            grant_premium_entitlement(payment.payer, tier, duration, EntitlementSource::LedgerTransfer {
+                ledger: payment.ledger,
+                block_index: payment.block_index.clone().unwrap_or_default(),
+            });
+            let mut beneficiaries = vec![payment.payer];
+            beneficiaries.extend(reward_referral(payment));
+            beneficiaries
+        }
+        Some(PaymentPurchase::Gift { code }) => {
+            GIFT_CODES.with_borrow_mut(|codes| {
+                if let Some(mut gift) = codes.get(code) {
+                    gift.status = GiftCodeStatus::Available;
+                    gift.updated_at = now;
+                    codes.insert(code.clone(), gift);
+                }
+            });
+            Vec::new()
+        }
+        Some(PaymentPurchase::Seats { pool_id, count }) => {
+            let mut pool = SEAT_POOLS.with_borrow(|pools| pools.get(pool_id)).unwrap_or_else(|| SeatPool {
+                id: *pool_id,
+                owner: payment.payer,
+                tier: payment.tier.clone().unwrap_or(EntitlementTier::Team),
+                next_seat_id: 0,
+                created_at: now,
+                updated_at: now,
+            });
+            SEATS.with_borrow_mut(|seats| {
+                for _ in 0..*count {
+                    let seat_id = pool.next_seat_id;
+                    pool.next_seat_id += 1;
+                    seats.insert(SeatKey { pool_id: *pool_id, seat_id }, Seat {
+                        pool_id: *pool_id,
+                        seat_id,
+                        assignee: None,
+                        assigned_at: None,
+                        start: now,
+                        end: now.saturating_add(duration),
+                    });
+                }
+            });
+            pool.updated_at = now;
+            SEAT_POOLS.with_borrow_mut(|pools| pools.insert(*pool_id, pool));
+            Vec::new()
+        }
+        Some(PaymentPurchase::SeatRenewal { pool_id, seat_ids }) => {
+            let mut assignees = Vec::new();
+            for seat_id in seat_ids {
+                let key = SeatKey { pool_id: *pool_id, seat_id: *seat_id };
+                let Some(mut seat) = SEATS.with_borrow(|seats| seats.get(&key)) else {
+                    continue;
+                };
+                // lapsed seats start a fresh period
+                if !seat.is_active(now) {
+                    seat.start = now;
+                }
+                seat.end = seat.end.max(now).saturating_add(duration);
+                if let Some(assignee) = seat.assignee {
+                    ENTITLEMENT_EXPIRIES.with_borrow_mut(|expiries| expiries.insert(EntitlementExpiryKey { end: seat.end, principal: assignee }));
+                    refresh_premium_status(assignee);
+                    assignees.push(assignee);
+                }
+                SEATS.with_borrow_mut(|seats| seats.insert(key, seat));
+            }
+            schedule_entitlement_expiry();
+            assignees
+        }
+        Some(PaymentPurchase::Tip { note_id, author }) => {
+            record_tip(payment, note_id, *author);
+            Vec::new()
+        }
+        Some(PaymentPurchase::Note { note_id, author }) => {
+            record_note_sale(payment, note_id, *author);
+            Vec::new()
+        }
+        // run_workspace_deployment moves on once it sees the payment granted
+        Some(PaymentPurchase::Workspace) => Vec::new(),
+    }
+}
+
+fn void_gift_code(payment: &PaymentRecord) {
+    let Some(PaymentPurchase::Gift { code }) = &payment.purchase else {
+        return;
+    };
+    GIFT_CODES.with_borrow_mut(|codes| {
+        if let Some(mut gift) = codes.get(code) {
+            if gift.status == GiftCodeStatus::AwaitingPayment {
+                gift.status = GiftCodeStatus::Void;
+                gift.updated_at = payment.updated_at;
+                codes.insert(code.clone(), gift);
+            }
+        }
+    });
+}
+
+fn payment_response(payment: &PaymentRecord, success_message: String) -> PremiumPaymentResponse {
+    let (success, message) = match &payment.state {
+        PaymentState::Granted | PaymentState::Completed => (true, success_message),
+        PaymentState::Pending | PaymentState::Transferred => (
+            false,
+            format!("Payment {} is awaiting ledger confirmation and will be settled automatically", payment.id),
+        ),
+        PaymentState::Failed(transfer_error) => (false, format!("Transfer error: {}", transfer_error)),
+        PaymentState::RefundPending | PaymentState::Refunded => (
+            false,
+            format!("Payment {} was confirmed too late and is refunded", payment.id),
+        ),
+        PaymentState::Unconfirmed => (
+            false,
+            format!("Payment {} could not be confirmed by the ledger and is being reviewed", payment.id),
+        ),
+    };
+    PremiumPaymentResponse {
+        success,
+        message,
+        transaction_id: payment.block_index.clone(),
+    }
+}
+
+// Milliseconds since epoch to a UTC YYYY-MM-DD date
+fn format_date(millis: u64) -> String {
+    let days = (millis / 86_400_000) as i64;
+    // civil-from-days, see https://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn render_invoice_page(payment: &PaymentRecord) {
+    let Some(path) = payment.invoice_path.clone() else {
+        return;
+    };
+    let context = InvoiceTemplateContext {
+        invoice_number: format!("DOT-{:06}", payment.id),
+        payer: payment.payer.to_text(),
+        token: get_token_config(&payment.token_type).symbol,
+        amount: u64_to_decimal(payment.amount, token_decimals(&payment.token_type)),
+        period: payment.period.as_ref().map(|period| format!("{:?}", period)).unwrap_or_default(),
+        ledger: payment.ledger.to_text(),
+        block_index: payment.block_index.clone().unwrap_or_default(),
+        issued_at: format_date(payment.settled_at.unwrap_or(payment.created_at)),
+        site: Site::new("Dotane".to_string(), "https://dotane.io".to_string()),
+    };
+    match HANDLEBARS.with_borrow(|handlebars| handlebars.render("invoice", &context)) {
+        Ok(rendered_content) => add_asset(path, rendered_content.as_bytes().to_vec(), "text/html".to_string()),
+        Err(e) => ic_cdk::api::debug_print(&format!("Failed to render invoice {}: {}", payment.id, e)),
+    }
+}
+
+// Index payments recorded before PAYER_PAYMENTS existed and re-certify invoice pages
+fn setup_payment_history() {
+    PAYMENTS.with_borrow(|payments| {
+        for entry in payments.iter() {
+            let payment = entry.value();
+            PAYER_PAYMENTS.with_borrow_mut(|payer_payments| {
+                payer_payments.insert(PayerPaymentKey { payer: payment.payer, payment_id: payment.id });
+            });
+            render_invoice_page(&payment);
+        }
+    });
+}
+
+// Max page size for payment listings
+const MAX_PAYMENTS_PAGE_SIZE: u32 = 100;
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_payments(offset: u32, limit: u32) -> Vec<PaymentRecord> {
+    let caller = ic_cdk::api::msg_caller();
+    let mut payment_ids: Vec<u64> = PAYER_PAYMENTS.with_borrow(|payer_payments| {
+        payer_payments
+            .range(PayerPaymentKey { payer: caller, payment_id: 0 }..)
+            .take_while(|key| key.payer == caller)
+            .map(|key| key.payment_id)
+            .collect()
+    });
+    // newest first
+    payment_ids.reverse();
+
+    PAYMENTS.with_borrow(|payments| {
+        payment_ids
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAYMENTS_PAGE_SIZE) as usize)
+            .filter_map(|payment_id| payments.get(&payment_id))
+            .collect()
+    })
+}
+
+/// Settled payments between `from` and `to` (milliseconds, inclusive), newest first,
+/// with per-token totals over the whole window.
+#[ic_cdk::query(guard = "is_controller")]
+fn get_revenue_report(from: Option<u64>, to: Option<u64>, offset: u32, limit: u32) -> RevenueReport {
+    let from = from.unwrap_or(0);
+    let to = to.unwrap_or(u64::MAX);
+    let mut settled: Vec<PaymentRecord> = PAYMENTS.with_borrow(|payments| {
+        payments
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|payment| payment.state == PaymentState::Granted)
+            // tips and note sales belong to the authors
+            .filter(|payment| !matches!(payment.purchase, Some(PaymentPurchase::Tip { .. } | PaymentPurchase::Note { .. })))
+            .filter(|payment| payment.settled_at.is_some_and(|settled_at| settled_at >= from && settled_at <= to))
+            .collect()
+    });
+    settled.reverse();
+
+    let mut totals: Vec<RevenueTotal> = Vec::new();
+    for payment in settled.iter() {
+        match totals.iter_mut().find(|total| total.token_type == payment.token_type) {
+            Some(total) => {
+                total.amount += payment.amount;
+                total.payments += 1;
+            }
+            None => totals.push(RevenueTotal {
+                token_type: payment.token_type.clone(),
+                amount: payment.amount,
+                payments: 1,
+            }),
+        }
+    }
+
+    RevenueReport {
+        total: settled.len() as u64,
+        totals,
+        payments: settled
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAYMENTS_PAGE_SIZE) as usize)
+            .collect(),
+    }
+}
+
+// Subscription renewal constants
+const RENEWAL_CHECK_INTERVAL_SECS: u64 = 60 * 60;
+// Renewals are charged this long before the entitlement ends
+const RENEWAL_LEAD: u64 = 24 * 60 * 60 * 1000;
+const RENEWAL_RETRY_INTERVAL: u64 = 6 * 60 * 60 * 1000;
+// Failed renewals are retried for this long after the first failure
+const RENEWAL_GRACE_PERIOD: u64 = 3 * 24 * 60 * 60 * 1000;
+
+async fn check_allowance(token_type: &TokenType, account: Account) -> Result<u64, String> {
+    let args = AllowanceArgs {
+        account,
+        spender: Account {
+            owner: canister_self(),
+            subaccount: None,
+        },
+    };
+    let allowance: Allowance = ic_cdk::call::Call::unbounded_wait(get_ledger_canister_id(token_type), "icrc2_allowance")
+        .with_arg(args)
+        .await
+        .map_err(|e| format!("Failed to call ledger: {:?}", e))?
+        .candid::<Allowance>()
+        .map_err(|e| format!("Failed to decode allowance: {:?}", e))?;
+
+    let now = time();
+    if allowance.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Ok(0);
+    }
+    allowance.allowance.0.try_into().map_err(|_| "Allowance does not fit in u64".to_string())
+}
+
+// When the principal's own entitlement should be renewed, 0 if they have none. Seats don't count.
+fn renewal_due_at(principal: &Principal) -> u64 {
+    PREMIUM_ENTITLEMENTS
+        .with_borrow(|entitlements| entitlements.get(principal))
+        .map(|entitlement| entitlement.end.saturating_sub(RENEWAL_LEAD))
+        .unwrap_or_default()
+}
+
+/// Renew the caller's plan automatically from `payer`'s ICRC-2 allowance to this canister. `payer` must be
+/// one of the caller's own accounts, and the allowance must cover at least one period at the regular price.
+/// Only a plan the caller paid for can be renewed, not a team seat.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn enable_auto_renew(
+    payer: Account,
+    payment_period: PaymentPeriod,
+    tier: Option<EntitlementTier>,
+    token_type: TokenType,
+) -> Result<Subscription, String> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_own_account(caller, &payer)?;
+    let now = get_current_time_in_milli();
+    // renewals extend the caller's own entitlement, a team seat ends with its pool
+    let own_tier = PREMIUM_ENTITLEMENTS
+        .with_borrow(|entitlements| entitlements.get(&caller))
+        .filter(|entitlement| entitlement.is_active(now))
+        .map(|entitlement| entitlement.tier.normalized())
+        .ok_or("Pay for the first period before turning on auto-renew".to_string())?;
+    let tier = tier.unwrap_or(own_tier).normalized();
+    let quote = quote_premium(caller, &tier, &token_type, &payment_period, None)?;
+
+    let allowance = check_allowance(&token_type, payer).await?;
+    if allowance < quote.amount {
+        return Err(format!(
+            "Approve at least {} {} for renewals, the current allowance is {}",
+            u64_to_decimal(quote.amount, token_decimals(&token_type)),
+            get_token_config(&token_type).symbol,
+            u64_to_decimal(allowance, token_decimals(&token_type))
+        ));
+    }
+
+    let subscription = Subscription {
+        payer,
+        tier,
+        period: payment_period,
+        token_type,
+        status: SubscriptionStatus::Active,
+        next_charge_at: renewal_due_at(&caller),
+        grace_until: None,
+        last_payment_id: None,
+        failed_attempts: 0,
+        last_error: None,
+        created_at: SUBSCRIPTIONS
+            .with_borrow(|subscriptions| subscriptions.get(&caller))
+            .map(|existing| existing.created_at)
+            .unwrap_or(now),
+        updated_at: now,
+    };
+    SUBSCRIPTIONS.with_borrow_mut(|subscriptions| subscriptions.insert(caller, subscription.clone()));
+    Ok(subscription)
+}
+
+/// Stop future renewals. The current period runs to its end.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn cancel_auto_renew() -> Result<Subscription, String> {
+    let caller = ic_cdk::api::msg_caller();
+    SUBSCRIPTIONS.with_borrow_mut(|subscriptions| {
+        let mut subscription = subscriptions
+            .get(&caller)
+            .filter(|subscription| subscription.is_renewing())
+            .ok_or("Auto-renew is not enabled".to_string())?;
+        subscription.status = SubscriptionStatus::Cancelled;
+        subscription.grace_until = None;
+        subscription.updated_at = get_current_time_in_milli();
+        subscriptions.insert(caller, subscription.clone());
+        Ok(subscription)
+    })
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_subscription() -> Option<Subscription> {
+    let caller = ic_cdk::api::msg_caller();
+    SUBSCRIPTIONS.with_borrow(|subscriptions| subscriptions.get(&caller))
+}
+
+// Timer: charge subscriptions whose entitlement ends within RENEWAL_LEAD
+fn process_renewals() {
+    let Some(lock) = FlagLock::acquire(&RENEWING_SUBSCRIPTIONS) else {
+        return;
+    };
+    let now = get_current_time_in_milli();
+    let due: Vec<(Principal, Subscription)> = SUBSCRIPTIONS.with_borrow(|subscriptions| {
+        subscriptions
+            .iter()
+            .map(|entry| (*entry.key(), entry.value()))
+            .filter(|(_, subscription)| subscription.is_renewing() && subscription.next_charge_at <= now)
+            .collect()
+    });
+    if due.is_empty() {
+        return;
+    }
+
+    ic_cdk::futures::spawn(async move {
+        let _lock = lock;
+        for (principal, subscription) in due {
+            renew_subscription(principal, subscription).await;
+        }
+    });
+}
+
+async fn renew_subscription(principal: Principal, mut subscription: Subscription) {
+    // a charge from an earlier run may still be settling
+    if has_open_payment(principal) {
+        return;
+    }
+
+    let now = get_current_time_in_milli();
+    // paid manually or renewed already, wait for the new period to run down
+    let due_at = renewal_due_at(&principal);
+    if due_at > now {
+        subscription.status = SubscriptionStatus::Active;
+        subscription.next_charge_at = due_at;
+        subscription.grace_until = None;
+        subscription.failed_attempts = 0;
+        subscription.updated_at = now;
+        save_subscription(principal, subscription);
+        return;
+    }
+
+    let outcome = match quote_premium(principal, &subscription.tier, &subscription.token_type, &subscription.period, None)
+        .and_then(|quote| open_premium_payment(principal, PaymentKind::Approval, subscription.payer, &quote))
+    {
+        Ok(payment) => {
+            subscription.last_payment_id = Some(payment.id);
+            settle_payment(payment.id).await
+        }
+        Err(e) => Err(e),
+    };
+
+    let now = get_current_time_in_milli();
+    subscription.updated_at = now;
+    let error = match outcome {
+        Ok(payment) if payment.state == PaymentState::Granted => {
+            subscription.status = SubscriptionStatus::Active;
+            subscription.next_charge_at = renewal_due_at(&principal);
+            subscription.grace_until = None;
+            subscription.failed_attempts = 0;
+            subscription.last_error = None;
+            save_subscription(principal, subscription);
+            return;
+        }
+        // the ledger outcome is unknown, reconcile_payments finishes it and the next run picks up the new end
+        Ok(payment) if payment.is_open() => {
+            subscription.next_charge_at = now.saturating_add(RENEWAL_RETRY_INTERVAL);
+            subscription.last_error = payment.last_error;
+            save_subscription(principal, subscription);
+            return;
+        }
+        Ok(payment) => payment.last_error.unwrap_or(format!("Renewal payment {} failed", payment.id)),
+        Err(e) => e,
+    };
+
+    subscription.failed_attempts += 1;
+    subscription.last_error = Some(error);
+    let grace_until = *subscription.grace_until.get_or_insert(now.saturating_add(RENEWAL_GRACE_PERIOD));
+    if now >= grace_until {
+        subscription.status = SubscriptionStatus::Lapsed;
+    } else {
+        subscription.status = SubscriptionStatus::PastDue;
+        subscription.next_charge_at = now.saturating_add(RENEWAL_RETRY_INTERVAL);
+    }
+    save_subscription(principal, subscription);
+}
+
+// Store a renewal outcome unless the user cancelled while it was in flight
+fn save_subscription(principal: Principal, subscription: Subscription) {
+    SUBSCRIPTIONS.with_borrow_mut(|subscriptions| {
+        let renewing = matches!(
+            subscriptions.get(&principal),
+            Some(current) if current.status != SubscriptionStatus::Cancelled
+        );
+        if renewing {
+            subscriptions.insert(principal, subscription);
+        }
+    });
+}
+
+// Gift and team seat constants
+const MAX_SEATS_PER_PURCHASE: u32 = 100;
+
+// Price of `quantity` periods of a plan bought for others, in token units. Promo codes don't apply.
+fn quote_purchase(
+    tier: &EntitlementTier,
+    token_type: &TokenType,
+    payment_period: &PaymentPeriod,
+    quantity: u32,
+) -> Result<PriceQuote, String> {
+    ensure_token_accepted(token_type)?;
+    let plan = get_plan_price(tier, payment_period)?;
+    let amount = price_to_token_units(plan.amount, token_type)
+        .checked_mul(quantity as u64)
+        .ok_or("Purchase amount is too large".to_string())?;
+    if amount == 0 {
+        return Err("Plan price is too small for this token".to_string());
+    }
+    Ok(PriceQuote {
+        tier: plan.plan_tier(),
+        period: payment_period.clone(),
+        token_type: token_type.clone(),
+        price: amount,
+        discount: 0,
+        amount,
+        promo_code: None,
+    })
+}
+
+// Pay for a purchase from the caller's deposit subaccount. `prepare` runs in the same message
+// that journals the payment and says what it buys.
+async fn pay_for_purchase<F>(caller: Principal, quote: PriceQuote, prepare: F) -> Result<PaymentRecord, String>
+where
+    F: FnOnce(&PaymentRecord) -> PaymentPurchase,
+{
+    if has_open_payment(caller) {
+        return Err("Another payment from this account is still being settled".to_string());
+    }
+
+    let deposit_account = Account {
+        owner: canister_self(),
+        subaccount: Some(principal_to_subaccount(caller)),
+    };
+    let decimals = token_decimals(&quote.token_type);
+    let fee = get_ledger_fee(get_ledger_canister_id(&quote.token_type)).await?;
+    let balance = check_user_balance(&quote.token_type, deposit_account).await?;
+    if balance < quote.amount.saturating_add(fee) {
+        return Err(format!(
+            "Insufficient balance. Required: {} (including {} fee), Available: {}",
+            u64_to_decimal(quote.amount.saturating_add(fee), decimals),
+            u64_to_decimal(fee, decimals),
+            u64_to_decimal(balance, decimals)
+        ));
+    }
+    // another payment may have started while we were waiting on the ledger
+    if has_open_payment(caller) {
+        return Err("Another payment from this account is still being settled".to_string());
+    }
+
+    let mut payment = open_premium_payment(caller, PaymentKind::Deposit, deposit_account, &quote)?;
+    payment.purchase = Some(prepare(&payment));
+    save_payment(&payment);
+
+    settle_payment(payment.id).await
+}
+
+fn owned_seat_pool(owner: Principal, pool_id: u64) -> Result<SeatPool, String> {
+    SEAT_POOLS
+        .with_borrow(|pools| pools.get(&pool_id))
+        .filter(|pool| pool.owner == owner)
+        .ok_or("Seat pool not found".to_string())
+}
+
+fn pool_seats(pool_id: u64) -> Vec<Seat> {
+    SEATS.with_borrow(|seats| {
+        seats
+            .range(SeatKey { pool_id, seat_id: 0 }..)
+            .take_while(|entry| entry.key().pool_id == pool_id)
+            .map(|entry| entry.value())
+            .collect()
+    })
+}
+
+/// Buy one period of `tier` (Pro by default) as a gift code from the caller's deposit subaccount.
+/// The code in the payment's purchase can be redeemed by anyone once the payment is granted.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn buy_gift_subscription(
+    token_type: TokenType,
+    payment_period: PaymentPeriod,
+    tier: Option<EntitlementTier>,
+) -> Result<PaymentRecord, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let tier = tier.unwrap_or(EntitlementTier::Pro);
+    let quote = quote_purchase(&tier, &token_type, &payment_period, 1)?;
+
+    let random_bytes = raw_rand().await.map_err(|e| format!("Failed to generate gift code: {:?}", e))?;
+    let code = format!("GIFT-{}", hex::encode(&random_bytes[..10]).to_ascii_uppercase());
+
+    pay_for_purchase(caller, quote, |payment| {
+        let now = get_current_time_in_milli();
+        GIFT_CODES.with_borrow_mut(|codes| {
+            codes.insert(code.clone(), GiftCode {
+                code: code.clone(),
+                purchaser: caller,
+                tier: payment.tier.clone().unwrap_or(EntitlementTier::Pro),
+                period: payment_period,
+                payment_id: payment.id,
+                status: GiftCodeStatus::AwaitingPayment,
+                created_at: now,
+                updated_at: now,
+            })
+        });
+        PaymentPurchase::Gift { code }
+    })
+    .await
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn redeem_gift_code(code: String) -> Result<PremiumEntitlement, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let code = code.trim().to_ascii_uppercase();
+    let mut gift = GIFT_CODES
+        .with_borrow(|codes| codes.get(&code))
+        .ok_or("Invalid gift code".to_string())?;
+    match gift.status {
+        GiftCodeStatus::Available => {}
+        GiftCodeStatus::AwaitingPayment => return Err("This gift code has not been paid for yet".to_string()),
+        GiftCodeStatus::Redeemed { .. } => return Err("This gift code has already been redeemed".to_string()),
+        GiftCodeStatus::Void => return Err("Invalid gift code".to_string()),
+    }
+
+    let now = get_current_time_in_milli();
+    gift.status = GiftCodeStatus::Redeemed { by: caller, at: now };
+    gift.updated_at = now;
+    GIFT_CODES.with_borrow_mut(|codes| codes.insert(code.clone(), gift.clone()));
+    let entitlement = grant_premium_entitlement(caller, gift.tier, gift.period.to_millis(), EntitlementSource::Gift {
+        code,
+        payment_id: gift.payment_id,
+    });
+
+    if let Err(e) = sync_asset_storage_access(caller).await {
+        ic_cdk::api::debug_print(&format!("Failed to update asset storage access for {}: {}", caller, e));
+    }
+    Ok(entitlement)
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_gift_codes() -> Vec<GiftCode> {
+    let caller = ic_cdk::api::msg_caller();
+    GIFT_CODES.with_borrow(|codes| {
+        codes
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|gift| gift.purchaser == caller)
+            .collect()
+    })
+}
+
+/// Buy `count` seats of one period each, into a new pool or an existing one the caller owns.
+/// New pools are Team unless `tier` says otherwise, existing pools keep their tier.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn buy_seats(
+    pool_id: Option<u64>,
+    count: u32,
+    token_type: TokenType,
+    payment_period: PaymentPeriod,
+    tier: Option<EntitlementTier>,
+) -> Result<PaymentRecord, String> {
+    let caller = ic_cdk::api::msg_caller();
+    if count == 0 || count > MAX_SEATS_PER_PURCHASE {
+        return Err(format!("Seat count must be between 1 and {}", MAX_SEATS_PER_PURCHASE));
+    }
+    let pool = match pool_id {
+        Some(pool_id) => Some(owned_seat_pool(caller, pool_id)?),
+        None => None,
+    };
+    let tier = match &pool {
+        Some(pool) => pool.tier.clone(),
+        None => tier.unwrap_or(EntitlementTier::Team),
+    };
+    let quote = quote_purchase(&tier, &token_type, &payment_period, count)?;
+
+    pay_for_purchase(caller, quote, |_| {
+        // a new pool only reserves its id here, fulfil_payment creates it once the seats are paid for
+        let pool_id = match pool {
+            Some(pool) => pool.id,
+            None => NEXT_SEAT_POOL_ID.with_borrow_mut(|cell| {
+                let id = *cell.get();
+                cell.set(id + 1);
+                id
+            }),
+        };
+        PaymentPurchase::Seats { pool_id, count }
+    })
+    .await
+}
+
+/// Add one period to each of `seat_ids`. Lapsed seats start again from now.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn renew_seats(
+    pool_id: u64,
+    mut seat_ids: Vec<u32>,
+    token_type: TokenType,
+    payment_period: PaymentPeriod,
+) -> Result<PaymentRecord, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let pool = owned_seat_pool(caller, pool_id)?;
+    seat_ids.sort_unstable();
+    seat_ids.dedup();
+    if seat_ids.is_empty() || seat_ids.len() > MAX_SEATS_PER_PURCHASE as usize {
+        return Err(format!("Seat count must be between 1 and {}", MAX_SEATS_PER_PURCHASE));
+    }
+    let missing = SEATS.with_borrow(|seats| {
+        seat_ids
+            .iter()
+            .find(|seat_id| !seats.contains_key(&SeatKey { pool_id, seat_id: **seat_id }))
+            .copied()
+    });
+    if let Some(seat_id) = missing {
+        return Err(format!("Seat {} not found", seat_id));
+    }
+    let quote = quote_purchase(&pool.tier, &token_type, &payment_period, seat_ids.len() as u32)?;
+
+    pay_for_purchase(caller, quote, |_| PaymentPurchase::SeatRenewal { pool_id, seat_ids }).await
+}
+
+/// Give a seat to `assignee`, or free it with None. The previous holder loses the seat's tier straight away.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn assign_seat(pool_id: u64, seat_id: u32, assignee: Option<Principal>) -> Result<Seat, String> {
+    let caller = ic_cdk::api::msg_caller();
+    owned_seat_pool(caller, pool_id)?;
+    let key = SeatKey { pool_id, seat_id };
+    let mut seat = SEATS
+        .with_borrow(|seats| seats.get(&key))
+        .ok_or("Seat not found".to_string())?;
+    if seat.assignee == assignee {
+        return Ok(seat);
+    }
+    if let Some(assignee) = assignee {
+        if assignee == Principal::anonymous() {
+            return Err("Cannot assign a seat to the anonymous principal".to_string());
+        }
+    }
+
+    let now = get_current_time_in_milli();
+    if let Some(assignee) = assignee {
+        if let Some(held) = SEAT_ASSIGNMENTS.with_borrow(|assignments| assignments.get(&assignee)) {
+            if SEATS.with_borrow(|seats| seats.get(&held)).is_some_and(|seat| seat.is_active(now)) {
+                return Err("This principal already holds a seat".to_string());
+            }
+            // a lapsed seat gives no tier, free it so the principal can take this one
+            SEATS.with_borrow_mut(|seats| {
+                if let Some(mut lapsed) = seats.get(&held) {
+                    lapsed.assignee = None;
+                    lapsed.assigned_at = None;
+                    seats.insert(held, lapsed);
+                }
+            });
+            SEAT_ASSIGNMENTS.with_borrow_mut(|assignments| assignments.remove(&assignee));
+        }
+    }
+    let previous = seat.assignee.take();
+    if let Some(previous) = previous {
+        SEAT_ASSIGNMENTS.with_borrow_mut(|assignments| assignments.remove(&previous));
+    }
+    seat.assignee = assignee;
+    seat.assigned_at = assignee.map(|_| now);
+    SEATS.with_borrow_mut(|seats| seats.insert(key, seat.clone()));
+    if let Some(assignee) = assignee {
+        SEAT_ASSIGNMENTS.with_borrow_mut(|assignments| assignments.insert(assignee, key));
+        if seat.is_active(now) {
+            ENTITLEMENT_EXPIRIES.with_borrow_mut(|expiries| expiries.insert(EntitlementExpiryKey { end: seat.end, principal: assignee }));
+            schedule_entitlement_expiry();
+        }
+    }
+
+    let affected: Vec<Principal> = previous.into_iter().chain(assignee).collect();
+    for principal in affected.iter() {
+        refresh_premium_status(*principal);
+    }
+    for principal in affected {
+        if let Err(e) = sync_asset_storage_access(principal).await {
+            ic_cdk::api::debug_print(&format!("Failed to update asset storage access for {}: {}", principal, e));
+        }
+    }
+    Ok(seat)
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_seat_pools() -> Vec<SeatPoolView> {
+    let caller = ic_cdk::api::msg_caller();
+    let pools: Vec<SeatPool> = SEAT_POOLS.with_borrow(|pools| {
+        pools
+            .iter()
+            .map(|entry| entry.value())
+            .filter(|pool| pool.owner == caller)
+            .collect()
+    });
+    pools
+        .into_iter()
+        .map(|pool| SeatPoolView {
+            seats: pool_seats(pool.id),
+            pool,
+        })
+        .collect()
+}
+
+/// The team seat the caller holds, if any.
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_seat() -> Option<Seat> {
+    let caller = ic_cdk::api::msg_caller();
+    SEAT_ASSIGNMENTS
+        .with_borrow(|assignments| assignments.get(&caller))
+        .and_then(|key| SEATS.with_borrow(|seats| seats.get(&key)))
+}
+
+// Referral constants
+const REFERRAL_CODE_PREFIX: &str = "REF-";
+const DAY_MILLIS: u64 = 24 * 60 * 60 * 1000;
+
+fn default_referral_config() -> ReferralConfig {
+    ReferralConfig {
+        enabled: true,
+        reward: ReferralReward::PremiumDays(30),
+    }
+}
+
+// Whether the principal has had a plan of their own granted from a payment
+fn has_paid_premium(principal: &Principal) -> bool {
+    let payment_ids: Vec<u64> = PAYER_PAYMENTS.with_borrow(|payer_payments| {
+        payer_payments
+            .range(PayerPaymentKey { payer: *principal, payment_id: 0 }..)
+            .take_while(|key| key.payer == *principal)
+            .map(|key| key.payment_id)
+            .collect()
+    });
+    PAYMENTS.with_borrow(|payments| {
+        payment_ids
+            .into_iter()
+            .filter_map(|payment_id| payments.get(&payment_id))
+            .any(|payment| payment.state == PaymentState::Granted && payment.purchase.is_none() && payment.period.is_some())
+    })
+}
+
+// Credit the referrer the first time a referred user pays for their own plan.
+// Returns the referrer when they were granted premium days.
+fn reward_referral(payment: &PaymentRecord) -> Option<Principal> {
+    let mut referral = REFERRALS.with_borrow(|referrals| referrals.get(&payment.payer))?;
+    if !matches!(referral.status, ReferralStatus::Pending) {
+        return None;
+    }
+    let config = REFERRAL_CONFIG.with_borrow(|config| config.get().clone());
+    if !config.enabled {
+        return None;
+    }
+
+    let referrer = referral.referrer;
+    let (payout_payment_id, granted) = match &config.reward {
+        ReferralReward::PremiumDays(days) => {
+            grant_premium_entitlement(
+                referrer,
+                EntitlementTier::Pro,
+                (*days as u64).saturating_mul(DAY_MILLIS),
+                EntitlementSource::Referral { referee: payment.payer },
+            );
+            (None, Some(referrer))
+        }
+        ReferralReward::TokenPayout(amount) => {
+            let amount = price_to_token_units(*amount, &payment.token_type);
+            let to = Account {
+                owner: canister_self(),
+                subaccount: Some(principal_to_subaccount(referrer)),
+            };
+            // paid into the referrer's deposit subaccount, reconcile_payments retries it if this attempt fails
+            let payout = open_payment(referrer, PaymentKind::Payout { to }, payment.token_type.clone(), payment.ledger, get_system_account(), amount, None, None);
+            let payout_id = payout.id;
+            ic_cdk::futures::spawn(async move {
+                if let Err(e) = settle_payment(payout_id).await {
+                    ic_cdk::api::debug_print(&format!("Failed to settle referral payout {}: {}", payout_id, e));
+                }
+            });
+            (Some(payout_id), None)
+        }
+    };
+
+    referral.status = ReferralStatus::Rewarded {
+        payment_id: payment.id,
+        reward: config.reward,
+        payout_payment_id,
+        at: get_current_time_in_milli(),
+    };
+    REFERRALS.with_borrow_mut(|referrals| referrals.insert(payment.payer, referral));
+    granted
+}
+
+/// The caller's referral code, created on first use.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn get_referral_code() -> Result<String, String> {
+    let caller = ic_cdk::api::msg_caller();
+    if let Some(code) = REFERRER_CODES.with_borrow(|codes| codes.get(&caller)) {
+        return Ok(code);
+    }
+
+    let random_bytes = raw_rand().await.map_err(|e| format!("Failed to generate referral code: {:?}", e))?;
+    let code = format!("{}{}", REFERRAL_CODE_PREFIX, hex::encode(&random_bytes[..5]).to_ascii_uppercase());
+    // a concurrent call may have created one while we waited for randomness
+    if let Some(existing) = REFERRER_CODES.with_borrow(|codes| codes.get(&caller)) {
+        return Ok(existing);
+    }
+    if REFERRAL_CODES.with_borrow(|codes| codes.contains_key(&code)) {
+        return Err("Failed to generate a unique referral code, please try again".to_string());
+    }
+    REFERRAL_CODES.with_borrow_mut(|codes| codes.insert(code.clone(), caller));
+    REFERRER_CODES.with_borrow_mut(|codes| codes.insert(caller, code.clone()));
+    Ok(code)
+}
+
+/// Record who referred the caller. Only possible once and before the caller's first premium payment.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn apply_referral_code(code: String) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    if !REFERRAL_CONFIG.with_borrow(|config| config.get().enabled) {
+        return Err("The referral program is not running".to_string());
+    }
+    let code = code.trim().to_ascii_uppercase();
+    let referrer = REFERRAL_CODES
+        .with_borrow(|codes| codes.get(&code))
+        .ok_or("Invalid referral code".to_string())?;
+    if referrer == caller {
+        return Err("You cannot use your own referral code".to_string());
+    }
+    if REFERRALS.with_borrow(|referrals| referrals.contains_key(&caller)) {
+        return Err("You have already used a referral code".to_string());
+    }
+    // two accounts referring each other
+    let referrer_referred_by_caller = REFERRALS
+        .with_borrow(|referrals| referrals.get(&referrer))
+        .is_some_and(|referral| referral.referrer == caller);
+    if referrer_referred_by_caller {
+        return Err("You cannot use the code of someone you referred".to_string());
+    }
+    if has_paid_premium(&caller) {
+        return Err("Referral codes only apply before your first premium payment".to_string());
+    }
+
+    REFERRALS.with_borrow_mut(|referrals| {
+        referrals.insert(caller, Referral {
+            referrer,
+            referee: caller,
+            code,
+            status: ReferralStatus::Pending,
+            referred_at: get_current_time_in_milli(),
+        })
+    });
+    REFERRER_REFERRALS.with_borrow_mut(|keys| keys.insert(ReferrerKey { referrer, referee: caller }));
+    Ok(())
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_referral_stats() -> ReferralStats {
+    let caller = ic_cdk::api::msg_caller();
+    let referrals: Vec<Referral> = REFERRER_REFERRALS.with_borrow(|keys| {
+        keys.range(ReferrerKey { referrer: caller, referee: Principal::management_canister() }..)
+            .take_while(|key| key.referrer == caller)
+            .filter_map(|key| REFERRALS.with_borrow(|referrals| referrals.get(&key.referee)))
+            .collect()
+    });
+
+    let mut stats = ReferralStats {
+        code: REFERRER_CODES.with_borrow(|codes| codes.get(&caller)),
+        referred: referrals.len() as u64,
+        rewarded: 0,
+        premium_days_earned: 0,
+        payouts: Vec::new(),
+        referred_by: REFERRALS.with_borrow(|referrals| referrals.get(&caller)).map(|referral| referral.referrer),
+    };
+    for referral in referrals {
+        let ReferralStatus::Rewarded { reward, payout_payment_id, .. } = referral.status else {
+            continue;
+        };
+        stats.rewarded += 1;
+        match reward {
+            ReferralReward::PremiumDays(days) => stats.premium_days_earned += days as u64,
+            ReferralReward::TokenPayout(_) => {
+                let payout = payout_payment_id
+                    .and_then(|payment_id| PAYMENTS.with_borrow(|payments| payments.get(&payment_id)))
+                    .filter(|payout| payout.state == PaymentState::Completed);
+                let Some(payout) = payout else {
+                    continue;
+                };
+                match stats.payouts.iter_mut().find(|total| total.token_type == payout.token_type) {
+                    Some(total) => {
+                        total.amount += payout.amount;
+                        total.payments += 1;
+                    }
+                    None => stats.payouts.push(RevenueTotal {
+                        token_type: payout.token_type.clone(),
+                        amount: payout.amount,
+                        payments: 1,
+                    }),
+                }
+            }
+        }
+    }
+    stats
+}
+
+#[ic_cdk::query]
+fn get_referral_config() -> ReferralConfig {
+    REFERRAL_CONFIG.with_borrow(|config| config.get().clone())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn set_referral_config(config: ReferralConfig) -> Result<(), String> {
+    match config.reward {
+        ReferralReward::PremiumDays(0) => return Err("Premium day rewards must be greater than zero".to_string()),
+        ReferralReward::TokenPayout(0) => return Err("Token payouts must be greater than zero".to_string()),
+        _ => {}
+    }
+    REFERRAL_CONFIG.with_borrow_mut(|cell| cell.set(config));
+    Ok(())
+}
+
+// Tip constants
+const MAX_TIP_FEE_BASIS_POINTS: u16 = 5_000;
+
+fn default_tip_config() -> TipConfig {
+    TipConfig {
+        enabled: true,
+        fee_basis_points: 500,
+        // 0.10 USD
+        min_amount: 100_000,
+    }
+}
+
+fn add_to_totals(totals: &mut Vec<RevenueTotal>, token_type: &TokenType, amount: u64) {
+    match totals.iter_mut().find(|total| total.token_type == *token_type) {
+        Some(total) => {
+            total.amount += amount;
+            total.payments += 1;
+        }
+        None => totals.push(RevenueTotal {
+            token_type: token_type.clone(),
+            amount,
+            payments: 1,
+        }),
+    }
+}
+
+fn summarize_tips(payment_ids: Vec<u64>) -> TipSummary {
+    let mut summary = TipSummary {
+        tips: 0,
+        totals: Vec::new(),
+    };
+    TIPS.with_borrow(|tips| {
+        for tip in payment_ids.into_iter().filter_map(|payment_id| tips.get(&payment_id)) {
+            summary.tips += 1;
+            add_to_totals(&mut summary.totals, &tip.token_type, tip.amount);
+        }
+    });
+    summary
+}
+
+fn note_tip_ids(note_id: &String) -> Vec<u64> {
+    NOTE_TIPS.with_borrow(|keys| {
+        keys.range(NoteTipKey { note_id: note_id.clone(), payment_id: 0 }..)
+            .take_while(|key| key.note_id == *note_id)
+            .map(|key| key.payment_id)
+            .collect()
+    })
+}
+
+fn author_tip_ids(author: Principal) -> Vec<u64> {
+    AUTHOR_TIPS.with_borrow(|keys| {
+        keys.range(AuthorTipKey { author, payment_id: 0 }..)
+            .take_while(|key| key.author == author)
+            .map(|key| key.payment_id)
+            .collect()
+    })
+}
+
+// Tip totals for the note template, None until the note has been tipped
+fn note_tips_context(note_id: &String) -> Option<NoteTips> {
+    let summary = summarize_tips(note_tip_ids(note_id));
+    if summary.tips == 0 {
+        return None;
+    }
+    Some(NoteTips {
+        count: summary.tips,
+        totals: summary
+            .totals
+            .iter()
+            .map(|total| TipTotal {
+                symbol: get_token_config(&total.token_type).symbol,
+                amount: u64_to_decimal(total.amount, token_decimals(&total.token_type)),
+            })
+            .collect(),
+    })
+}
+
+fn record_tip(payment: &PaymentRecord, note_id: &String, author: Principal) {
+    let fee_basis_points = TIP_CONFIG.with_borrow(|config| config.get().fee_basis_points);
+    let fee = credit_author(payment, author, fee_basis_points, LedgerEntryKind::Tip {
+        note_id: note_id.clone(),
+        payment_id: payment.id,
+    });
+
+    TIPS.with_borrow_mut(|tips| {
+        tips.insert(payment.id, Tip {
+            payment_id: payment.id,
+            note_id: note_id.clone(),
+            author,
+            tipper: payment.payer,
+            token_type: payment.token_type.clone(),
+            amount: payment.amount,
+            fee,
+            payout_payment_id: None,
+            created_at: get_current_time_in_milli(),
+        })
+    });
+    NOTE_TIPS.with_borrow_mut(|keys| keys.insert(NoteTipKey { note_id: note_id.clone(), payment_id: payment.id }));
+    AUTHOR_TIPS.with_borrow_mut(|keys| keys.insert(AuthorTipKey { author, payment_id: payment.id }));
+
+    // show the new totals on the note page
+    if let Err(e) = render_and_save_note(note_id.clone()) {
+        ic_cdk::api::debug_print(&format!("Failed to re-render note {}: {}", note_id, e));
+    }
+}
+
+/// Tip the author of a public note `amount` token units, taken from `from`, one of the caller's
+/// accounts, through its ICRC-2 approval to this canister. The author's creator balance is credited the tip minus the platform fee.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn tip_note(note_id: String, token_type: TokenType, amount: u64, from: Account) -> Result<PaymentRecord, String> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_own_account(caller, &from)?;
+    let config = TIP_CONFIG.with_borrow(|config| config.get().clone());
+    if !config.enabled {
+        return Err("Tipping is turned off".to_string());
+    }
+    ensure_token_accepted(&token_type)?;
+    if !is_public_note(&note_id) {
+        return Err("Only public notes can be tipped".to_string());
+    }
+    let author = PUBLISHED_NOTES
+        .with_borrow(|published| published.get(&note_id))
+        .and_then(|published_note| Principal::from_text(&published_note.author).ok())
+        .ok_or("Note not found".to_string())?;
+    if author == caller {
+        return Err("You cannot tip your own note".to_string());
+    }
+    let min_amount = price_to_token_units(config.min_amount, &token_type);
+    if amount < min_amount {
+        return Err(format!(
+            "Tips must be at least {} {}",
+            u64_to_decimal(min_amount, token_decimals(&token_type)),
+            get_token_config(&token_type).symbol
+        ));
+    }
+    if has_open_payment(caller) {
+        return Err("Another payment from this account is still being settled".to_string());
+    }
+
+    let ledger_canister_id = get_ledger_canister_id(&token_type);
+    let mut payment = open_payment(caller, PaymentKind::Approval, token_type, ledger_canister_id, from, amount, None, None);
+    payment.purchase = Some(PaymentPurchase::Tip { note_id, author });
+    save_payment(&payment);
+
+    let payment = settle_payment(payment.id).await?;
+    match &payment.state {
+        PaymentState::Failed(transfer_error) => Err(format!("Transfer error: {}", transfer_error)),
+        _ => Ok(payment),
+    }
+}
+
+#[ic_cdk::query]
+fn get_note_tips(note_id: String) -> Result<TipSummary, String> {
+    if !is_public_note(&note_id) {
+        return Err("Note not found".to_string());
+    }
+    Ok(summarize_tips(note_tip_ids(&note_id)))
+}
+
+/// Tips received on the caller's notes, newest first.
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_tips(offset: u32, limit: u32) -> Vec<Tip> {
+    let caller = ic_cdk::api::msg_caller();
+    let mut payment_ids = author_tip_ids(caller);
+    payment_ids.reverse();
+    TIPS.with_borrow(|tips| {
+        payment_ids
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAYMENTS_PAGE_SIZE) as usize)
+            .filter_map(|payment_id| tips.get(&payment_id))
+            .collect()
+    })
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_tip_summary() -> TipSummary {
+    summarize_tips(author_tip_ids(ic_cdk::api::msg_caller()))
+}
+
+#[ic_cdk::query]
+fn get_tip_config() -> TipConfig {
+    TIP_CONFIG.with_borrow(|config| config.get().clone())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn set_tip_config(config: TipConfig) -> Result<(), String> {
+    if config.fee_basis_points > MAX_TIP_FEE_BASIS_POINTS {
+        return Err(format!("The platform fee cannot exceed {} basis points", MAX_TIP_FEE_BASIS_POINTS));
+    }
+    if config.min_amount == 0 {
+        return Err("The minimum tip must be greater than zero".to_string());
+    }
+    TIP_CONFIG.with_borrow_mut(|cell| cell.set(config));
+    Ok(())
+}
+
+// Paid note constants
+const MAX_NOTE_SALE_FEE_BASIS_POINTS: u16 = 5_000;
+const PAID_NOTE_PREVIEW_CHARS: usize = 600;
+
+fn default_paid_note_config() -> PaidNoteConfig {
+    PaidNoteConfig {
+        enabled: true,
+        fee_basis_points: 1_000,
+        // 0.50 USD
+        min_price: 500_000,
+    }
+}
+
+fn has_note_access(principal: Principal, note_id: &String) -> bool {
+    !NOTE_PRICES.with_borrow(|prices| prices.contains_key(note_id))
+        || BUYER_NOTES.with_borrow(|buyer_notes| buyer_notes.contains_key(&BuyerNoteKey { buyer: principal, note_id: note_id.clone() }))
+}
+
+// Plain-text opening of a paid note, at most a third of it so the preview never gives the note away
+fn paid_note_preview(content: &str) -> String {
+    let collapsed = strip_html(content).split_whitespace().collect::<Vec<_>>().join(" ");
+    let limit = (collapsed.chars().count() / 3).min(PAID_NOTE_PREVIEW_CHARS);
+    let mut preview: String = collapsed.chars().take(limit).collect();
+    preview.push('…');
+    preview
+}
+
+// Paid notes are rendered with a preview in place of their content
+fn gate_paid_article(article: Article, note_id: &String) -> Article {
+    let Some(price) = NOTE_PRICES.with_borrow(|prices| prices.get(note_id)) else {
+        return article;
+    };
+    let preview = paid_note_preview(&article.content);
+    let mut article = article
+        .set_info_fi_asset()
+        .with_price(format!("{} USD", u64_to_decimal(price, PRICE_DECIMALS)))
+        .with_excerpt(preview.clone());
+    article.content = format!("<p>{}</p>", html_escape(&preview));
+    article
+}
+
+fn record_note_sale(payment: &PaymentRecord, note_id: &String, author: Principal) {
+    let fee_basis_points = PAID_NOTE_CONFIG.with_borrow(|config| config.get().fee_basis_points);
+    let fee = credit_author(payment, author, fee_basis_points, LedgerEntryKind::NoteSale {
+        note_id: note_id.clone(),
+        payment_id: payment.id,
+    });
+
+    NOTE_SALES.with_borrow_mut(|sales| {
+        sales.insert(payment.id, NoteSale {
+            payment_id: payment.id,
+            note_id: note_id.clone(),
+            author,
+            buyer: payment.payer,
+            token_type: payment.token_type.clone(),
+            amount: payment.amount,
+            fee,
+            payout_payment_id: None,
+            sold_at: get_current_time_in_milli(),
+        })
+    });
+    BUYER_NOTES.with_borrow_mut(|buyer_notes| {
+        buyer_notes.insert(BuyerNoteKey { buyer: payment.payer, note_id: note_id.clone() }, payment.id)
+    });
+    AUTHOR_SALES.with_borrow_mut(|keys| keys.insert(AuthorSaleKey { author, payment_id: payment.id }));
+}
+
+/// Sell access to one of the caller's public notes for `price` plan price units (USD, 6 decimals),
+/// payable in any accepted token. `None` makes the note free again; existing buyers keep access.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn set_note_price(note_id: String, price: Option<u64>) -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    let published_note = PUBLISHED_NOTES
+        .with_borrow(|published| published.get(&note_id))
+        .ok_or("Note not found".to_string())?;
+    if published_note.author != caller.to_text() {
+        return Err("Not authorized to price this note".to_string());
+    }
+    if !matches!(published_note.access_type, AccessType::Public) {
+        return Err("Only public notes can be sold".to_string());
+    }
+
+    match price {
+        Some(price) => {
+            let config = PAID_NOTE_CONFIG.with_borrow(|config| config.get().clone());
+            if !config.enabled {
+                return Err("Paid notes are turned off".to_string());
+            }
+            if price < config.min_price {
+                return Err(format!("Notes must cost at least {} USD", u64_to_decimal(config.min_price, PRICE_DECIMALS)));
+            }
+            NOTE_PRICES.with_borrow_mut(|prices| prices.insert(note_id.clone(), price));
+        }
+        None => {
+            NOTE_PRICES.with_borrow_mut(|prices| prices.remove(&note_id));
+        }
+    }
+    // excerpts on search results and tag pages follow the price
+    reindex_published_note(&note_id);
+    sync_note_tags(&note_id, &[]);
+    render_and_save_note(note_id)
+}
+
+#[ic_cdk::query]
+fn get_note_price(note_id: String) -> Option<u64> {
+    NOTE_PRICES.with_borrow(|prices| prices.get(&note_id))
+}
+
+/// Buy access to a paid note, taken from `from`, one of the caller's accounts, through its ICRC-2
+/// approval to this canister.
+/// Once settled, `get_restricted_note` returns the full note to the caller.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn buy_note(note_id: String, token_type: TokenType, from: Account) -> Result<PaymentRecord, String> {
+    let caller = ic_cdk::api::msg_caller();
+    ensure_own_account(caller, &from)?;
+    if !PAID_NOTE_CONFIG.with_borrow(|config| config.get().enabled) {
+        return Err("Paid notes are turned off".to_string());
+    }
+    ensure_token_accepted(&token_type)?;
+    let price = NOTE_PRICES
+        .with_borrow(|prices| prices.get(&note_id))
+        .ok_or("This note is not for sale".to_string())?;
+    let author = PUBLISHED_NOTES
+        .with_borrow(|published| published.get(&note_id))
+        .and_then(|published_note| Principal::from_text(&published_note.author).ok())
+        .ok_or("Note not found".to_string())?;
+    if author == caller {
+        return Err("You cannot buy your own note".to_string());
+    }
+    if BUYER_NOTES.with_borrow(|buyer_notes| buyer_notes.contains_key(&BuyerNoteKey { buyer: caller, note_id: note_id.clone() })) {
+        return Err("You already own this note".to_string());
+    }
+    if has_open_payment(caller) {
+        return Err("Another payment from this account is still being settled".to_string());
+    }
+
+    let ledger_canister_id = get_ledger_canister_id(&token_type);
+    let amount = price_to_token_units(price, &token_type);
+    let mut payment = open_payment(caller, PaymentKind::Approval, token_type, ledger_canister_id, from, amount, None, None);
+    payment.purchase = Some(PaymentPurchase::Note { note_id, author });
+    save_payment(&payment);
+
+    let payment = settle_payment(payment.id).await?;
+    match &payment.state {
+        PaymentState::Failed(transfer_error) => Err(format!("Transfer error: {}", transfer_error)),
+        _ => Ok(payment),
+    }
+}
+
+/// Ids of the notes the caller has bought.
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_purchased_notes() -> Vec<String> {
+    let caller = ic_cdk::api::msg_caller();
+    BUYER_NOTES.with_borrow(|buyer_notes| {
+        buyer_notes
+            .range(BuyerNoteKey { buyer: caller, note_id: String::new() }..)
+            .take_while(|entry| entry.key().buyer == caller)
+            .map(|entry| entry.key().note_id.clone())
+            .collect()
+    })
+}
+
+fn author_sale_ids(author: Principal) -> Vec<u64> {
+    AUTHOR_SALES.with_borrow(|keys| {
+        keys.range(AuthorSaleKey { author, payment_id: 0 }..)
+            .take_while(|key| key.author == author)
+            .map(|key| key.payment_id)
+            .collect()
+    })
+}
+
+/// Sales of the caller's notes, newest first.
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_note_sales(offset: u32, limit: u32) -> Vec<NoteSale> {
+    let mut payment_ids = author_sale_ids(ic_cdk::api::msg_caller());
+    payment_ids.reverse();
+    NOTE_SALES.with_borrow(|sales| {
+        payment_ids
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAYMENTS_PAGE_SIZE) as usize)
+            .filter_map(|payment_id| sales.get(&payment_id))
+            .collect()
+    })
+}
+
+/// Gross sales and the author's share after the platform fee over all of the caller's notes.
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_note_sales_summary() -> NoteSalesSummary {
+    let mut summary = NoteSalesSummary {
+        sales: 0,
+        totals: Vec::new(),
+        earnings: Vec::new(),
+    };
+    let sales: Vec<NoteSale> = NOTE_SALES.with_borrow(|sales| {
+        author_sale_ids(ic_cdk::api::msg_caller())
+            .into_iter()
+            .filter_map(|payment_id| sales.get(&payment_id))
+            .collect()
+    });
+    for sale in sales {
+        summary.sales += 1;
+        add_to_totals(&mut summary.totals, &sale.token_type, sale.amount);
+        add_to_totals(&mut summary.earnings, &sale.token_type, sale.amount - sale.fee);
+    }
+    summary
+}
+
+#[ic_cdk::query]
+fn get_paid_note_config() -> PaidNoteConfig {
+    PAID_NOTE_CONFIG.with_borrow(|config| config.get().clone())
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+fn set_paid_note_config(config: PaidNoteConfig) -> Result<(), String> {
+    if config.fee_basis_points > MAX_NOTE_SALE_FEE_BASIS_POINTS {
+        return Err(format!("The platform fee cannot exceed {} basis points", MAX_NOTE_SALE_FEE_BASIS_POINTS));
+    }
+    if config.min_price == 0 {
+        return Err("The minimum price must be greater than zero".to_string());
+    }
+    PAID_NOTE_CONFIG.with_borrow_mut(|cell| cell.set(config));
+    Ok(())
+}
+
+// The platform fee account in the creator ledger
+fn platform_account() -> Principal {
+    canister_self()
+}
+
+fn ledger_balance(owner: Principal, token_type: &TokenType) -> u64 {
+    LEDGER_BALANCES
+        .with_borrow(|balances| balances.get(&LedgerBalanceKey { owner, token_type: token_type.clone() }))
+        .unwrap_or_default()
+}
+
+// Append an entry to the owner's statement and move their balance. Overdrafts are refused.
+fn post_ledger_entry(owner: Principal, token_type: &TokenType, kind: LedgerEntryKind, credit: u64, debit: u64, now: u64) -> Result<LedgerEntry, String> {
+    let balance = ledger_balance(owner, token_type)
+        .checked_add(credit)
+        .and_then(|balance| balance.checked_sub(debit))
+        .ok_or("Insufficient balance".to_string())?;
+    let id = NEXT_LEDGER_ENTRY_ID.with_borrow_mut(|cell| {
+        let id = *cell.get();
+        cell.set(id + 1);
+        id
+    });
+    let entry = LedgerEntry {
+        id,
+        owner,
+        token_type: token_type.clone(),
+        kind,
+        credit,
+        debit,
+        balance,
+        created_at: now,
+    };
+    LEDGER_ENTRIES.with_borrow_mut(|entries| entries.insert(id, entry.clone()));
+    OWNER_LEDGER_ENTRIES.with_borrow_mut(|keys| keys.insert(OwnerLedgerEntryKey { owner, entry_id: id }));
+    LEDGER_BALANCES.with_borrow_mut(|balances| balances.insert(LedgerBalanceKey { owner, token_type: token_type.clone() }, balance));
+    Ok(entry)
+}
+
+// Split a settled tip or sale between the author and the platform fee account. Returns the fee.
+fn credit_author(payment: &PaymentRecord, author: Principal, fee_basis_points: u16, kind: LedgerEntryKind) -> u64 {
+    let fee = (payment.amount as u128 * fee_basis_points as u128 / 10_000) as u64;
+    if let Err(e) = post_ledger_entry(author, &payment.token_type, kind, payment.amount - fee, 0, payment.updated_at) {
+        ic_cdk::api::debug_print(&format!("Failed to credit author {} for payment {}: {}", author, payment.id, e));
+    }
+    if fee > 0 {
+        if let Err(e) = post_ledger_entry(platform_account(), &payment.token_type, LedgerEntryKind::PlatformFee { payment_id: payment.id }, fee, 0, payment.updated_at) {
+            ic_cdk::api::debug_print(&format!("Failed to credit platform fee for payment {}: {}", payment.id, e));
+        }
+    }
+    fee
+}
+
+// Give back what a rejected withdrawal debited
+fn reverse_earnings_withdrawal(payment: &PaymentRecord) {
+    if !matches!(payment.kind, PaymentKind::Earnings { .. }) || !matches!(payment.state, PaymentState::Failed(_)) {
+        return;
+    }
+    let amount = payment.amount.saturating_add(payment.fee.unwrap_or_default());
+    if let Err(e) = post_ledger_entry(payment.payer, &payment.token_type, LedgerEntryKind::WithdrawalReversal { payment_id: payment.id }, amount, 0, payment.updated_at) {
+        ic_cdk::api::debug_print(&format!("Failed to reverse withdrawal {}: {}", payment.id, e));
+    }
+}
+
+// Internal balances must be backed by what the system account holds on the ledger
+async fn check_ledger_invariant(token_type: &TokenType) -> Result<LedgerInvariant, String> {
+    let held = check_user_balance(token_type, get_system_account()).await?;
+    let internal_total: u128 = LEDGER_BALANCES.with_borrow(|balances| {
+        balances
+            .iter()
+            .filter(|entry| entry.key().token_type == *token_type)
+            .map(|entry| entry.value() as u128)
+            .sum()
+    });
+    Ok(LedgerInvariant {
+        token_type: token_type.clone(),
+        internal_total: internal_total.min(u64::MAX as u128) as u64,
+        held,
+        holds: internal_total <= held as u128,
+    })
+}
+
+// Pay `amount` out of `owner`'s ledger balance. The ledger fee is debited on top.
+async fn withdraw_from_ledger(owner: Principal, token_type: TokenType, amount: u64, to: Account) -> Result<PaymentRecord, String> {
+    if amount == 0 {
+        return Err("Amount must be greater than zero".to_string());
+    }
+    if to == get_system_account() {
+        return Err("Cannot withdraw to the account holding the balances".to_string());
+    }
+
+    let ledger_canister_id = get_ledger_canister_id(&token_type);
+    let fee = get_ledger_fee(ledger_canister_id).await?;
+    if !check_ledger_invariant(&token_type).await?.holds {
+        return Err("Balances are not fully backed by the ledger, withdrawals are paused".to_string());
+    }
+
+    // checked after the ledger calls, nothing can move the balance between here and the debit
+    let decimals = token_decimals(&token_type);
+    let required = amount.saturating_add(fee);
+    let available = ledger_balance(owner, &token_type);
+    if available < required {
+        return Err(format!(
+            "Insufficient balance. Required: {} (including {} fee), Available: {}",
+            u64_to_decimal(required, decimals),
+            u64_to_decimal(fee, decimals),
+            u64_to_decimal(available, decimals)
+        ));
+    }
+    let payment = open_payment(owner, PaymentKind::Earnings { to }, token_type.clone(), ledger_canister_id, get_system_account(), amount, Some(fee), None);
+    post_ledger_entry(owner, &token_type, LedgerEntryKind::Withdrawal { payment_id: payment.id }, 0, required, payment.created_at)?;
+
+    let payment = settle_payment(payment.id).await?;
+    match &payment.state {
+        PaymentState::Failed(transfer_error) => Err(format!("Transfer error: {}", transfer_error)),
+        _ => Ok(payment),
+    }
+}
+
+fn ledger_statement(owner: Principal, offset: u32, limit: u32) -> LedgerStatement {
+    let balances = LEDGER_BALANCES.with_borrow(|balances| {
+        balances
+            .range(LedgerBalanceKey { owner, token_type: TokenType::CKUSDC }..)
+            .take_while(|entry| entry.key().owner == owner)
+            .map(|entry| LedgerBalance { token_type: entry.key().token_type.clone(), balance: entry.value() })
+            .collect()
+    });
+    let mut entry_ids: Vec<u64> = OWNER_LEDGER_ENTRIES.with_borrow(|keys| {
+        keys.range(OwnerLedgerEntryKey { owner, entry_id: 0 }..)
+            .take_while(|key| key.owner == owner)
+            .map(|key| key.entry_id)
+            .collect()
+    });
+    entry_ids.reverse();
+
+    LedgerStatement {
+        owner,
+        balances,
+        total: entry_ids.len() as u64,
+        entries: LEDGER_ENTRIES.with_borrow(|entries| {
+            entry_ids
+                .into_iter()
+                .skip(offset as usize)
+                .take(limit.min(MAX_PAYMENTS_PAGE_SIZE) as usize)
+                .filter_map(|entry_id| entries.get(&entry_id))
+                .collect()
+        }),
+    }
+}
+
+/// The caller's creator balances and statement entries, newest first.
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_my_statement(offset: u32, limit: u32) -> LedgerStatement {
+    ledger_statement(ic_cdk::api::msg_caller(), offset, limit)
+}
+
+/// Withdraw creator earnings to any ICRC-1 account. The ledger fee is charged on top of `amount`.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn withdraw_earnings(token_type: TokenType, amount: u64, to: Account) -> Result<PaymentRecord, String> {
+    withdraw_from_ledger(ic_cdk::api::msg_caller(), token_type, amount, to).await
+}
+
+/// Statement of the platform fee account, or of any creator when `owner` is given.
+#[ic_cdk::query(guard = "is_controller")]
+fn get_ledger_statement(owner: Option<Principal>, offset: u32, limit: u32) -> LedgerStatement {
+    ledger_statement(owner.unwrap_or_else(platform_account), offset, limit)
+}
+
+#[ic_cdk::update(guard = "is_controller")]
+async fn withdraw_platform_fees(token_type: TokenType, amount: u64, to: Account) -> Result<PaymentRecord, String> {
+    withdraw_from_ledger(platform_account(), token_type, amount, to).await
+}
+
+/// Compare the internal balances of every configured token with what the system account holds.
+#[ic_cdk::update(guard = "is_controller")]
+async fn check_ledger_invariants() -> Result<Vec<LedgerInvariant>, String> {
+    // disabled tokens can still carry balances
+    let tokens: Vec<TokenType> = get_pricing().tokens.into_iter().map(|token| token.token_type).collect();
+    let mut invariants = Vec::new();
+    for token_type in tokens {
+        invariants.push(check_ledger_invariant(&token_type).await?);
+    }
+    Ok(invariants)
+}
+
+fn nft_canister() -> Result<Principal, String> {
+    let canister_id = NFT_CANISTER_ID.ok_or("Note NFTs are not available".to_string())?;
+    Principal::from_text(canister_id).map_err(|e| format!("Failed to parse note collection canister ID: {}", e))
+}
+
+/// Mint one of the caller's public notes into the note collection. The token's owner owns the note:
+/// transferring it hands the note to the recipient.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn mint_note_nft(note_id: String) -> Result<u64, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let nft_canister = nft_canister()?;
+    let published_note = PUBLISHED_NOTES
+        .with_borrow(|published| published.get(&note_id))
+        .ok_or("Note not found".to_string())?;
+    if published_note.author != caller.to_text() {
+        return Err("Not authorized to mint this note".to_string());
+    }
+    if !matches!(published_note.access_type, AccessType::Public) {
+        return Err("Only public notes can be minted".to_string());
+    }
+    if NOTE_NFTS.with_borrow(|note_nfts| note_nfts.contains_key(&note_id)) {
+        return Err("Note is already minted".to_string());
+    }
+    let note = NOTES
+        .with_borrow(|notes| notes.get(&note_id))
+        .ok_or("Note not found".to_string())?;
+    let Some(_lock) = HeapLock::acquire(&MINTING_NOTES, note_id.clone()) else {
+        return Err("Note is already being minted".to_string());
+    };
+
+    let args = MintNoteNftArgs {
+        owner: caller,
+        metadata: NoteNftMetadata {
+            note_id: note_id.clone(),
+            title: note.title.clone(),
+            content_hash: hex::encode(Sha256::digest(note.content.as_bytes())),
+            author: caller.to_text(),
+            url: format!("https://{}.icp0.io/{}", canister_self().to_text(), note_id),
+            minted_at: get_current_time_in_milli(),
+        },
+    };
+    let result = match ic_cdk::call::Call::unbounded_wait(nft_canister, "mint").with_arg(args).await {
+        Ok(response) => response
+            .candid::<Result<Nat, String>>()
+            .map_err(|e| format!("Failed to decode mint response: {:?}", e))
+            .and_then(|result| result)
+            .and_then(|token_id| u64::try_from(token_id.0).map_err(|_| "Token id out of range".to_string())),
+        Err(e) => Err(format!("Failed to call mint: {:?}", e)),
+    };
+
+    // mint is idempotent, so if this reply was lost a retry gets the same token id to record
+    let token_id = result?;
+    NOTE_NFTS.with_borrow_mut(|note_nfts| note_nfts.insert(note_id, token_id));
+    Ok(token_id)
+}
+
+#[ic_cdk::query]
+fn get_note_nft(note_id: String) -> Option<u64> {
+    NOTE_NFTS.with_borrow(|note_nfts| note_nfts.get(&note_id))
+}
+
+/// Called by the note collection before it moves a token: the note changes author to the new holder.
+#[ic_cdk::update]
+fn transfer_note_ownership(transfer: NoteNftTransfer) -> Result<(), String> {
+    if ic_cdk::api::msg_caller() != nft_canister()? {
+        return Err("Only the note collection can transfer notes".to_string());
+    }
+    if NOTE_NFTS.with_borrow(|note_nfts| note_nfts.get(&transfer.note_id)) != Some(transfer.token_id) {
+        return Err("Token does not belong to this note".to_string());
+    }
+    let mut published_note = PUBLISHED_NOTES
+        .with_borrow(|published| published.get(&transfer.note_id))
+        .ok_or("Note not found".to_string())?;
+    if published_note.author != transfer.from.to_text() {
+        return Err("Note is not owned by the sender".to_string());
+    }
+    let mut note = NOTES
+        .with_borrow(|notes| notes.get(&transfer.note_id))
+        .ok_or("Note not found".to_string())?;
+
+    published_note.author = transfer.to.to_text();
+    note.author = transfer.to.to_text();
+    PUBLISHED_NOTES.with_borrow_mut(|published| published.insert(transfer.note_id.clone(), published_note));
+    NOTES.with_borrow_mut(|notes| notes.insert(transfer.note_id.clone(), note));
+    USER_PUBLISHED_NOTES.with_borrow_mut(|published_ids| {
+        published_ids.remove(&owner_note_key(&transfer.from, &transfer.note_id));
+        published_ids.insert(owner_note_key(&transfer.to, &transfer.note_id));
+    });
+    reindex_owner_note(&transfer.from, &transfer.note_id);
+    reindex_owner_note(&transfer.to, &transfer.note_id);
+    // the page and tag listings show the author
+    reindex_published_note(&transfer.note_id);
+    sync_note_tags(&transfer.note_id, &[]);
+    // ownership is committed, the collection moves the token once this returns Ok
+    if let Err(e) = render_and_save_note(transfer.note_id.clone()) {
+        ic_cdk::api::debug_print(&format!("Failed to render transferred note {}: {}", transfer.note_id, e));
+    }
+    Ok(())
+}
+
+/// Send `amount` of `token_type` from the caller's deposit subaccount to any ICRC-1 account.
+/// The ledger fee is charged on top of `amount`, so the subaccount must hold both.
+#[ic_cdk::update(guard = "is_authenticated")]
+async fn withdraw(token_type: TokenType, amount: u64, to: Account) -> Result<PaymentRecord, String> {
+    let caller = ic_cdk::api::msg_caller();
+    if amount == 0 {
+        return Err("Amount must be greater than zero".to_string());
+    }
+
+    let deposit_account = Account {
+        owner: canister_self(),
+        subaccount: Some(principal_to_subaccount(caller)),
+    };
+    if to == deposit_account {
+        return Err("Cannot withdraw to the deposit account itself".to_string());
+    }
+    if has_open_payment(caller) {
+        return Err("Another payment from this account is still being settled".to_string());
+    }
+
+    let ledger_canister_id = get_ledger_canister_id(&token_type);
+    let decimals = token_decimals(&token_type);
+    let fee = get_ledger_fee(ledger_canister_id).await?;
+    let balance = check_user_balance(&token_type, deposit_account).await?;
+    if balance < amount.saturating_add(fee) {
+        return Err(format!(
+            "Insufficient balance. Required: {} (including {} fee), Available: {}",
+            u64_to_decimal(amount.saturating_add(fee), decimals),
+            u64_to_decimal(fee, decimals),
+            u64_to_decimal(balance, decimals)
+        ));
+    }
+    // another withdrawal may have started while we were waiting on the ledger
+    if has_open_payment(caller) {
+        return Err("Another payment from this account is still being settled".to_string());
+    }
+
+    let payment = open_payment(
+        caller,
+        PaymentKind::Withdrawal { to },
+        token_type,
+        ledger_canister_id,
+        deposit_account,
+        amount,
+        Some(fee),
+        None,
+    );
+
+    let payment = settle_payment(payment.id).await?;
+    match &payment.state {
+        PaymentState::Failed(transfer_error) => Err(format!("Transfer error: {}", transfer_error)),
+        _ => Ok(payment),
+    }
+}
+
+// Deposit watch constants
+const DEPOSIT_CHECK_INTERVAL_SECS: u64 = 60;
+// watches are dropped if nothing arrives within a week
+const DEPOSIT_WATCH_TTL: u64 = 7 * 24 * 60 * 60 * 1000;
+
+/// Grant premium for `payment_period` as soon as the caller's deposit subaccount
+/// holds enough, so the browser doesn't have to stay open after funding it.
+#[ic_cdk::update(guard = "is_authenticated")]
+fn watch_deposit(
+    payment_period: PaymentPeriod,
+    promo_code: Option<String>,
+    tier: Option<EntitlementTier>,
+) -> Result<DepositWatch, String> {
+    let caller = ic_cdk::api::msg_caller();
+    let tier = tier.unwrap_or(EntitlementTier::Pro).normalized();
+    let plan = get_plan_price(&tier, &payment_period)?;
+    let current_tier = user_tier(&caller);
+    if current_tier.rank() > tier.rank() {
+        return Err(format!("You already have an active {:?} plan", current_tier));
+    }
+    // the code is applied again when the deposit arrives
+    let promo_code = match promo_code {
+        Some(code) => {
+            let (code, _) = apply_promo_code(&code, caller, &plan, get_current_time_in_milli())?;
+            ensure_promo_code_allowed(&code, &PaymentKind::Deposit)?;
+            Some(code)
+        }
+        None => None,
+    };
+    let watch = DepositWatch {
+        payment_period,
+        promo_code,
+        tier: Some(tier),
+        created_at: get_current_time_in_milli(),
+        last_checked_at: None,
+        last_error: None,
+    };
+    DEPOSIT_WATCHES.with_borrow_mut(|watches| watches.insert(caller, watch.clone()));
+    Ok(watch)
+}
+
+#[ic_cdk::update(guard = "is_authenticated")]
+fn cancel_deposit_watch() -> Result<(), String> {
+    let caller = ic_cdk::api::msg_caller();
+    DEPOSIT_WATCHES
+        .with_borrow_mut(|watches| watches.remove(&caller))
+        .map(|_| ())
+        .ok_or("No deposit watch found".to_string())
+}
+
+#[ic_cdk::query(guard = "is_authenticated")]
+fn get_deposit_watch() -> Option<DepositWatch> {
+    let caller = ic_cdk::api::msg_caller();
+    DEPOSIT_WATCHES.with_borrow(|watches| watches.get(&caller))
+}
+
+fn has_open_payment(payer: Principal) -> bool {
+    let payment_ids: Vec<u64> = PAYER_PAYMENTS.with_borrow(|payer_payments| {
+        payer_payments
+            .range(PayerPaymentKey { payer, payment_id: 0 }..)
+            .take_while(|key| key.payer == payer)
+            .map(|key| key.payment_id)
+            .collect()
+    });
+    OPEN_PAYMENTS.with_borrow(|open| payment_ids.iter().any(|payment_id| open.contains(payment_id)))
+}
+
+// Timer: poll watched deposit subaccounts and settle a payment once enough has arrived
+fn check_deposits() {
+    let Some(lock) = FlagLock::acquire(&CHECKING_DEPOSITS) else {
+        return;
+    };
+
+    let now = get_current_time_in_milli();
+    let watches: Vec<(Principal, DepositWatch)> = DEPOSIT_WATCHES.with_borrow_mut(|watches| {
+        let expired: Vec<Principal> = watches
+            .iter()
+            .filter(|entry| now.saturating_sub(entry.value().created_at) > DEPOSIT_WATCH_TTL)
+            .map(|entry| *entry.key())
+            .collect();
+        for payer in expired {
+            watches.remove(&payer);
+        }
+        watches.iter().map(|entry| (*entry.key(), entry.value())).collect()
+    });
+    if watches.is_empty() {
+        return;
+    }
+
+    ic_cdk::futures::spawn(async move {
+        let _lock = lock;
+        for (payer, watch) in watches {
+            // an earlier payment may still move these funds, let reconcile_payments finish it first
+            if has_open_payment(payer) {
+                continue;
+            }
+            check_deposit(payer, watch).await;
+        }
+    });
+}
+
+async fn check_deposit(payer: Principal, mut watch: DepositWatch) {
+    let deposit_account = Account {
+        owner: canister_self(),
+        subaccount: Some(principal_to_subaccount(payer)),
+    };
+    watch.last_checked_at = Some(get_current_time_in_milli());
+    watch.last_error = None;
+
+    let accepted_tokens: Vec<TokenType> = get_pricing()
+        .tokens
+        .into_iter()
+        .filter(|token| token.enabled)
+        .map(|token| token.token_type)
+        .collect();
+    for token_type in accepted_tokens {
+        let tier = watch.tier.clone().unwrap_or(EntitlementTier::Pro);
+        let quote = match quote_premium(payer, &tier, &token_type, &watch.payment_period, watch.promo_code.as_deref()) {
+            Ok(quote) => quote,
+            Err(e) => {
+                watch.last_error = Some(e);
+                break;
+            }
+        };
+        let ledger_canister_id = get_ledger_canister_id(&token_type);
+        let balance = match check_user_balance(&token_type, deposit_account).await {
+            Ok(balance) => balance,
+            Err(e) => {
+                watch.last_error = Some(e);
+                continue;
+            }
+        };
+        // the transfer out of the subaccount pays the ledger fee on top of the price
+        let fee = match get_ledger_fee(ledger_canister_id).await {
+            Ok(fee) => fee,
+            Err(e) => {
+                watch.last_error = Some(e);
+                continue;
+            }
+        };
+        if balance < quote.amount.saturating_add(fee) {
+            continue;
+        }
+        // a workspace fee or another payment may have been opened while we waited on the ledgers
+        if has_open_payment(payer) {
+            break;
+        }
+
+        let payment = match open_premium_payment(payer, PaymentKind::Deposit, deposit_account, &quote) {
+            Ok(payment) => payment,
+            Err(e) => {
+                watch.last_error = Some(e);
+                break;
+            }
+        };
+        match settle_payment(payment.id).await {
+            Ok(payment) if payment.state == PaymentState::Granted => {
+                DEPOSIT_WATCHES.with_borrow_mut(|watches| watches.remove(&payer));
+                return;
+            }
+            Ok(payment) => watch.last_error = payment.last_error,
+            Err(e) => watch.last_error = Some(e),
+        }
+        break;
+    }
+
+    // the user may have cancelled the watch while we were waiting on the ledgers
+    DEPOSIT_WATCHES.with_borrow_mut(|watches| {
+        if watches.contains_key(&payer) {
+            watches.insert(payer, watch);
         }
-    })
+    });
 }
 
-// Premium payment constants
-const MONTHLY_PAYMENT_AMOUNT: u64 = 6_000_000; // 6 USDC/USDT (6 decimal places)
-const YEARLY_PAYMENT_AMOUNT: u64 = 60_000_000; // 60 USDC/USDT (6 decimal places)
+#[ic_cdk::query(guard = "is_controller")]
+fn list_unconfirmed_payments(start_after: Option<u64>, limit: u32) -> Vec<PaymentRecord> {
+    let start = start_after.map(Bound::Excluded).unwrap_or(Bound::Unbounded);
+    PAYMENTS.with_borrow(|payments| {
+        payments
+            .range((start, Bound::Unbounded))
+            .map(|entry| entry.value())
+            .filter(|payment| payment.state == PaymentState::Unconfirmed)
+            .take(limit as usize)
+            .collect()
+    })
+}
 
-// Promo pricing constants (50% discount)
-const MONTHLY_PROMO_AMOUNT: u64 = 3_990_000; // 4 USDC/USDT (6 decimal places)
-const YEARLY_PROMO_AMOUNT: u64 = 55_000_000; // 55 USDC/USDT (6 decimal places)
+/// Settles a payment the ledger could no longer confirm. Pass the ledger block of the transfer
+/// to complete it, or None if it never reached the ledger to fail it.
+#[ic_cdk::update(guard = "is_controller")]
+async fn resolve_unconfirmed_payment(payment_id: u64, block_index: Option<String>) -> Result<PaymentRecord, String> {
+    {
+        let Some(_lock) = HeapLock::acquire(&SETTLING_PAYMENTS, payment_id) else {
+            return Err("Payment is already being processed".to_string());
+        };
+        let mut payment = PAYMENTS
+            .with_borrow(|payments| payments.get(&payment_id))
+            .ok_or("Payment not found".to_string())?;
+        if payment.state != PaymentState::Unconfirmed {
+            return Err("Payment is not waiting to be confirmed".to_string());
+        }
 
-// System account for receiving payments
-fn get_system_account() -> Account {
-    // This should be the account where payments are received
-    // For now, using a placeholder - you'll need to set this to your actual system account
-    Account {
-        owner: canister_self(),
-        subaccount: None,
+        payment.updated_at = get_current_time_in_milli();
+        match block_index {
+            Some(block_index) => {
+                payment.block_index = Some(block_index);
+                payment.last_error = None;
+                payment.state = if is_outgoing_payment(&payment.kind) {
+                    payment.settled_at = Some(payment.updated_at);
+                    PaymentState::Completed
+                } else {
+                    PaymentState::Transferred
+                };
+            }
+            None => {
+                let error = payment.last_error.clone().unwrap_or("Transfer not found on the ledger".to_string());
+                payment.state = PaymentState::Failed(error);
+            }
+        }
+        save_payment(&payment);
     }
+
+    // grants what a confirmed purchase bought
+    settle_payment(payment_id).await
 }
 
-// Get the appropriate ledger canister ID based on token type
-fn get_ledger_canister_id(token_type: &TokenType) -> Principal {
-    match token_type {
-        TokenType::CKUSDC => {
-            // CKUSDC ledger canister ID
-            Principal::from_text("xevnm-gaaaa-aaaar-qafnq-cai").unwrap()
-        },
-        TokenType::CKUSDT => {
-            // CKUSDT ledger canister ID  
-            Principal::from_text("cngnf-vqaaa-aaaar-qag4q-cai").unwrap()
-        }
+// Timer: finish or refund payments left open by a trap or a failed ledger call
+fn reconcile_payments() {
+    let open: Vec<u64> = OPEN_PAYMENTS.with_borrow(|open| open.iter().collect());
+    if open.is_empty() {
+        return;
     }
+
+    ic_cdk::futures::spawn(async move {
+        for payment_id in open {
+            if let Err(e) = settle_payment(payment_id).await {
+                ic_cdk::api::debug_print(&format!("Failed to settle payment {}: {}", payment_id, e));
+            }
+        }
+    });
 }
 
-// Get payment amount based on period and promo status
-fn get_payment_amount(payment_period: &PaymentPeriod, is_promo: bool) -> u64 {
-    match (payment_period, is_promo) {
-        (PaymentPeriod::Monthly, false) => MONTHLY_PAYMENT_AMOUNT,
-        (PaymentPeriod::Monthly, true) => MONTHLY_PROMO_AMOUNT,
-        (PaymentPeriod::Yearly, false) => YEARLY_PAYMENT_AMOUNT,
-        (PaymentPeriod::Yearly, true) => YEARLY_PROMO_AMOUNT,
-    }
+#[ic_cdk::query]
+fn get_pricing_config() -> PricingConfig {
+    get_pricing()
 }
 
-// Check user's balance for a specific token
-async fn check_user_balance(token_type: &TokenType, user_account: Account) -> Result<u64, String> {
-    let ledger_canister_id = get_ledger_canister_id(token_type);
-    
-    let response: Nat = 
-        ic_cdk::call::Call::unbounded_wait(ledger_canister_id, "icrc1_balance_of").with_arg(user_account).await.expect("Failed to get balance").candid::<Nat>().expect("Failed to decode balance");
-    
-    Ok(response.0.try_into().unwrap())
+fn validate_token_config(token: &TokenConfig) -> Result<(), String> {
+    if token.symbol.trim().is_empty() {
+        return Err("Token symbol cannot be empty".to_string());
+    }
+    if token.decimals > 18 {
+        return Err("Token decimals must be at most 18".to_string());
+    }
+    Ok(())
 }
 
-#[ic_cdk::update(guard = "is_authenticated")]
-async fn notify_deposit_premium_payment(
-    request: PremiumPaymentRequest
-) -> PremiumPaymentResponse {
-    let caller = ic_cdk::api::msg_caller();
-    
-    // Check if user is already premium
-    if PREMIUM_USERS_SET.with_borrow(|set| set.contains(&caller)) {
-        return PremiumPaymentResponse {
-            success: false,
-            message: "User is already a premium member".to_string(),
-            transaction_id: None,
-        };
+fn validate_plan_price(plan: &PlanPrice) -> Result<(), String> {
+    if plan.plan_tier() == EntitlementTier::Free {
+        return Err("The Free tier cannot be priced".to_string());
+    }
+    if plan.amount == 0 {
+        return Err("Plan price must be greater than zero".to_string());
     }
+    if plan.promo_amount.is_some_and(|promo_amount| promo_amount == 0 || promo_amount > plan.amount) {
+        return Err("Promo price must be between zero and the plan price".to_string());
+    }
+    Ok(())
+}
 
-    let payment_amount = get_payment_amount(&request.payment_period, false); // Default to regular pricing
-    
-    // Get user's account identifier
-    let user_account = Account {
-        owner: canister_self(),
-        subaccount: Some(principal_to_subaccount(caller)),
-    };
-    
-    // Check user's balance
-    let balance_result = check_user_balance(&request.token_type, user_account.clone()).await;
-    match balance_result {
-        Ok(balance) => {
-            if balance < payment_amount {
-                return PremiumPaymentResponse {
-                    success: false,
-                    message: format!("Insufficient balance. Required: {}, Available: {}", 
-                                   payment_amount, balance),
-                    transaction_id: None,
-                };
-            }
-        },
-        Err(error) => {
-            return PremiumPaymentResponse {
-                success: false,
-                message: format!("Failed to check balance: {}", error),
-                transaction_id: None,
-            };
+fn validate_pricing_config(config: &PricingConfig) -> Result<(), String> {
+    for (i, token) in config.tokens.iter().enumerate() {
+        validate_token_config(token)?;
+        if config.tokens[..i].iter().any(|other| other.token_type == token.token_type) {
+            return Err(format!("{:?} is configured more than once", token.token_type));
         }
     }
-    
-    let ledger_canister_id = get_ledger_canister_id(&request.token_type);
-    let system_account = get_system_account();
-
-    // Create transfer arguments
-    let transfer_args = TransferArg {
-        from_subaccount: Some(principal_to_subaccount(caller)),
-        to: system_account,
-        amount: Nat::from(payment_amount),
-        fee: None, // Standard fee
-        memo: None,
-        created_at_time: None,
-    };
+    for (i, plan) in config.plans.iter().enumerate() {
+        validate_plan_price(plan)?;
+        if config.plans[..i].iter().any(|other| other.plan_tier() == plan.plan_tier() && other.period == plan.period) {
+            return Err(format!("{:?} {:?} is priced more than once", plan.plan_tier(), plan.period));
+        }
+    }
+    if config.workspace_fee == Some(0) {
+        return Err("Workspace fee must be greater than zero".to_string());
+    }
+    Ok(())
+}
 
-    // Perform the transfer
-    let transfer_result: Result<Nat, TransferError> = 
-        ic_cdk::call::Call::unbounded_wait(ledger_canister_id, "icrc1_transfer").with_arg(transfer_args).await.expect("Transfer failed").candid::<Result<Nat, TransferError>>().expect("Failed to decode transfer result");
+#[ic_cdk::update(guard = "is_controller")]
+fn set_token_config(token: TokenConfig) -> Result<(), String> {
+    validate_token_config(&token)?;
+    PRICING_CONFIG.with_borrow_mut(|cell| {
+        let mut config = cell.get().clone();
+        match config.tokens.iter_mut().find(|existing| existing.token_type == token.token_type) {
+            Some(existing) => *existing = token,
+            None => config.tokens.push(token),
+        }
+        cell.set(config);
+    });
+    Ok(())
+}
 
-    match transfer_result {
-        Ok(block_index) => {
-            let expiration_time = get_current_time_in_milli() + request.payment_period.to_millis();
-            PREMIUM_EXPIRATION_HEAP.with_borrow_mut(|heap| {
-                heap.push(Reverse(expiration_time));
-            });
-            EXPIRATION_MAP.with_borrow_mut(|map| {
-                map.insert(expiration_time, caller);
-            });
-                        // Transfer successful, add user to premium
-            PREMIUM_USERS_SET.with_borrow_mut(|set| {
-                set.insert(caller);
-            });
-            
-            // Update user profile to mark as premium
-            USER_PROFILES.with_borrow_mut(|profiles| {
-                if let Some(mut profile) = profiles.get(&caller) {
-                    profile.premium = true;
-                    profile.updated_at = get_current_time_in_milli();
-                    profiles.insert(caller, profile);
-                }
-            });
-            
-           
-            
-            // Authorize user in asset storage canister
-            if let Err(e) = authorize_user_in_asset_storage(caller).await {
-                ic_cdk::api::debug_print(&format!("Failed to authorize user {} in asset storage: {}", caller, e));
-            }
+#[ic_cdk::update(guard = "is_controller")]
+fn set_plan_price(plan: PlanPrice) -> Result<(), String> {
+    validate_plan_price(&plan)?;
+    PRICING_CONFIG.with_borrow_mut(|cell| {
+        let mut config = cell.get().clone();
+        match config
+            .plans
+            .iter_mut()
+            .find(|existing| existing.plan_tier() == plan.plan_tier() && existing.period == plan.period)
+        {
+            Some(existing) => *existing = plan,
+            None => config.plans.push(plan),
+        }
+        cell.set(config);
+    });
+    Ok(())
+}
 
-            PremiumPaymentResponse {
-                success: true,
-                message: format!("Premium payment successful for {:?} {:?}", request.payment_period, request.token_type),
-                transaction_id: Some(block_index.to_string()),
-            }
-        },
-        Err(transfer_error) => {
-            PremiumPaymentResponse {
-                success: false,
-                message: format!("Transfer error: {:?}", transfer_error),
-                transaction_id: None,
-            }
-        },
-        
-    }
+/// Set the workspace deployment fee, in millionths of a dollar. Deployments already under way keep their fee.
+#[ic_cdk::update(guard = "is_controller")]
+fn set_workspace_fee(amount: u64) -> Result<(), String> {
+    PRICING_CONFIG.with_borrow_mut(|cell| {
+        let mut config = cell.get().clone();
+        config.workspace_fee = Some(amount);
+        validate_pricing_config(&config)?;
+        cell.set(config);
+        Ok(())
+    })
 }
 
-#[ic_cdk::update(guard = "is_authenticated")]
-async fn notify_payment_approval(
-    principal_string: String,
-    payment_period: PaymentPeriod
-) -> PremiumPaymentResponse {
+/// Price of a premium plan for the caller, with the promo code applied if one is given.
+#[ic_cdk::query(guard = "is_authenticated")]
+fn quote_premium_payment(
+    token_type: TokenType,
+    payment_period: PaymentPeriod,
+    promo_code: Option<String>,
+    tier: Option<EntitlementTier>,
+) -> Result<PriceQuote, String> {
     let caller = ic_cdk::api::msg_caller();
-    
-    // Convert string to principal
-    let user_principal = match Principal::from_text(&principal_string) {
-        Ok(principal) => principal,
-        Err(_) => {
-            return PremiumPaymentResponse {
-                success: false,
-                message: "Invalid principal string".to_string(),
-                transaction_id: None,
-            };
+    let tier = tier.unwrap_or(EntitlementTier::Pro);
+    quote_premium(caller, &tier, &token_type, &payment_period, promo_code.as_deref())
+}
+
+/// Create or update a promo code. Redemption counts survive updates.
+#[ic_cdk::update(guard = "is_controller")]
+fn set_promo_code(mut config: PromoCodeConfig) -> Result<PromoCode, String> {
+    config.code = normalize_promo_code(&config.code)?;
+    match config.discount {
+        PromoDiscount::Percentage(percent) if percent == 0 || percent >= 100 => {
+            return Err("Percentage discounts must be between 1 and 99".to_string());
         }
-    };
-    
-    // Check if user is already premium
-    if PREMIUM_USERS_SET.with_borrow(|set| set.contains(&user_principal)) {
-        return PremiumPaymentResponse {
-            success: false,
-            message: "User is already a premium member".to_string(),
-            transaction_id: None,
-        };
+        PromoDiscount::Fixed(0) => return Err("Fixed discounts must be greater than zero".to_string()),
+        _ => {}
     }
-    
-    let payment_amount = get_payment_amount(&payment_period, true); // promo pricing
-    
-    // Get user's account identifier
-    let user_account = Account {
-        owner: user_principal,
-        subaccount: None,
-    };
-    
-    // Check user's balance for USDC
-    let balance_result = check_user_balance(&TokenType::CKUSDC, user_account.clone()).await;
-    match balance_result {
-        Ok(balance) => {
-            if balance < payment_amount {
-                return PremiumPaymentResponse {
-                    success: false,
-                    message: format!("Insufficient USDC balance. Required: {}, Available: {}", 
-                                   payment_amount, balance),
-                    transaction_id: None,
-                };
-            }
-        },
-        Err(error) => {
-            return PremiumPaymentResponse {
-                success: false,
-                message: format!("Failed to check USDC balance: {}", error),
-                transaction_id: None,
-            };
+    if let (Some(starts_at), Some(ends_at)) = (config.starts_at, config.ends_at) {
+        if ends_at <= starts_at {
+            return Err("Promo code must end after it starts".to_string());
         }
     }
-    
-    let ledger_canister_id = get_ledger_canister_id(&TokenType::CKUSDC);
-    let system_account = get_system_account();
-
-    let transfer_args = TransferFromArgs {
-        spender_subaccount: None,
-        from: user_account,
-        to: system_account,
-        amount: Nat::from(payment_amount),
-        fee: None,
-        memo: None,
-        created_at_time: None,
-    };
-    
-    
-    // Perform the transfer
-    let transfer_result: Result<Nat, TransferFromError> = 
-        ic_cdk::call::Call::unbounded_wait(ledger_canister_id, "icrc2_transfer_from")
-            .with_arg(transfer_args)
-            .await
-            .expect("Transfer failed")
-            .candid::<Result<Nat, TransferFromError>>()
-            .expect("Failed to decode transfer result");
-    
-    match transfer_result {
-        Ok(block_index) => {
-            let expiration_time = get_current_time_in_milli() + payment_period.to_millis();
-            PREMIUM_EXPIRATION_HEAP.with_borrow_mut(|heap| {
-                heap.push(Reverse(expiration_time));
-            });
-            EXPIRATION_MAP.with_borrow_mut(|map| {
-                map.insert(expiration_time, caller);
-            });
-            // Transfer successful, add user to premium
-            PREMIUM_USERS_SET.with_borrow_mut(|set| {
-                set.insert(caller);
-            });
-            
-            // Update user profile to mark as premium
-            USER_PROFILES.with_borrow_mut(|profiles| {
-                if let Some(mut profile) = profiles.get(&caller) {
-                    profile.premium = true;
-                    profile.updated_at = get_current_time_in_milli();
-                    profiles.insert(user_principal, profile);
-                }
-            });
+    if config.max_redemptions == Some(0) || config.max_redemptions_per_principal == Some(0) {
+        return Err("Redemption limits must be greater than zero".to_string());
+    }
 
-            if let Err(e) = authorize_user_in_asset_storage(caller).await {
-                ic_cdk::api::debug_print(&format!("Failed to authorize user {} in asset storage: {}", caller, e));
-            }
-            
-            PremiumPaymentResponse {
-                success: true,
-                message: format!("Premium payment approval successful for {:?} period", payment_period),
-                transaction_id: Some(block_index.to_string()),
-            }
-        },
-        Err(transfer_error) => {
-            PremiumPaymentResponse {
-                success: false,
-                message: format!("Transfer error: {:?}", transfer_error),
-                transaction_id: None,
+    let now = get_current_time_in_milli();
+    let promo = PROMO_CODES.with_borrow_mut(|codes| {
+        let promo = match codes.get(&config.code) {
+            Some(existing) => PromoCode {
+                config,
+                updated_at: now,
+                ..existing
+            },
+            None => PromoCode {
+                config,
+                redemptions: 0,
+                created_at: now,
+                updated_at: now,
+            },
+        };
+        codes.insert(promo.config.code.clone(), promo.clone());
+        promo
+    });
+    Ok(promo)
+}
+
+#[ic_cdk::query(guard = "is_controller")]
+fn list_promo_codes() -> Vec<PromoCode> {
+    PROMO_CODES.with_borrow(|codes| codes.iter().map(|entry| entry.value()).collect())
+}
+
+/// Redemptions of a promo code, newest first, with per-token totals paid through it.
+#[ic_cdk::query(guard = "is_controller")]
+fn get_promo_redemptions(code: String, offset: u32, limit: u32) -> Result<PromoRedemptionReport, String> {
+    let code = normalize_promo_code(&code)?;
+    let mut redemptions: Vec<PromoRedemption> = PROMO_REDEMPTIONS.with_borrow(|redemptions| {
+        redemptions
+            .range(PromoRedemptionKey { code: code.clone(), payment_id: 0 }..)
+            .take_while(|entry| entry.key().code == code)
+            .map(|entry| entry.value())
+            .collect()
+    });
+    redemptions.reverse();
+
+    let mut totals: Vec<RevenueTotal> = Vec::new();
+    for redemption in redemptions.iter() {
+        match totals.iter_mut().find(|total| total.token_type == redemption.token_type) {
+            Some(total) => {
+                total.amount += redemption.amount;
+                total.payments += 1;
             }
-        },
+            None => totals.push(RevenueTotal {
+                token_type: redemption.token_type.clone(),
+                amount: redemption.amount,
+                payments: 1,
+            }),
+        }
     }
+
+    Ok(PromoRedemptionReport {
+        total: redemptions.len() as u64,
+        totals,
+        redemptions: redemptions
+            .into_iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAYMENTS_PAGE_SIZE) as usize)
+            .collect(),
+    })
 }
 
 #[ic_cdk::query(guard = "is_authenticated")]
 fn get_premium_payment_info() -> Result<HashMap<String, u64>, String> {
     let mut payment_info = HashMap::new();
-    payment_info.insert("monthly_amount".to_string(), MONTHLY_PAYMENT_AMOUNT);
-    payment_info.insert("yearly_amount".to_string(), YEARLY_PAYMENT_AMOUNT);
+    for plan in get_pricing().plans {
+        // Pro keeps the unprefixed keys the premium page already reads
+        let name = match plan.plan_tier() {
+            EntitlementTier::Pro => format!("{:?}", plan.period).to_lowercase(),
+            tier => format!("{:?}_{:?}", tier, plan.period).to_lowercase(),
+        };
+        payment_info.insert(format!("{}_amount", name), plan.amount);
+        if let Some(promo_amount) = plan.promo_amount {
+            payment_info.insert(format!("{}_promo_amount", name), promo_amount);
+        }
+    }
     Ok(payment_info)
 }
 
@@ -1243,34 +6295,15 @@ async fn make_user_premium(principal_string: String, expiration_time: u64) -> Re
         return Err("User is already a premium member".to_string());
     }
     
-    // Add to premium expiration tracking
-    PREMIUM_EXPIRATION_HEAP.with_borrow_mut(|heap| {
-        heap.push(Reverse(expiration_time));
-    });
-    
-    EXPIRATION_MAP.with_borrow_mut(|map| {
-        map.insert(expiration_time, user_principal);
-    });
-    
-    // Add user to premium set
-    PREMIUM_USERS_SET.with_borrow_mut(|set| {
-        set.insert(user_principal);
-    });
-    
-    // Update user profile to mark as premium
+    // Create a default profile if none exists
     USER_PROFILES.with_borrow_mut(|profiles| {
-        if let Some(mut profile) = profiles.get(&user_principal) {
-            profile.premium = true;
-            profile.updated_at = get_current_time_in_milli();
-            profiles.insert(user_principal, profile);
-        } else {
-            let mut profile = UserProfile::anonymous();
-            profile.premium = true;
-            profile.updated_at = get_current_time_in_milli();
-            profiles.insert(user_principal, profile);
-            // Create a default profile if none exists
+        if !profiles.contains_key(&user_principal) {
+            profiles.insert(user_principal, UserProfile::anonymous());
         }
     });
+
+    let duration = expiration_time.saturating_sub(get_current_time_in_milli());
+    grant_premium_entitlement(user_principal, EntitlementTier::Pro, duration, EntitlementSource::Manual);
     
     
     
@@ -1279,3 +6312,250 @@ async fn make_user_premium(principal_string: String, expiration_time: u64) -> Re
 
 // Export candid interface
 ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    fn pending_payment(kind: PaymentKind) -> PaymentRecord {
+        PaymentRecord {
+            id: 1,
+            payer: principal(1),
+            kind,
+            token_type: TokenType::CKUSDC,
+            ledger: get_ledger_canister_id(&TokenType::CKUSDC),
+            from: Account { owner: principal(1), subaccount: None },
+            amount: 6_000_000,
+            fee: Some(10_000),
+            period: Some(PaymentPeriod::Monthly),
+            created_at_time: NOW * 1_000_000,
+            refund_created_at_time: None,
+            state: PaymentState::Pending,
+            block_index: None,
+            refund_block_index: None,
+            attempts: 1,
+            last_error: None,
+            created_at: NOW,
+            updated_at: NOW,
+            settled_at: None,
+            tier: Some(EntitlementTier::Pro),
+            purchase: None,
+            promo_code: None,
+            discount: None,
+            invoice_path: None,
+        }
+    }
+
+    fn monthly_plan() -> PlanPrice {
+        PlanPrice {
+            tier: Some(EntitlementTier::Pro),
+            period: PaymentPeriod::Monthly,
+            amount: 6_000_000,
+            promo_amount: Some(3_990_000),
+        }
+    }
+
+    fn add_promo_code(code: &str, discount: PromoDiscount) {
+        PROMO_CODES.with_borrow_mut(|codes| {
+            codes.insert(code.to_string(), PromoCode {
+                config: PromoCodeConfig {
+                    code: code.to_string(),
+                    discount,
+                    plans: Vec::new(),
+                    starts_at: None,
+                    ends_at: Some(NOW + 1),
+                    max_redemptions: Some(2),
+                    max_redemptions_per_principal: Some(1),
+                    enabled: true,
+                },
+                redemptions: 0,
+                created_at: NOW,
+                updated_at: NOW,
+            })
+        });
+    }
+
+    #[test]
+    fn transferred_payments_are_granted() {
+        let mut payment = pending_payment(PaymentKind::Deposit);
+        record_transfer_outcome(&mut payment, Ok(Ok("42".to_string())), NOW + 1_000);
+        assert_eq!(payment.state, PaymentState::Transferred);
+        assert_eq!(payment.block_index.as_deref(), Some("42"));
+
+        record_grant(&mut payment, NOW + 2_000);
+        assert_eq!(payment.state, PaymentState::Granted);
+        assert_eq!(payment.settled_at, Some(NOW + 2_000));
+    }
+
+    #[test]
+    fn duplicate_transfers_count_as_transferred() {
+        let outcome = transfer_outcome(Err(TransferError::Duplicate { duplicate_of: Nat::from(7u64) }));
+        assert!(matches!(&outcome, Ok(block_index) if block_index == "7"));
+        let outcome = transfer_from_outcome(Err(TransferFromError::Duplicate { duplicate_of: Nat::from(8u64) }));
+        assert!(matches!(&outcome, Ok(block_index) if block_index == "8"));
+
+        let mut payment = pending_payment(PaymentKind::Approval);
+        payment.attempts = 2;
+        payment.last_error = Some("Failed to call ledger".to_string());
+        record_transfer_outcome(&mut payment, Ok(outcome), NOW + 1_000);
+        assert_eq!(payment.state, PaymentState::Transferred);
+        assert_eq!(payment.last_error, None);
+    }
+
+    #[test]
+    fn late_transfers_are_refunded() {
+        let mut payment = pending_payment(PaymentKind::Deposit);
+        record_transfer_outcome(&mut payment, Ok(Ok("42".to_string())), NOW + PAYMENT_GRANT_WINDOW + 1);
+        assert_eq!(payment.state, PaymentState::RefundPending);
+
+        let mut payment = pending_payment(PaymentKind::Deposit);
+        record_transfer_outcome(&mut payment, Ok(Ok("42".to_string())), NOW + PAYMENT_GRANT_WINDOW);
+        assert_eq!(payment.state, PaymentState::Transferred);
+    }
+
+    #[test]
+    fn expired_retries_wait_for_a_controller() {
+        let mut payment = pending_payment(PaymentKind::Deposit);
+        record_transfer_outcome(&mut payment, Err("Failed to call ledger".to_string()), NOW + 1_000);
+        assert_eq!(payment.state, PaymentState::Pending);
+
+        payment.attempts += 1;
+        record_transfer_outcome(&mut payment, Ok(transfer_outcome(Err(TransferError::TooOld))), NOW + 2_000);
+        assert_eq!(payment.state, PaymentState::Unconfirmed);
+
+        let mut payment = pending_payment(PaymentKind::Deposit);
+        record_transfer_outcome(&mut payment, Ok(transfer_outcome(Err(TransferError::TooOld))), NOW + 1_000);
+        assert!(matches!(payment.state, PaymentState::Failed(_)));
+    }
+
+    #[test]
+    fn failed_payments_release_their_promo_redemption() {
+        add_promo_code("LAUNCH", PromoDiscount::Percentage(50));
+        let mut payment = pending_payment(PaymentKind::Deposit);
+        payment.promo_code = Some("LAUNCH".to_string());
+        PROMO_REDEMPTIONS.with_borrow_mut(|redemptions| {
+            redemptions.insert(PromoRedemptionKey { code: "LAUNCH".to_string(), payment_id: payment.id }, PromoRedemption {
+                code: "LAUNCH".to_string(),
+                payment_id: payment.id,
+                payer: payment.payer,
+                period: PaymentPeriod::Monthly,
+                token_type: TokenType::CKUSDC,
+                price: 6_000_000,
+                discount: 3_000_000,
+                amount: 3_000_000,
+                redeemed_at: NOW,
+            })
+        });
+        PROMO_CODES.with_borrow_mut(|codes| {
+            let mut promo = codes.get(&"LAUNCH".to_string()).unwrap();
+            promo.redemptions = 1;
+            codes.insert("LAUNCH".to_string(), promo);
+        });
+        PROMO_USES.with_borrow_mut(|uses| uses.insert(PromoUseKey { code: "LAUNCH".to_string(), principal: payment.payer }, 1));
+
+        record_transfer_outcome(&mut payment, Ok(transfer_outcome(Err(TransferError::InsufficientFunds { balance: Nat::from(0u64) }))), NOW + 1_000);
+        assert!(matches!(payment.state, PaymentState::Failed(_)));
+        save_payment(&payment);
+
+        assert_eq!(PROMO_CODES.with_borrow(|codes| codes.get(&"LAUNCH".to_string()).unwrap().redemptions), 0);
+        assert_eq!(promo_uses("LAUNCH", payment.payer), 0);
+        assert_eq!(apply_promo_code("launch", payment.payer, &monthly_plan(), NOW), Ok(("LAUNCH".to_string(), 3_000_000)));
+    }
+
+    #[test]
+    fn failed_earnings_withdrawals_are_credited_back() {
+        let mut payment = pending_payment(PaymentKind::Earnings { to: Account { owner: principal(9), subaccount: None } });
+        post_ledger_entry(payment.payer, &payment.token_type, LedgerEntryKind::Tip { note_id: "note_1".to_string(), payment_id: 0 }, 6_010_000, 0, NOW).unwrap();
+        post_ledger_entry(payment.payer, &payment.token_type, LedgerEntryKind::Withdrawal { payment_id: payment.id }, 0, 6_010_000, NOW).unwrap();
+        assert_eq!(ledger_balance(payment.payer, &payment.token_type), 0);
+
+        record_transfer_outcome(&mut payment, Ok(transfer_outcome(Err(TransferError::TemporarilyUnavailable))), NOW + 1_000);
+        save_payment(&payment);
+        assert_eq!(ledger_balance(payment.payer, &payment.token_type), 6_010_000);
+    }
+
+    #[test]
+    fn ledger_entries_cannot_overdraw() {
+        let owner = principal(3);
+        post_ledger_entry(owner, &TokenType::CKUSDC, LedgerEntryKind::PlatformFee { payment_id: 1 }, 100, 0, NOW).unwrap();
+        assert_eq!(
+            post_ledger_entry(owner, &TokenType::CKUSDC, LedgerEntryKind::Withdrawal { payment_id: 2 }, 0, 101, NOW).map(|entry| entry.balance),
+            Err("Insufficient balance".to_string())
+        );
+        assert_eq!(ledger_balance(owner, &TokenType::CKUSDC), 100);
+        let entry = post_ledger_entry(owner, &TokenType::CKUSDC, LedgerEntryKind::Withdrawal { payment_id: 3 }, 0, 100, NOW).unwrap();
+        assert_eq!(entry.balance, 0);
+    }
+
+    #[test]
+    fn promo_codes_discount_the_plan_price() {
+        let payer = principal(4);
+        add_promo_code("HALF", PromoDiscount::Percentage(50));
+        add_promo_code("MINUS2", PromoDiscount::Fixed(2_000_000));
+        add_promo_code("PLANPROMO", PromoDiscount::PlanPromo);
+        add_promo_code("FREE", PromoDiscount::Fixed(6_000_000));
+
+        assert_eq!(apply_promo_code(" half ", payer, &monthly_plan(), NOW), Ok(("HALF".to_string(), 3_000_000)));
+        assert_eq!(apply_promo_code("minus2", payer, &monthly_plan(), NOW), Ok(("MINUS2".to_string(), 4_000_000)));
+        assert_eq!(apply_promo_code("PlanPromo", payer, &monthly_plan(), NOW), Ok(("PLANPROMO".to_string(), 3_990_000)));
+        assert!(apply_promo_code("FREE", payer, &monthly_plan(), NOW).is_err());
+        assert!(apply_promo_code("HALF", payer, &monthly_plan(), NOW + 2).is_err());
+        assert!(apply_promo_code("MISSING", payer, &monthly_plan(), NOW).is_err());
+
+        let quote = quote_plan(payer, &monthly_plan(), &TokenType::CKUSDC, Some("HALF"), NOW).unwrap();
+        assert_eq!((quote.price, quote.discount, quote.amount), (6_000_000, 3_000_000, 3_000_000));
+        assert_eq!(quote.promo_code.as_deref(), Some("HALF"));
+        let quote = quote_plan(payer, &monthly_plan(), &TokenType::CKUSDT, None, NOW).unwrap();
+        assert_eq!((quote.price, quote.discount, quote.amount), (6_000_000, 0, 6_000_000));
+    }
+
+    #[test]
+    fn promo_codes_only_apply_to_their_plans() {
+        let payer = principal(4);
+        add_promo_code("TEAMONLY", PromoDiscount::Percentage(10));
+        PROMO_CODES.with_borrow_mut(|codes| {
+            let mut promo = codes.get(&"TEAMONLY".to_string()).unwrap();
+            promo.config.plans = vec![crate::types::PromoPlan { tier: Some(EntitlementTier::Team), period: PaymentPeriod::Monthly }];
+            codes.insert("TEAMONLY".to_string(), promo);
+        });
+        let team_monthly = PlanPrice { tier: Some(EntitlementTier::Team), ..monthly_plan() };
+        let team_yearly = PlanPrice { period: PaymentPeriod::Yearly, ..team_monthly.clone() };
+
+        assert_eq!(apply_promo_code("TEAMONLY", payer, &team_monthly, NOW), Ok(("TEAMONLY".to_string(), 5_400_000)));
+        assert!(apply_promo_code("TEAMONLY", payer, &monthly_plan(), NOW).is_err());
+        assert!(apply_promo_code("TEAMONLY", payer, &team_yearly, NOW).is_err());
+    }
+
+    #[test]
+    fn plan_prices_scale_to_token_decimals() {
+        assert_eq!(price_to_token_units(6_000_000, &TokenType::CKUSDC), 6_000_000);
+
+        let mut pricing = get_pricing();
+        for token in pricing.tokens.iter_mut() {
+            token.decimals = if token.token_type == TokenType::CKUSDC { 8 } else { 2 };
+        }
+        PRICING_CONFIG.with_borrow_mut(|config| config.set(pricing));
+        assert_eq!(price_to_token_units(6_000_000, &TokenType::CKUSDC), 600_000_000);
+        assert_eq!(price_to_token_units(3_990_000, &TokenType::CKUSDT), 399);
+        assert_eq!(price_to_token_units(u64::MAX, &TokenType::CKUSDC), u64::MAX);
+    }
+
+    #[test]
+    fn approval_payments_cannot_spend_another_principals_allowance() {
+        let wallet = Principal::from_slice(&[1; 29]);
+        let third_party = Principal::from_slice(&[2; 29]);
+
+        assert!(approval_payer_account(third_party, &wallet.to_text()).is_err());
+        assert!(ensure_own_account(third_party, &Account { owner: wallet, subaccount: Some([7; 32]) }).is_err());
+        assert_eq!(
+            approval_payer_account(wallet, &wallet.to_text()),
+            Ok(Account { owner: wallet, subaccount: None })
+        );
+    }
+}
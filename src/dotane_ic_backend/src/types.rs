@@ -13,12 +13,12 @@
 // limitations under the License.
 
 use candid::{CandidType, Decode, Encode, Principal};
-use dotane_types::Note;
+use dotane_types::{note_context::Site, Note};
 use ic_stable_structures::Storable;
+use icrc_ledger_types::icrc1::account::Account;
 use serde::{Deserialize, Serialize};
 use std::{borrow::Cow, collections::{HashMap, HashSet}};
 
-
 #[derive(CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct UserCanister {
     // canister_id -> associated_domain
@@ -113,7 +113,7 @@ pub struct UpdateUserProfileRequest {
     pub marked_public: Option<bool>,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct Workspace {
     pub canister_id: String,
     pub domain: Option<String>,
@@ -126,13 +126,13 @@ pub struct SessionData {
     pub expires_at: u64
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub enum TokenType {
     CKUSDC,
     CKUSDT,
 }
 
-#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 pub enum PaymentPeriod {
     Monthly,
     Yearly,
@@ -151,6 +151,9 @@ impl PaymentPeriod {
 pub struct PremiumPaymentRequest {
     pub token_type: TokenType,
     pub payment_period: PaymentPeriod,
+    pub promo_code: Option<String>,
+    // defaults to Pro
+    pub tier: Option<EntitlementTier>,
 }
 
 #[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
@@ -159,3 +162,1673 @@ pub struct PremiumPaymentResponse {
     pub message: String,
     pub transaction_id: Option<String>,
 }
+
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum WorkspaceDeploymentStage {
+    AwaitingPayment,
+    FeePaid,
+    CanisterCreated,
+    CodeInstalled,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct WorkspaceDeployment {
+    pub token_type: TokenType,
+    pub stage: WorkspaceDeploymentStage,
+    pub fee_block_index: Option<String>,
+    // fixed when the deployment starts so a retried transfer matches the first one
+    pub fee: Option<u64>,
+    // the journaled fee payment, settled and retried like any other payment
+    pub payment_id: Option<u64>,
+    pub canister_id: Option<Principal>,
+    // nanoseconds
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub last_error: Option<String>,
+}
+
+impl Storable for WorkspaceDeployment {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, WorkspaceDeployment).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum WorkspaceUpgradeState {
+    Pending,
+    InProgress,
+    Upgraded,
+    Failed,
+    RolledBack,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct WorkspaceUpgradeStatus {
+    pub owner: Principal,
+    pub target_hash: String,
+    pub previous_hash: Option<String>,
+    pub state: WorkspaceUpgradeState,
+    // true when target_hash is the module the canister is being rolled back to
+    pub rollback: bool,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub updated_at: u64,
+}
+
+impl Storable for WorkspaceUpgradeStatus {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, WorkspaceUpgradeStatus).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WorkspaceUpgradeRollout {
+    pub active: bool,
+    pub paused: bool,
+    pub wasm_hash: String,
+    pub batch_size: u32,
+    pub max_attempts: u32,
+    pub started_at: u64,
+}
+
+impl Storable for WorkspaceUpgradeRollout {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, WorkspaceUpgradeRollout).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct WorkspaceWasm {
+    #[serde(with = "serde_bytes")]
+    pub wasm: Vec<u8>,
+    pub added_at: u64,
+}
+
+impl Storable for WorkspaceWasm {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, WorkspaceWasm).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct WorkspaceCyclesKey {
+    pub canister_id: Principal,
+    pub timestamp: u64,
+}
+
+impl Storable for WorkspaceCyclesKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, WorkspaceCyclesKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct WorkspaceCyclesSample {
+    pub timestamp: u64,
+    pub cycles: u128,
+    pub topped_up: Option<u128>,
+    // why a low workspace was not topped up
+    pub note: Option<String>,
+}
+
+impl Storable for WorkspaceCyclesSample {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, WorkspaceCyclesSample).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CyclesBudget {
+    pub period_start: u64,
+    pub spent: u128,
+}
+
+impl Storable for CyclesBudget {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, CyclesBudget).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct CyclesBudgetInfo {
+    pub allowance: u128,
+    pub spent: u128,
+    pub period_start: u64,
+    pub period_end: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum DomainStatus {
+    // ic-domains is written, waiting on DNS records and the boundary node registration
+    PendingDns,
+    Registering,
+    Verified,
+    Failed(String),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct WorkspaceDomain {
+    pub domain: String,
+    pub canister_id: Principal,
+    pub owner: Principal,
+    pub status: DomainStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+    pub last_checked_at: Option<u64>,
+}
+
+impl Storable for WorkspaceDomain {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, WorkspaceDomain).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NoteRevisionKey {
+    pub note_id: String,
+    pub revision: u64,
+}
+
+impl Storable for NoteRevisionKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, NoteRevisionKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NoteRevision {
+    pub revision: u64,
+    pub title: String,
+    pub content: String,
+    // when the note held this content, i.e. the note's updated_at at the time
+    pub updated_at: u64,
+    pub saved_at: u64,
+}
+
+impl Storable for NoteRevision {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, NoteRevision).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NoteRevisionSummary {
+    pub revision: u64,
+    pub title: String,
+    pub content_length: u64,
+    pub updated_at: u64,
+    pub saved_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SearchPostingKey {
+    pub term: String,
+    pub note_id: String,
+}
+
+impl Storable for SearchPostingKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, SearchPostingKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub struct IndexedNote {
+    pub terms: Vec<String>,
+    pub excerpt: String,
+}
+
+impl Storable for IndexedNote {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, IndexedNote).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SearchResult {
+    pub note_id: String,
+    pub title: String,
+    pub author: String,
+    pub excerpt: String,
+    pub score: f64,
+    pub updated_at: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SearchNotesResponse {
+    pub results: Vec<SearchResult>,
+    pub total: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TagNoteKey {
+    pub tag: String,
+    pub note_id: String,
+}
+
+impl Storable for TagNoteKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, TagNoteKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NoteAccessLink {
+    pub token: String,
+    pub note_id: String,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+impl Storable for NoteAccessLink {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, NoteAccessLink).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct NoteGuests {
+    pub guests: Vec<String>,
+    pub num_of_guests: u32,
+    pub access_link_expiry: Option<u64>,
+    pub access_links: Vec<NoteAccessLink>,
+}
+
+// An owner's notes in sort timestamp order, see list_notes_page
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OwnerNoteTimeKey {
+    pub owner: Principal,
+    pub timestamp: u64,
+    pub note_id: String,
+}
+
+impl Storable for OwnerNoteTimeKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, OwnerNoteTimeKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OwnerNoteKey {
+    pub owner: Principal,
+    pub note_id: String,
+}
+
+impl Storable for OwnerNoteKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, OwnerNoteKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum EntitlementTier {
+    // granted before tiers existed, treated as Pro
+    Premium,
+    Free,
+    Pro,
+    Team,
+}
+
+impl EntitlementTier {
+    pub fn normalized(&self) -> EntitlementTier {
+        match self {
+            EntitlementTier::Premium => EntitlementTier::Pro,
+            tier => tier.clone(),
+        }
+    }
+
+    pub fn rank(&self) -> u8 {
+        match self {
+            EntitlementTier::Free => 0,
+            EntitlementTier::Premium | EntitlementTier::Pro => 1,
+            EntitlementTier::Team => 2,
+        }
+    }
+}
+
+// What a tier may use, None means unlimited
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TierLimits {
+    pub tier: EntitlementTier,
+    pub private_notes: Option<u64>,
+    // enforced by the asset storage canister, access is revoked when this is zero
+    pub storage_bytes: Option<u64>,
+    pub ai_queries_per_session: Option<u32>,
+    pub workspaces: Option<u32>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EntitlementTable {
+    pub tiers: Vec<TierLimits>,
+}
+
+impl Storable for EntitlementTable {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, EntitlementTable).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PlanUsage {
+    pub tier: EntitlementTier,
+    pub limits: TierLimits,
+    pub entitlement: Option<PremiumEntitlement>,
+    pub private_notes: u64,
+    pub workspaces: u32,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum EntitlementSource {
+    // ledger transfer that paid for the period
+    LedgerTransfer { ledger: Principal, block_index: String },
+    // carried over from the old expiration map, the original payment is unknown
+    Legacy,
+    Manual,
+    // redeemed gift code, paid for by payment_id
+    Gift { code: String, payment_id: u64 },
+    // reward for referring `referee`
+    Referral { referee: Principal },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PremiumEntitlement {
+    pub tier: EntitlementTier,
+    // milliseconds since epoch
+    pub start: u64,
+    pub end: u64,
+    pub source: EntitlementSource,
+    pub updated_at: u64,
+}
+
+impl PremiumEntitlement {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.end > now
+    }
+}
+
+impl Storable for PremiumEntitlement {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, PremiumEntitlement).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+// Expiry queue entry, the principal makes equal end times distinct
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EntitlementExpiryKey {
+    pub end: u64,
+    pub principal: Principal,
+}
+
+impl Storable for EntitlementExpiryKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, EntitlementExpiryKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum PaymentKind {
+    // icrc1_transfer out of the payer's deposit subaccount
+    Deposit,
+    // icrc2_transfer_from against the payer's approval
+    Approval,
+    // payer moving funds out of their deposit subaccount
+    Withdrawal { to: Account },
+    // the system account paying the payer, e.g. a referral reward
+    Payout { to: Account },
+    // the system account paying out the payer's creator ledger balance
+    Earnings { to: Account },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum PaymentState {
+    // journaled, ledger outcome not known yet
+    Pending,
+    // funds received, entitlement not granted yet
+    Transferred,
+    Granted,
+    // withdrawal sent, nothing to grant
+    Completed,
+    Failed(String),
+    // settled too late to grant, funds are being returned
+    RefundPending,
+    Refunded,
+    // a retry fell outside the ledger's dedup window after an attempt with an unknown outcome,
+    // held until a controller confirms whether the transfer went through
+    Unconfirmed,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PaymentRecord {
+    pub id: u64,
+    pub payer: Principal,
+    pub kind: PaymentKind,
+    pub token_type: TokenType,
+    pub ledger: Principal,
+    pub from: Account,
+    pub amount: u64,
+    // ledger fee the transfer was submitted with, None lets the ledger pick
+    pub fee: Option<u64>,
+    // premium period paid for, None for withdrawals
+    pub period: Option<PaymentPeriod>,
+    // tier paid for, None for withdrawals and payments made before tiers existed
+    pub tier: Option<EntitlementTier>,
+    // what the payment buys for others, None for the payer's own plan
+    pub purchase: Option<PaymentPurchase>,
+    // ledger created_at_time in nanoseconds, reused on every retry for deduplication
+    pub created_at_time: u64,
+    pub refund_created_at_time: Option<u64>,
+    pub state: PaymentState,
+    pub block_index: Option<String>,
+    pub refund_block_index: Option<String>,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    // milliseconds since epoch
+    pub created_at: u64,
+    pub updated_at: u64,
+    // when the entitlement was granted
+    pub settled_at: Option<u64>,
+    // promo code redeemed at checkout and the discount it gave, in token units
+    pub promo_code: Option<String>,
+    pub discount: Option<u64>,
+    // certified invoice page, set once the payment is granted
+    pub invoice_path: Option<String>,
+}
+
+impl PaymentRecord {
+    pub fn is_open(&self) -> bool {
+        matches!(self.state, PaymentState::Pending | PaymentState::Transferred | PaymentState::RefundPending)
+    }
+}
+
+impl Storable for PaymentRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, PaymentRecord).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PayerPaymentKey {
+    pub payer: Principal,
+    pub payment_id: u64,
+}
+
+impl Storable for PayerPaymentKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, PayerPaymentKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RevenueTotal {
+    pub token_type: TokenType,
+    pub amount: u64,
+    pub payments: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RevenueReport {
+    pub payments: Vec<PaymentRecord>,
+    // settled payments in the requested window, before paging
+    pub total: u64,
+    pub totals: Vec<RevenueTotal>,
+}
+
+#[derive(Serialize)]
+pub struct InvoiceTemplateContext {
+    pub invoice_number: String,
+    pub payer: String,
+    pub token: String,
+    pub amount: String,
+    pub period: String,
+    pub ledger: String,
+    pub block_index: String,
+    pub issued_at: String,
+    pub site: Site,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct DepositWatch {
+    pub payment_period: PaymentPeriod,
+    pub promo_code: Option<String>,
+    pub tier: Option<EntitlementTier>,
+    pub created_at: u64,
+    pub last_checked_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl Storable for DepositWatch {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, DepositWatch).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TokenConfig {
+    pub token_type: TokenType,
+    pub symbol: String,
+    pub ledger: Principal,
+    pub decimals: u8,
+    // disabled tokens are refused for new payments, withdrawals still work
+    pub enabled: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PlanPrice {
+    // None for prices set before tiers existed, which are Pro
+    pub tier: Option<EntitlementTier>,
+    pub period: PaymentPeriod,
+    // in millionths of a dollar, converted to each token's decimals when charged
+    pub amount: u64,
+    pub promo_amount: Option<u64>,
+}
+
+impl PlanPrice {
+    pub fn plan_tier(&self) -> EntitlementTier {
+        self.tier.as_ref().map(EntitlementTier::normalized).unwrap_or(EntitlementTier::Pro)
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PricingConfig {
+    pub tokens: Vec<TokenConfig>,
+    pub plans: Vec<PlanPrice>,
+    // in millionths of a dollar like plan prices, None for configs stored before it was configurable
+    pub workspace_fee: Option<u64>,
+}
+
+impl Storable for PricingConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, PricingConfig).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum PromoDiscount {
+    // percent off the regular plan price, 1 to 99
+    Percentage(u8),
+    // fixed amount off, in plan price units
+    Fixed(u64),
+    // the plan's configured promo price
+    PlanPromo,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PromoPlan {
+    // None means this period of every tier
+    pub tier: Option<EntitlementTier>,
+    pub period: PaymentPeriod,
+}
+
+impl PromoPlan {
+    pub fn matches(&self, plan: &PlanPrice) -> bool {
+        self.period == plan.period && self.tier.as_ref().is_none_or(|tier| tier.normalized() == plan.plan_tier())
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PromoCodeConfig {
+    pub code: String,
+    pub discount: PromoDiscount,
+    // empty means every plan
+    pub plans: Vec<PromoPlan>,
+    // milliseconds since epoch
+    pub starts_at: Option<u64>,
+    pub ends_at: Option<u64>,
+    pub max_redemptions: Option<u32>,
+    pub max_redemptions_per_principal: Option<u32>,
+    pub enabled: bool,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PromoCode {
+    pub config: PromoCodeConfig,
+    // redemptions of payments that have not failed or been refunded
+    pub redemptions: u32,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for PromoCode {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, PromoCode).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PromoRedemption {
+    pub code: String,
+    pub payment_id: u64,
+    pub payer: Principal,
+    pub period: PaymentPeriod,
+    pub token_type: TokenType,
+    // token units
+    pub price: u64,
+    pub discount: u64,
+    pub amount: u64,
+    pub redeemed_at: u64,
+}
+
+impl Storable for PromoRedemption {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, PromoRedemption).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PromoRedemptionKey {
+    pub code: String,
+    pub payment_id: u64,
+}
+
+impl Storable for PromoRedemptionKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, PromoRedemptionKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PromoUseKey {
+    pub code: String,
+    pub principal: Principal,
+}
+
+impl Storable for PromoUseKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, PromoUseKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PromoRedemptionReport {
+    pub redemptions: Vec<PromoRedemption>,
+    pub total: u64,
+    pub totals: Vec<RevenueTotal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct PriceQuote {
+    pub tier: EntitlementTier,
+    pub period: PaymentPeriod,
+    pub token_type: TokenType,
+    // token units
+    pub price: u64,
+    pub discount: u64,
+    pub amount: u64,
+    pub promo_code: Option<String>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum SubscriptionStatus {
+    Active,
+    // the last renewal failed, retried until the grace period ends
+    PastDue,
+    Cancelled,
+    // the grace period ran out without a successful renewal
+    Lapsed,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Subscription {
+    // account whose ICRC-2 allowance pays for renewals
+    pub payer: Account,
+    pub tier: EntitlementTier,
+    pub period: PaymentPeriod,
+    pub token_type: TokenType,
+    pub status: SubscriptionStatus,
+    // milliseconds since epoch
+    pub next_charge_at: u64,
+    pub grace_until: Option<u64>,
+    pub last_payment_id: Option<u64>,
+    pub failed_attempts: u32,
+    pub last_error: Option<String>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Subscription {
+    pub fn is_renewing(&self) -> bool {
+        matches!(self.status, SubscriptionStatus::Active | SubscriptionStatus::PastDue)
+    }
+}
+
+impl Storable for Subscription {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Subscription).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum PaymentPurchase {
+    Gift { code: String },
+    // new seats in a team pool, a new pool is created when its first seats are granted
+    Seats { pool_id: u64, count: u32 },
+    // another period for existing seats
+    SeatRenewal { pool_id: u64, seat_ids: Vec<u32> },
+    // a reader tipping the author of a published note
+    Tip { note_id: String, author: Principal },
+    // a reader buying access to a paid note
+    Note { note_id: String, author: Principal },
+    // the fee for deploying a workspace, see WorkspaceDeployment
+    Workspace,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum GiftCodeStatus {
+    AwaitingPayment,
+    Available,
+    Redeemed { by: Principal, at: u64 },
+    // the payment failed or was refunded
+    Void,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct GiftCode {
+    pub code: String,
+    pub purchaser: Principal,
+    pub tier: EntitlementTier,
+    pub period: PaymentPeriod,
+    pub payment_id: u64,
+    pub status: GiftCodeStatus,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for GiftCode {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, GiftCode).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct SeatPool {
+    pub id: u64,
+    pub owner: Principal,
+    pub tier: EntitlementTier,
+    pub next_seat_id: u32,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Storable for SeatPool {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, SeatPool).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SeatKey {
+    pub pool_id: u64,
+    pub seat_id: u32,
+}
+
+impl Storable for SeatKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, SeatKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Seat {
+    pub pool_id: u64,
+    pub seat_id: u32,
+    pub assignee: Option<Principal>,
+    pub assigned_at: Option<u64>,
+    // milliseconds since epoch
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Seat {
+    pub fn is_active(&self, now: u64) -> bool {
+        self.end > now
+    }
+}
+
+impl Storable for Seat {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Seat).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SeatPoolView {
+    pub pool: SeatPool,
+    pub seats: Vec<Seat>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ReferralReward {
+    PremiumDays(u32),
+    // in plan price units, paid in the token the referee paid with
+    TokenPayout(u64),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ReferralConfig {
+    pub enabled: bool,
+    pub reward: ReferralReward,
+}
+
+impl Storable for ReferralConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, ReferralConfig).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum ReferralStatus {
+    // waiting for the referee's first premium payment
+    Pending,
+    Rewarded {
+        // the referee's payment that earned the reward
+        payment_id: u64,
+        reward: ReferralReward,
+        payout_payment_id: Option<u64>,
+        at: u64,
+    },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Referral {
+    pub referrer: Principal,
+    pub referee: Principal,
+    pub code: String,
+    pub status: ReferralStatus,
+    pub referred_at: u64,
+}
+
+impl Storable for Referral {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Referral).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ReferrerKey {
+    pub referrer: Principal,
+    pub referee: Principal,
+}
+
+impl Storable for ReferrerKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, ReferrerKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReferralStats {
+    pub code: Option<String>,
+    pub referred: u64,
+    pub rewarded: u64,
+    pub premium_days_earned: u64,
+    // token units, per token
+    pub payouts: Vec<RevenueTotal>,
+    // who referred the caller, if anyone
+    pub referred_by: Option<Principal>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct TipConfig {
+    pub enabled: bool,
+    // platform share of every tip, in basis points
+    pub fee_basis_points: u16,
+    // smallest accepted tip, in plan price units
+    pub min_amount: u64,
+}
+
+impl Storable for TipConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, TipConfig).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Tip {
+    // the tipper's payment
+    pub payment_id: u64,
+    pub note_id: String,
+    pub author: Principal,
+    pub tipper: Principal,
+    pub token_type: TokenType,
+    // token units, what the tipper paid
+    pub amount: u64,
+    // token units, kept by the platform
+    pub fee: u64,
+    // direct payout of amount - fee to the author, None once tips accrue to the creator ledger
+    pub payout_payment_id: Option<u64>,
+    pub created_at: u64,
+}
+
+impl Storable for Tip {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Tip).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct NoteTipKey {
+    pub note_id: String,
+    pub payment_id: u64,
+}
+
+impl Storable for NoteTipKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, NoteTipKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AuthorTipKey {
+    pub author: Principal,
+    pub payment_id: u64,
+}
+
+impl Storable for AuthorTipKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, AuthorTipKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TipSummary {
+    pub tips: u64,
+    // token units the tippers paid, per token
+    pub totals: Vec<RevenueTotal>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct PaidNoteConfig {
+    pub enabled: bool,
+    // platform share of every sale, in basis points
+    pub fee_basis_points: u16,
+    // cheapest price an author can set, in plan price units
+    pub min_price: u64,
+}
+
+impl Storable for PaidNoteConfig {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, PaidNoteConfig).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct NoteSale {
+    // the buyer's payment
+    pub payment_id: u64,
+    pub note_id: String,
+    pub author: Principal,
+    pub buyer: Principal,
+    pub token_type: TokenType,
+    // token units, what the buyer paid
+    pub amount: u64,
+    // token units, kept by the platform
+    pub fee: u64,
+    // direct payout of amount - fee to the author, None once sales accrue to the creator ledger
+    pub payout_payment_id: Option<u64>,
+    pub sold_at: u64,
+}
+
+impl Storable for NoteSale {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, NoteSale).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BuyerNoteKey {
+    pub buyer: Principal,
+    pub note_id: String,
+}
+
+impl Storable for BuyerNoteKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, BuyerNoteKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AuthorSaleKey {
+    pub author: Principal,
+    pub payment_id: u64,
+}
+
+impl Storable for AuthorSaleKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, AuthorSaleKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct NoteSalesSummary {
+    pub sales: u64,
+    // token units the buyers paid, per token
+    pub totals: Vec<RevenueTotal>,
+    // token units the author earned after the platform fee, per token
+    pub earnings: Vec<RevenueTotal>,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub enum LedgerEntryKind {
+    // author's share of a tip
+    Tip { note_id: String, payment_id: u64 },
+    // author's share of a note sale
+    NoteSale { note_id: String, payment_id: u64 },
+    // platform's share of a tip or sale
+    PlatformFee { payment_id: u64 },
+    // balance paid out to an ICRC-1 account, ledger fee included
+    Withdrawal { payment_id: u64 },
+    // a withdrawal the ledger rejected, credited back
+    WithdrawalReversal { payment_id: u64 },
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct LedgerEntry {
+    pub id: u64,
+    // creator, or this canister for the platform fee account
+    pub owner: Principal,
+    pub token_type: TokenType,
+    pub kind: LedgerEntryKind,
+    // token units
+    pub credit: u64,
+    pub debit: u64,
+    // owner's balance in this token after the entry
+    pub balance: u64,
+    pub created_at: u64,
+}
+
+impl Storable for LedgerEntry {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, LedgerEntry).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LedgerBalanceKey {
+    pub owner: Principal,
+    pub token_type: TokenType,
+}
+
+impl Storable for LedgerBalanceKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, LedgerBalanceKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OwnerLedgerEntryKey {
+    pub owner: Principal,
+    pub entry_id: u64,
+}
+
+impl Storable for OwnerLedgerEntryKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, OwnerLedgerEntryKey).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LedgerBalance {
+    pub token_type: TokenType,
+    pub balance: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LedgerStatement {
+    pub owner: Principal,
+    pub balances: Vec<LedgerBalance>,
+    // newest first
+    pub entries: Vec<LedgerEntry>,
+    pub total: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LedgerInvariant {
+    pub token_type: TokenType,
+    // sum of all creator and platform balances, token units
+    pub internal_total: u64,
+    // what the system account holds on the ledger, token units
+    pub held: u64,
+    pub holds: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    fn round_trip<K: Storable + PartialEq + std::fmt::Debug>(key: K) {
+        assert_eq!(K::from_bytes(key.to_bytes()), key);
+    }
+
+    #[test]
+    fn composite_keys_round_trip() {
+        let note_id = || "note_1700000000000_intro".to_string();
+        round_trip(WorkspaceCyclesKey { canister_id: principal(1), timestamp: 1_700_000_000_000 });
+        round_trip(NoteRevisionKey { note_id: note_id(), revision: 256 });
+        round_trip(SearchPostingKey { term: "rust".to_string(), note_id: note_id() });
+        round_trip(TagNoteKey { tag: "icp".to_string(), note_id: note_id() });
+        round_trip(OwnerNoteKey { owner: principal(2), note_id: note_id() });
+        round_trip(OwnerNoteTimeKey { owner: principal(2), timestamp: 1_700_000_000_000, note_id: note_id() });
+        round_trip(EntitlementExpiryKey { end: u64::MAX, principal: principal(3) });
+        round_trip(PayerPaymentKey { payer: principal(4), payment_id: 70_000 });
+        round_trip(PromoRedemptionKey { code: "LAUNCH".to_string(), payment_id: 1 });
+        round_trip(SeatKey { pool_id: 2, seat_id: u32::MAX });
+        round_trip(ReferrerKey { referrer: principal(5), referee: principal(6) });
+        round_trip(NoteTipKey { note_id: note_id(), payment_id: 255 });
+        round_trip(AuthorTipKey { author: principal(7), payment_id: 0 });
+        round_trip(BuyerNoteKey { buyer: principal(8), note_id: String::new() });
+        round_trip(AuthorSaleKey { author: principal(9), payment_id: 257 });
+        round_trip(LedgerBalanceKey { owner: principal(10), token_type: TokenType::CKUSDT });
+        round_trip(OwnerLedgerEntryKey { owner: principal(11), entry_id: 42 });
+    }
+}
@@ -0,0 +1,24 @@
+// Copyright 2025 Declan Nnadozie
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     https://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{env, path::Path};
+
+fn main() {
+    // dfx writes .env on deploy, a plain cargo build runs without it
+    let _ = dotenv::from_path(Path::new("../../.env"));
+    println!("cargo:rerun-if-env-changed=CANISTER_ID_DOTANE_IC_BACKEND");
+    if let Ok(backend_canister_id) = env::var("CANISTER_ID_DOTANE_IC_BACKEND") {
+        println!("cargo:rustc-env=CANISTER_ID_DOTANE_IC_BACKEND={}", backend_canister_id);
+    }
+}
@@ -0,0 +1,857 @@
+use std::{cell::RefCell, collections::HashSet};
+
+use candid::{Nat, Principal};
+use dotane_types::{MintNoteNftArgs, NoteNftTransfer};
+use ic_cdk::api::{canister_self, msg_caller, time};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory}, BTreeSet, DefaultMemoryImpl, StableBTreeMap, StableCell
+};
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use serde_bytes::ByteBuf;
+
+use crate::types::{
+    ApprovalInfo, ApproveCollectionArg, ApproveCollectionError, ApproveCollectionResult, ApproveTokenArg, ApproveTokenError,
+    ApproveTokenResult, CollectionApprovalKey, IsApprovedArg, OwnerTokenKey, RevokeCollectionApprovalArg,
+    RevokeCollectionApprovalError, RevokeCollectionApprovalResult, RevokeTokenApprovalArg, RevokeTokenApprovalError,
+    RevokeTokenApprovalResult, StandardRecord, Token, TokenApproval, TokenApprovalKey, TransferArg, TransferError,
+    TransferFromArg, TransferFromError, TransferFromResult, TransferResult, Value,
+};
+
+mod types;
+
+const BACKEND_CANISTER_ID: Option<&str> = option_env!("CANISTER_ID_DOTANE_IC_BACKEND");
+
+// Collection constants
+const COLLECTION_NAME: &str = "Dotane Notes";
+const COLLECTION_SYMBOL: &str = "DNOTE";
+const COLLECTION_DESCRIPTION: &str = "Notes published on Dotane. Whoever holds a token owns its note.";
+const MAX_QUERY_BATCH_SIZE: usize = 100;
+const MAX_UPDATE_BATCH_SIZE: usize = 20;
+const DEFAULT_TAKE_VALUE: usize = 100;
+const MAX_TAKE_VALUE: usize = 500;
+const MAX_MEMO_SIZE: usize = 32;
+const MAX_APPROVALS: usize = 50;
+// created_at_time checks, in nanoseconds
+const TX_WINDOW: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT: u64 = 2 * 60 * 1_000_000_000;
+const DEFAULT_SUBACCOUNT: Subaccount = [0; 32];
+
+// GenericError codes
+const ERROR_MEMO_TOO_LONG: u32 = 1;
+const ERROR_TOKEN_LOCKED: u32 = 2;
+const ERROR_NOTE_TRANSFER_FAILED: u32 = 3;
+const ERROR_TOO_MANY_APPROVALS: u32 = 4;
+const ERROR_APPROVAL_EXPIRED: u32 = 5;
+const ERROR_BATCH_TOO_LARGE: u32 = 6;
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    static TOKENS: RefCell<StableBTreeMap<u64, Token, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(0))),
+        )
+    );
+
+    static OWNER_TOKENS: RefCell<BTreeSet<OwnerTokenKey, Memory>> = RefCell::new(
+        BTreeSet::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
+        )
+    );
+
+    // note id -> token id, a note is minted at most once
+    static NOTE_TOKENS: RefCell<StableBTreeMap<String, u64, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2))),
+        )
+    );
+
+    static TOKEN_APPROVALS: RefCell<StableBTreeMap<TokenApprovalKey, ApprovalInfo, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))),
+        )
+    );
+
+    static COLLECTION_APPROVALS: RefCell<StableBTreeMap<CollectionApprovalKey, ApprovalInfo, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))),
+        )
+    );
+
+    static NEXT_TOKEN_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))), 1)
+    );
+
+    static NEXT_TX_ID: RefCell<StableCell<u64, Memory>> = RefCell::new(
+        StableCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))), 0)
+    );
+
+    // tokens waiting on dotane_ic_backend to hand their note over
+    static TRANSFERRING: RefCell<HashSet<u64>> = RefCell::new(HashSet::new());
+}
+
+fn backend_canister() -> Result<Principal, String> {
+    let canister_id = BACKEND_CANISTER_ID.ok_or("dotane_ic_backend is not configured".to_string())?;
+    Principal::from_text(canister_id).map_err(|e| format!("Failed to parse backend canister ID: {}", e))
+}
+
+fn is_backend() -> Result<(), String> {
+    if msg_caller() != backend_canister()? {
+        return Err("Only dotane_ic_backend can call this method".to_string());
+    }
+    Ok(())
+}
+
+fn resolve(subaccount: Option<Subaccount>) -> Subaccount {
+    subaccount.unwrap_or(DEFAULT_SUBACCOUNT)
+}
+
+fn same_account(a: &Account, b: &Account) -> bool {
+    a.owner == b.owner && resolve(a.subaccount) == resolve(b.subaccount)
+}
+
+fn owner_token_key(owner: &Account, token_id: u64) -> OwnerTokenKey {
+    OwnerTokenKey { owner: owner.owner, subaccount: resolve(owner.subaccount), token_id }
+}
+
+fn to_token_id(token_id: &Nat) -> Option<u64> {
+    u64::try_from(token_id.0.clone()).ok()
+}
+
+fn take_value(take: Option<Nat>) -> usize {
+    take.and_then(|take| usize::try_from(take.0).ok())
+        .unwrap_or(DEFAULT_TAKE_VALUE)
+        .min(MAX_TAKE_VALUE)
+}
+
+fn next_tx_id() -> Nat {
+    NEXT_TX_ID.with_borrow_mut(|cell| {
+        let id = *cell.get();
+        cell.set(id + 1);
+        Nat::from(id)
+    })
+}
+
+fn generic_error(error_code: u32) -> Nat {
+    Nat::from(error_code)
+}
+
+enum TimeError {
+    TooOld,
+    CreatedInFuture(u64),
+}
+
+fn check_created_at_time(created_at_time: Option<u64>) -> Result<(), TimeError> {
+    let Some(created_at_time) = created_at_time else {
+        return Ok(());
+    };
+    let now = time();
+    if created_at_time.saturating_add(TX_WINDOW).saturating_add(PERMITTED_DRIFT) < now {
+        return Err(TimeError::TooOld);
+    }
+    if created_at_time > now.saturating_add(PERMITTED_DRIFT) {
+        return Err(TimeError::CreatedInFuture(now));
+    }
+    Ok(())
+}
+
+fn memo_too_long(memo: &Option<ByteBuf>) -> bool {
+    memo.as_ref().is_some_and(|memo| memo.len() > MAX_MEMO_SIZE)
+}
+
+fn approval_active(approval: &ApprovalInfo, now: u64) -> bool {
+    !approval.expires_at.is_some_and(|expires_at| expires_at <= now)
+}
+
+fn valid_recipient(from: &Account, to: &Account) -> bool {
+    to.owner != Principal::anonymous() && to.owner != canister_self() && !same_account(from, to)
+}
+
+// A token can only be moved by one call at a time, the backend call in between must not race another transfer.
+// The lock is released on drop, so a trap in the callback can't leave the token stuck.
+struct TokenLock(u64);
+
+impl TokenLock {
+    fn acquire(token_id: u64) -> Option<Self> {
+        TRANSFERRING
+            .with_borrow_mut(|transferring| transferring.insert(token_id))
+            .then_some(TokenLock(token_id))
+    }
+}
+
+impl Drop for TokenLock {
+    fn drop(&mut self) {
+        TRANSFERRING.with_borrow_mut(|transferring| {
+            transferring.remove(&self.0);
+        });
+    }
+}
+
+fn token_approval_keys(token_id: u64) -> Vec<TokenApprovalKey> {
+    TOKEN_APPROVALS.with_borrow(|approvals| {
+        approvals
+            .range(TokenApprovalKey { token_id, spender: Principal::management_canister(), spender_subaccount: DEFAULT_SUBACCOUNT }..)
+            .take_while(|entry| entry.key().token_id == token_id)
+            .map(|entry| entry.key().clone())
+            .collect()
+    })
+}
+
+fn collection_approval_keys(owner: &Account) -> Vec<CollectionApprovalKey> {
+    let owner_subaccount = resolve(owner.subaccount);
+    COLLECTION_APPROVALS.with_borrow(|approvals| {
+        approvals
+            .range(CollectionApprovalKey {
+                owner: owner.owner,
+                owner_subaccount,
+                spender: Principal::management_canister(),
+                spender_subaccount: DEFAULT_SUBACCOUNT,
+            }..)
+            .take_while(|entry| entry.key().owner == owner.owner && entry.key().owner_subaccount == owner_subaccount)
+            .map(|entry| entry.key().clone())
+            .collect()
+    })
+}
+
+fn is_spender_approved(token_id: u64, owner: &Account, spender: &Account) -> bool {
+    let now = time();
+    let token_approval = TOKEN_APPROVALS.with_borrow(|approvals| {
+        approvals.get(&TokenApprovalKey { token_id, spender: spender.owner, spender_subaccount: resolve(spender.subaccount) })
+    });
+    let collection_approval = COLLECTION_APPROVALS.with_borrow(|approvals| {
+        approvals.get(&CollectionApprovalKey {
+            owner: owner.owner,
+            owner_subaccount: resolve(owner.subaccount),
+            spender: spender.owner,
+            spender_subaccount: resolve(spender.subaccount),
+        })
+    });
+    token_approval.is_some_and(|approval| approval_active(&approval, now))
+        || collection_approval.is_some_and(|approval| approval_active(&approval, now))
+}
+
+fn token_metadata(token: &Token) -> Vec<(String, Value)> {
+    let metadata = &token.metadata;
+    vec![
+        ("icrc7:name".to_string(), Value::Text(metadata.title.clone())),
+        ("dotane:note_id".to_string(), Value::Text(metadata.note_id.clone())),
+        ("dotane:content_hash".to_string(), Value::Text(metadata.content_hash.clone())),
+        ("dotane:author".to_string(), Value::Text(metadata.author.clone())),
+        ("dotane:url".to_string(), Value::Text(metadata.url.clone())),
+        ("dotane:minted_at".to_string(), Value::Nat(Nat::from(metadata.minted_at))),
+    ]
+}
+
+/// Mint a token for a published note. Only dotane_ic_backend mints, after checking the caller wrote the note.
+/// Minting a note again for the same owner returns the token it already has.
+#[ic_cdk::update(guard = "is_backend")]
+fn mint(args: MintNoteNftArgs) -> Result<Nat, String> {
+    let owner = Account { owner: args.owner, subaccount: None };
+    if let Some(token_id) = NOTE_TOKENS.with_borrow(|note_tokens| note_tokens.get(&args.metadata.note_id)) {
+        // a retry after the backend lost our reply gets the same token back
+        let minted_to_owner = TOKENS
+            .with_borrow(|tokens| tokens.get(&token_id))
+            .is_some_and(|token| same_account(&token.owner, &owner));
+        if minted_to_owner {
+            return Ok(Nat::from(token_id));
+        }
+        return Err(format!("Note is already minted as token {}", token_id));
+    }
+    if args.owner == Principal::anonymous() {
+        return Err("Anonymous principal cannot own a token".to_string());
+    }
+
+    let token_id = NEXT_TOKEN_ID.with_borrow_mut(|cell| {
+        let id = *cell.get();
+        cell.set(id + 1);
+        id
+    });
+    NOTE_TOKENS.with_borrow_mut(|note_tokens| note_tokens.insert(args.metadata.note_id.clone(), token_id));
+    OWNER_TOKENS.with_borrow_mut(|owner_tokens| owner_tokens.insert(owner_token_key(&owner, token_id)));
+    TOKENS.with_borrow_mut(|tokens| tokens.insert(token_id, Token { owner, metadata: args.metadata }));
+    next_tx_id();
+    Ok(Nat::from(token_id))
+}
+
+#[ic_cdk::query]
+fn get_note_token(note_id: String) -> Option<Nat> {
+    NOTE_TOKENS.with_borrow(|note_tokens| note_tokens.get(&note_id)).map(Nat::from)
+}
+
+// Hand the note over in dotane_ic_backend, then move the token. The caller has checked the move
+// is allowed and locked the token, the lock is released when this returns.
+async fn move_token(lock: TokenLock, mut token: Token, to: Account) -> Result<Nat, String> {
+    let token_id = lock.0;
+    if token.owner.owner != to.owner {
+        let transfer = NoteNftTransfer {
+            token_id,
+            note_id: token.metadata.note_id.clone(),
+            from: token.owner.owner,
+            to: to.owner,
+        };
+        let result = match ic_cdk::call::Call::unbounded_wait(backend_canister()?, "transfer_note_ownership")
+            .with_arg(transfer)
+            .await
+        {
+            Ok(response) => response
+                .candid::<Result<(), String>>()
+                .map_err(|e| format!("Failed to decode note transfer result: {:?}", e))
+                .and_then(|result| result),
+            Err(e) => Err(format!("Failed to call dotane_ic_backend: {:?}", e)),
+        };
+        result?;
+    }
+
+    OWNER_TOKENS.with_borrow_mut(|owner_tokens| {
+        owner_tokens.remove(&owner_token_key(&token.owner, token_id));
+        owner_tokens.insert(owner_token_key(&to, token_id));
+    });
+    // token approvals were granted by the previous owner
+    TOKEN_APPROVALS.with_borrow_mut(|approvals| {
+        for key in token_approval_keys(token_id) {
+            approvals.remove(&key);
+        }
+    });
+    token.owner = to;
+    TOKENS.with_borrow_mut(|tokens| tokens.insert(token_id, token));
+    Ok(next_tx_id())
+}
+
+async fn transfer_token(caller: Principal, arg: TransferArg) -> TransferResult {
+    if memo_too_long(&arg.memo) {
+        return Err(TransferError::GenericError { error_code: generic_error(ERROR_MEMO_TOO_LONG), message: "Memo is too long".to_string() });
+    }
+    match check_created_at_time(arg.created_at_time) {
+        Err(TimeError::TooOld) => return Err(TransferError::TooOld),
+        Err(TimeError::CreatedInFuture(ledger_time)) => return Err(TransferError::CreatedInFuture { ledger_time }),
+        Ok(()) => {}
+    }
+    let token_id = to_token_id(&arg.token_id).ok_or(TransferError::NonExistingTokenId)?;
+    let token = TOKENS
+        .with_borrow(|tokens| tokens.get(&token_id))
+        .ok_or(TransferError::NonExistingTokenId)?;
+    let from = Account { owner: caller, subaccount: arg.from_subaccount };
+    if !same_account(&token.owner, &from) {
+        return Err(TransferError::Unauthorized);
+    }
+    if !valid_recipient(&from, &arg.to) {
+        return Err(TransferError::InvalidRecipient);
+    }
+    let Some(lock) = TokenLock::acquire(token_id) else {
+        return Err(TransferError::GenericError { error_code: generic_error(ERROR_TOKEN_LOCKED), message: "Token is being transferred".to_string() });
+    };
+
+    move_token(lock, token, arg.to)
+        .await
+        .map_err(|message| TransferError::GenericError { error_code: generic_error(ERROR_NOTE_TRANSFER_FAILED), message })
+}
+
+async fn transfer_token_from(caller: Principal, arg: TransferFromArg) -> TransferFromResult {
+    if memo_too_long(&arg.memo) {
+        return Err(TransferFromError::GenericError { error_code: generic_error(ERROR_MEMO_TOO_LONG), message: "Memo is too long".to_string() });
+    }
+    match check_created_at_time(arg.created_at_time) {
+        Err(TimeError::TooOld) => return Err(TransferFromError::TooOld),
+        Err(TimeError::CreatedInFuture(ledger_time)) => return Err(TransferFromError::CreatedInFuture { ledger_time }),
+        Ok(()) => {}
+    }
+    let token_id = to_token_id(&arg.token_id).ok_or(TransferFromError::NonExistingTokenId)?;
+    let token = TOKENS
+        .with_borrow(|tokens| tokens.get(&token_id))
+        .ok_or(TransferFromError::NonExistingTokenId)?;
+    if !same_account(&token.owner, &arg.from) {
+        return Err(TransferFromError::Unauthorized);
+    }
+    let spender = Account { owner: caller, subaccount: arg.spender_subaccount };
+    if !is_spender_approved(token_id, &token.owner, &spender) {
+        return Err(TransferFromError::Unauthorized);
+    }
+    if !valid_recipient(&arg.from, &arg.to) {
+        return Err(TransferFromError::InvalidRecipient);
+    }
+    let Some(lock) = TokenLock::acquire(token_id) else {
+        return Err(TransferFromError::GenericError { error_code: generic_error(ERROR_TOKEN_LOCKED), message: "Token is being transferred".to_string() });
+    };
+
+    move_token(lock, token, arg.to)
+        .await
+        .map_err(|message| TransferFromError::GenericError { error_code: generic_error(ERROR_NOTE_TRANSFER_FAILED), message })
+}
+
+// ICRC-7
+
+#[ic_cdk::query]
+fn icrc7_collection_metadata() -> Vec<(String, Value)> {
+    vec![
+        ("icrc7:symbol".to_string(), Value::Text(COLLECTION_SYMBOL.to_string())),
+        ("icrc7:name".to_string(), Value::Text(COLLECTION_NAME.to_string())),
+        ("icrc7:description".to_string(), Value::Text(COLLECTION_DESCRIPTION.to_string())),
+        ("icrc7:total_supply".to_string(), Value::Nat(icrc7_total_supply())),
+        ("icrc7:max_query_batch_size".to_string(), Value::Nat(Nat::from(MAX_QUERY_BATCH_SIZE))),
+        ("icrc7:max_update_batch_size".to_string(), Value::Nat(Nat::from(MAX_UPDATE_BATCH_SIZE))),
+        ("icrc7:default_take_value".to_string(), Value::Nat(Nat::from(DEFAULT_TAKE_VALUE))),
+        ("icrc7:max_take_value".to_string(), Value::Nat(Nat::from(MAX_TAKE_VALUE))),
+        ("icrc7:max_memo_size".to_string(), Value::Nat(Nat::from(MAX_MEMO_SIZE))),
+        ("icrc7:atomic_batch_transfers".to_string(), Value::Text("false".to_string())),
+        ("icrc7:tx_window".to_string(), Value::Nat(Nat::from(TX_WINDOW))),
+        ("icrc7:permitted_drift".to_string(), Value::Nat(Nat::from(PERMITTED_DRIFT))),
+    ]
+}
+
+#[ic_cdk::query]
+fn icrc7_symbol() -> String {
+    COLLECTION_SYMBOL.to_string()
+}
+
+#[ic_cdk::query]
+fn icrc7_name() -> String {
+    COLLECTION_NAME.to_string()
+}
+
+#[ic_cdk::query]
+fn icrc7_description() -> Option<String> {
+    Some(COLLECTION_DESCRIPTION.to_string())
+}
+
+#[ic_cdk::query]
+fn icrc7_logo() -> Option<String> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_total_supply() -> Nat {
+    Nat::from(TOKENS.with_borrow(|tokens| tokens.len()))
+}
+
+#[ic_cdk::query]
+fn icrc7_supply_cap() -> Option<Nat> {
+    None
+}
+
+#[ic_cdk::query]
+fn icrc7_max_query_batch_size() -> Option<Nat> {
+    Some(Nat::from(MAX_QUERY_BATCH_SIZE))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_update_batch_size() -> Option<Nat> {
+    Some(Nat::from(MAX_UPDATE_BATCH_SIZE))
+}
+
+#[ic_cdk::query]
+fn icrc7_default_take_value() -> Option<Nat> {
+    Some(Nat::from(DEFAULT_TAKE_VALUE))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_take_value() -> Option<Nat> {
+    Some(Nat::from(MAX_TAKE_VALUE))
+}
+
+#[ic_cdk::query]
+fn icrc7_max_memo_size() -> Option<Nat> {
+    Some(Nat::from(MAX_MEMO_SIZE))
+}
+
+#[ic_cdk::query]
+fn icrc7_atomic_batch_transfers() -> Option<bool> {
+    Some(false)
+}
+
+#[ic_cdk::query]
+fn icrc7_tx_window() -> Option<Nat> {
+    Some(Nat::from(TX_WINDOW))
+}
+
+#[ic_cdk::query]
+fn icrc7_permitted_drift() -> Option<Nat> {
+    Some(Nat::from(PERMITTED_DRIFT))
+}
+
+#[ic_cdk::query]
+fn icrc7_token_metadata(token_ids: Vec<Nat>) -> Vec<Option<Vec<(String, Value)>>> {
+    TOKENS.with_borrow(|tokens| {
+        token_ids
+            .iter()
+            .take(MAX_QUERY_BATCH_SIZE)
+            .map(|token_id| to_token_id(token_id).and_then(|token_id| tokens.get(&token_id)).map(|token| token_metadata(&token)))
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_owner_of(token_ids: Vec<Nat>) -> Vec<Option<Account>> {
+    TOKENS.with_borrow(|tokens| {
+        token_ids
+            .iter()
+            .take(MAX_QUERY_BATCH_SIZE)
+            .map(|token_id| to_token_id(token_id).and_then(|token_id| tokens.get(&token_id)).map(|token| token.owner))
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_balance_of(accounts: Vec<Account>) -> Vec<Nat> {
+    accounts
+        .iter()
+        .take(MAX_QUERY_BATCH_SIZE)
+        .map(|account| Nat::from(owned_token_ids(account, None, usize::MAX).len()))
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens(prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    let start = prev.as_ref().and_then(to_token_id).map(|prev| prev.saturating_add(1)).unwrap_or(0);
+    TOKENS.with_borrow(|tokens| {
+        tokens
+            .range(start..)
+            .take(take_value(take))
+            .map(|entry| Nat::from(*entry.key()))
+            .collect()
+    })
+}
+
+fn owned_token_ids(account: &Account, prev: Option<u64>, take: usize) -> Vec<u64> {
+    let subaccount = resolve(account.subaccount);
+    let start = prev.map(|prev| prev.saturating_add(1)).unwrap_or(0);
+    OWNER_TOKENS.with_borrow(|owner_tokens| {
+        owner_tokens
+            .range(OwnerTokenKey { owner: account.owner, subaccount, token_id: start }..)
+            .take_while(|key| key.owner == account.owner && key.subaccount == subaccount)
+            .take(take)
+            .map(|key| key.token_id)
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc7_tokens_of(account: Account, prev: Option<Nat>, take: Option<Nat>) -> Vec<Nat> {
+    owned_token_ids(&account, prev.as_ref().and_then(to_token_id), take_value(take))
+        .into_iter()
+        .map(Nat::from)
+        .collect()
+}
+
+/// Transfer tokens from the caller. The note of every token moves to the recipient's principal with it.
+#[ic_cdk::update]
+async fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<TransferResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(TransferError::GenericBatchError {
+            error_code: generic_error(ERROR_BATCH_TOO_LARGE),
+            message: format!("At most {} transfers per call", MAX_UPDATE_BATCH_SIZE),
+        }))];
+    }
+    let caller = msg_caller();
+    let mut results = Vec::with_capacity(args.len());
+    for arg in args {
+        results.push(Some(transfer_token(caller, arg).await));
+    }
+    results
+}
+
+#[ic_cdk::query]
+fn icrc10_supported_standards() -> Vec<StandardRecord> {
+    vec![
+        StandardRecord {
+            name: "ICRC-7".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-7".to_string(),
+        },
+        StandardRecord {
+            name: "ICRC-10".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-10".to_string(),
+        },
+        StandardRecord {
+            name: "ICRC-37".to_string(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-37".to_string(),
+        },
+    ]
+}
+
+// ICRC-37
+
+#[ic_cdk::query]
+fn icrc37_metadata() -> Vec<(String, Value)> {
+    vec![
+        ("icrc37:max_approvals_per_token_or_collection".to_string(), Value::Nat(Nat::from(MAX_APPROVALS))),
+        ("icrc37:max_revoke_approvals".to_string(), Value::Nat(Nat::from(MAX_APPROVALS))),
+    ]
+}
+
+#[ic_cdk::query]
+fn icrc37_max_approvals_per_token_or_collection() -> Option<Nat> {
+    Some(Nat::from(MAX_APPROVALS))
+}
+
+#[ic_cdk::query]
+fn icrc37_max_revoke_approvals() -> Option<Nat> {
+    Some(Nat::from(MAX_APPROVALS))
+}
+
+fn approve_token(caller: Principal, arg: ApproveTokenArg) -> ApproveTokenResult {
+    let approval = arg.approval_info;
+    if memo_too_long(&approval.memo) {
+        return Err(ApproveTokenError::GenericError { error_code: generic_error(ERROR_MEMO_TOO_LONG), message: "Memo is too long".to_string() });
+    }
+    match check_created_at_time(Some(approval.created_at_time)) {
+        Err(TimeError::TooOld) => return Err(ApproveTokenError::TooOld),
+        Err(TimeError::CreatedInFuture(ledger_time)) => return Err(ApproveTokenError::CreatedInFuture { ledger_time }),
+        Ok(()) => {}
+    }
+    let token_id = to_token_id(&arg.token_id).ok_or(ApproveTokenError::NonExistingTokenId)?;
+    let token = TOKENS
+        .with_borrow(|tokens| tokens.get(&token_id))
+        .ok_or(ApproveTokenError::NonExistingTokenId)?;
+    let owner = Account { owner: caller, subaccount: approval.from_subaccount };
+    if !same_account(&token.owner, &owner) {
+        return Err(ApproveTokenError::Unauthorized);
+    }
+    if approval.spender.owner == caller {
+        return Err(ApproveTokenError::InvalidSpender);
+    }
+    if !approval_active(&approval, time()) {
+        return Err(ApproveTokenError::GenericError { error_code: generic_error(ERROR_APPROVAL_EXPIRED), message: "Approval is already expired".to_string() });
+    }
+
+    let key = TokenApprovalKey { token_id, spender: approval.spender.owner, spender_subaccount: resolve(approval.spender.subaccount) };
+    let is_new = !TOKEN_APPROVALS.with_borrow(|approvals| approvals.contains_key(&key));
+    if is_new && token_approval_keys(token_id).len() >= MAX_APPROVALS {
+        return Err(ApproveTokenError::GenericError {
+            error_code: generic_error(ERROR_TOO_MANY_APPROVALS),
+            message: format!("A token can have at most {} approvals", MAX_APPROVALS),
+        });
+    }
+    TOKEN_APPROVALS.with_borrow_mut(|approvals| approvals.insert(key, approval));
+    Ok(next_tx_id())
+}
+
+#[ic_cdk::update]
+fn icrc37_approve_tokens(args: Vec<ApproveTokenArg>) -> Vec<Option<ApproveTokenResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(ApproveTokenError::GenericBatchError {
+            error_code: generic_error(ERROR_BATCH_TOO_LARGE),
+            message: format!("At most {} approvals per call", MAX_UPDATE_BATCH_SIZE),
+        }))];
+    }
+    let caller = msg_caller();
+    args.into_iter().map(|arg| Some(approve_token(caller, arg))).collect()
+}
+
+fn approve_collection(caller: Principal, arg: ApproveCollectionArg) -> ApproveCollectionResult {
+    let approval = arg.approval_info;
+    if memo_too_long(&approval.memo) {
+        return Err(ApproveCollectionError::GenericError { error_code: generic_error(ERROR_MEMO_TOO_LONG), message: "Memo is too long".to_string() });
+    }
+    match check_created_at_time(Some(approval.created_at_time)) {
+        Err(TimeError::TooOld) => return Err(ApproveCollectionError::TooOld),
+        Err(TimeError::CreatedInFuture(ledger_time)) => return Err(ApproveCollectionError::CreatedInFuture { ledger_time }),
+        Ok(()) => {}
+    }
+    if approval.spender.owner == caller {
+        return Err(ApproveCollectionError::InvalidSpender);
+    }
+    if !approval_active(&approval, time()) {
+        return Err(ApproveCollectionError::GenericError { error_code: generic_error(ERROR_APPROVAL_EXPIRED), message: "Approval is already expired".to_string() });
+    }
+
+    let owner = Account { owner: caller, subaccount: approval.from_subaccount };
+    let key = CollectionApprovalKey {
+        owner: caller,
+        owner_subaccount: resolve(approval.from_subaccount),
+        spender: approval.spender.owner,
+        spender_subaccount: resolve(approval.spender.subaccount),
+    };
+    let is_new = !COLLECTION_APPROVALS.with_borrow(|approvals| approvals.contains_key(&key));
+    if is_new && collection_approval_keys(&owner).len() >= MAX_APPROVALS {
+        return Err(ApproveCollectionError::GenericError {
+            error_code: generic_error(ERROR_TOO_MANY_APPROVALS),
+            message: format!("An account can have at most {} collection approvals", MAX_APPROVALS),
+        });
+    }
+    COLLECTION_APPROVALS.with_borrow_mut(|approvals| approvals.insert(key, approval));
+    Ok(next_tx_id())
+}
+
+#[ic_cdk::update]
+fn icrc37_approve_collection(args: Vec<ApproveCollectionArg>) -> Vec<Option<ApproveCollectionResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(ApproveCollectionError::GenericBatchError {
+            error_code: generic_error(ERROR_BATCH_TOO_LARGE),
+            message: format!("At most {} approvals per call", MAX_UPDATE_BATCH_SIZE),
+        }))];
+    }
+    let caller = msg_caller();
+    args.into_iter().map(|arg| Some(approve_collection(caller, arg))).collect()
+}
+
+fn revoke_token_approval(caller: Principal, arg: RevokeTokenApprovalArg) -> RevokeTokenApprovalResult {
+    if memo_too_long(&arg.memo) {
+        return Err(RevokeTokenApprovalError::GenericError { error_code: generic_error(ERROR_MEMO_TOO_LONG), message: "Memo is too long".to_string() });
+    }
+    match check_created_at_time(arg.created_at_time) {
+        Err(TimeError::TooOld) => return Err(RevokeTokenApprovalError::TooOld),
+        Err(TimeError::CreatedInFuture(ledger_time)) => return Err(RevokeTokenApprovalError::CreatedInFuture { ledger_time }),
+        Ok(()) => {}
+    }
+    let token_id = to_token_id(&arg.token_id).ok_or(RevokeTokenApprovalError::NonExistingTokenId)?;
+    let token = TOKENS
+        .with_borrow(|tokens| tokens.get(&token_id))
+        .ok_or(RevokeTokenApprovalError::NonExistingTokenId)?;
+    if !same_account(&token.owner, &Account { owner: caller, subaccount: arg.from_subaccount }) {
+        return Err(RevokeTokenApprovalError::Unauthorized);
+    }
+
+    let keys = match arg.spender {
+        Some(spender) => vec![TokenApprovalKey { token_id, spender: spender.owner, spender_subaccount: resolve(spender.subaccount) }],
+        None => token_approval_keys(token_id),
+    };
+    let removed = TOKEN_APPROVALS.with_borrow_mut(|approvals| {
+        keys.iter().filter(|key| approvals.remove(key).is_some()).count()
+    });
+    if removed == 0 {
+        return Err(RevokeTokenApprovalError::ApprovalDoesNotExist);
+    }
+    Ok(next_tx_id())
+}
+
+#[ic_cdk::update]
+fn icrc37_revoke_token_approvals(args: Vec<RevokeTokenApprovalArg>) -> Vec<Option<RevokeTokenApprovalResult>> {
+    if args.len() > MAX_APPROVALS {
+        return vec![Some(Err(RevokeTokenApprovalError::GenericBatchError {
+            error_code: generic_error(ERROR_BATCH_TOO_LARGE),
+            message: format!("At most {} revocations per call", MAX_APPROVALS),
+        }))];
+    }
+    let caller = msg_caller();
+    args.into_iter().map(|arg| Some(revoke_token_approval(caller, arg))).collect()
+}
+
+fn revoke_collection_approval(caller: Principal, arg: RevokeCollectionApprovalArg) -> RevokeCollectionApprovalResult {
+    if memo_too_long(&arg.memo) {
+        return Err(RevokeCollectionApprovalError::GenericError { error_code: generic_error(ERROR_MEMO_TOO_LONG), message: "Memo is too long".to_string() });
+    }
+    match check_created_at_time(arg.created_at_time) {
+        Err(TimeError::TooOld) => return Err(RevokeCollectionApprovalError::TooOld),
+        Err(TimeError::CreatedInFuture(ledger_time)) => return Err(RevokeCollectionApprovalError::CreatedInFuture { ledger_time }),
+        Ok(()) => {}
+    }
+
+    let owner = Account { owner: caller, subaccount: arg.from_subaccount };
+    let keys = match arg.spender {
+        Some(spender) => vec![CollectionApprovalKey {
+            owner: caller,
+            owner_subaccount: resolve(arg.from_subaccount),
+            spender: spender.owner,
+            spender_subaccount: resolve(spender.subaccount),
+        }],
+        None => collection_approval_keys(&owner),
+    };
+    let removed = COLLECTION_APPROVALS.with_borrow_mut(|approvals| {
+        keys.iter().filter(|key| approvals.remove(key).is_some()).count()
+    });
+    if removed == 0 {
+        return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist);
+    }
+    Ok(next_tx_id())
+}
+
+#[ic_cdk::update]
+fn icrc37_revoke_collection_approvals(args: Vec<RevokeCollectionApprovalArg>) -> Vec<Option<RevokeCollectionApprovalResult>> {
+    if args.len() > MAX_APPROVALS {
+        return vec![Some(Err(RevokeCollectionApprovalError::GenericBatchError {
+            error_code: generic_error(ERROR_BATCH_TOO_LARGE),
+            message: format!("At most {} revocations per call", MAX_APPROVALS),
+        }))];
+    }
+    let caller = msg_caller();
+    args.into_iter().map(|arg| Some(revoke_collection_approval(caller, arg))).collect()
+}
+
+#[ic_cdk::query]
+fn icrc37_is_approved(args: Vec<IsApprovedArg>) -> Vec<bool> {
+    args.iter()
+        .take(MAX_QUERY_BATCH_SIZE)
+        .map(|arg| {
+            let token = to_token_id(&arg.token_id).and_then(|token_id| TOKENS.with_borrow(|tokens| tokens.get(&token_id)).map(|token| (token_id, token)));
+            match token {
+                Some((token_id, token)) if resolve(token.owner.subaccount) == resolve(arg.from_subaccount) => {
+                    is_spender_approved(token_id, &token.owner, &arg.spender)
+                }
+                _ => false,
+            }
+        })
+        .collect()
+}
+
+#[ic_cdk::query]
+fn icrc37_get_token_approvals(token_id: Nat, prev: Option<TokenApproval>, take: Option<Nat>) -> Vec<TokenApproval> {
+    let Some(token_id) = to_token_id(&token_id) else {
+        return Vec::new();
+    };
+    let prev_key = prev.map(|prev| TokenApprovalKey {
+        token_id,
+        spender: prev.approval_info.spender.owner,
+        spender_subaccount: resolve(prev.approval_info.spender.subaccount),
+    });
+    let start = prev_key.clone().unwrap_or(TokenApprovalKey {
+        token_id,
+        spender: Principal::management_canister(),
+        spender_subaccount: DEFAULT_SUBACCOUNT,
+    });
+    TOKEN_APPROVALS.with_borrow(|approvals| {
+        approvals
+            .range(start..)
+            .take_while(|entry| entry.key().token_id == token_id)
+            .filter(|entry| prev_key.as_ref() != Some(entry.key()))
+            .take(take_value(take))
+            .map(|entry| TokenApproval { token_id: Nat::from(token_id), approval_info: entry.value() })
+            .collect()
+    })
+}
+
+#[ic_cdk::query]
+fn icrc37_get_collection_approvals(owner: Account, prev: Option<ApprovalInfo>, take: Option<Nat>) -> Vec<ApprovalInfo> {
+    let owner_subaccount = resolve(owner.subaccount);
+    let prev_key = prev.map(|prev| CollectionApprovalKey {
+        owner: owner.owner,
+        owner_subaccount,
+        spender: prev.spender.owner,
+        spender_subaccount: resolve(prev.spender.subaccount),
+    });
+    let start = prev_key.clone().unwrap_or(CollectionApprovalKey {
+        owner: owner.owner,
+        owner_subaccount,
+        spender: Principal::management_canister(),
+        spender_subaccount: DEFAULT_SUBACCOUNT,
+    });
+    COLLECTION_APPROVALS.with_borrow(|approvals| {
+        approvals
+            .range(start..)
+            .take_while(|entry| entry.key().owner == owner.owner && entry.key().owner_subaccount == owner_subaccount)
+            .filter(|entry| prev_key.as_ref() != Some(entry.key()))
+            .take(take_value(take))
+            .map(|entry| entry.value())
+            .collect()
+    })
+}
+
+/// Transfer tokens on behalf of their owners. The note of every token moves to the recipient's principal with it.
+#[ic_cdk::update]
+async fn icrc37_transfer_from(args: Vec<TransferFromArg>) -> Vec<Option<TransferFromResult>> {
+    if args.len() > MAX_UPDATE_BATCH_SIZE {
+        return vec![Some(Err(TransferFromError::GenericBatchError {
+            error_code: generic_error(ERROR_BATCH_TOO_LARGE),
+            message: format!("At most {} transfers per call", MAX_UPDATE_BATCH_SIZE),
+        }))];
+    }
+    let caller = msg_caller();
+    let mut results = Vec::with_capacity(args.len());
+    for arg in args {
+        results.push(Some(transfer_token_from(caller, arg).await));
+    }
+    results
+}
+
+ic_cdk::export_candid!();
@@ -0,0 +1,389 @@
+use std::borrow::Cow;
+
+use candid::{CandidType, Decode, Encode, Int, Nat, Principal};
+use dotane_types::NoteNftMetadata;
+use ic_stable_structures::Storable;
+use icrc_ledger_types::icrc1::account::{Account, Subaccount};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+// Keys are written by hand rather than with candid, which encodes integers little-endian and may
+// reorder record fields. Integers are big-endian and principals length-prefixed, so an owner's or
+// a token's entries are stored together.
+fn push_principal(bytes: &mut Vec<u8>, principal: &Principal) {
+    let slice = principal.as_slice();
+    bytes.push(slice.len() as u8);
+    bytes.extend_from_slice(slice);
+}
+
+fn read_principal(bytes: &[u8]) -> (Principal, &[u8]) {
+    let len = bytes[0] as usize;
+    (Principal::from_slice(&bytes[1..1 + len]), &bytes[1 + len..])
+}
+
+fn read_subaccount(bytes: &[u8]) -> (Subaccount, &[u8]) {
+    let (value, rest) = bytes.split_at(32);
+    (value.try_into().unwrap(), rest)
+}
+
+fn read_u64(bytes: &[u8]) -> (u64, &[u8]) {
+    let (value, rest) = bytes.split_at(8);
+    (u64::from_be_bytes(value.try_into().unwrap()), rest)
+}
+
+// ICRC-3 value, used for collection and token metadata
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Value {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(ByteBuf),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct Token {
+    pub owner: Account,
+    pub metadata: NoteNftMetadata,
+}
+
+impl Storable for Token {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, Token).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+// Subaccounts are stored resolved, so None and the default subaccount are the same owner
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OwnerTokenKey {
+    pub owner: Principal,
+    pub subaccount: Subaccount,
+    pub token_id: u64,
+}
+
+impl Storable for OwnerTokenKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut data = Vec::new();
+        push_principal(&mut data, &self.owner);
+        data.extend_from_slice(&self.subaccount);
+        data.extend_from_slice(&self.token_id.to_be_bytes());
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (owner, rest) = read_principal(&bytes);
+        let (subaccount, rest) = read_subaccount(rest);
+        let (token_id, _) = read_u64(rest);
+        OwnerTokenKey { owner, subaccount, token_id }
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ApprovalInfo {
+    pub spender: Account,
+    pub from_subaccount: Option<Subaccount>,
+    pub expires_at: Option<u64>,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: u64,
+}
+
+impl Storable for ApprovalInfo {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let data = Encode!(&self).unwrap();
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(&bytes, ApprovalInfo).unwrap()
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TokenApprovalKey {
+    pub token_id: u64,
+    pub spender: Principal,
+    pub spender_subaccount: Subaccount,
+}
+
+impl Storable for TokenApprovalKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.token_id.to_be_bytes());
+        push_principal(&mut data, &self.spender);
+        data.extend_from_slice(&self.spender_subaccount);
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (token_id, rest) = read_u64(&bytes);
+        let (spender, rest) = read_principal(rest);
+        let (spender_subaccount, _) = read_subaccount(rest);
+        TokenApprovalKey { token_id, spender, spender_subaccount }
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CollectionApprovalKey {
+    pub owner: Principal,
+    pub owner_subaccount: Subaccount,
+    pub spender: Principal,
+    pub spender_subaccount: Subaccount,
+}
+
+impl Storable for CollectionApprovalKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut data = Vec::new();
+        push_principal(&mut data, &self.owner);
+        data.extend_from_slice(&self.owner_subaccount);
+        push_principal(&mut data, &self.spender);
+        data.extend_from_slice(&self.spender_subaccount);
+        Cow::Owned(data)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (owner, rest) = read_principal(&bytes);
+        let (owner_subaccount, rest) = read_subaccount(rest);
+        let (spender, rest) = read_principal(rest);
+        let (spender_subaccount, _) = read_subaccount(rest);
+        CollectionApprovalKey { owner, owner_subaccount, spender, spender_subaccount }
+    }
+
+    const BOUND: ic_stable_structures::storable::Bound = ic_stable_structures::storable::Bound::Unbounded;
+
+    fn into_bytes(self) -> Vec<u8> {
+        self.to_bytes().into_owned()
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StandardRecord {
+    pub name: String,
+    pub url: String,
+}
+
+// ICRC-7
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferError {
+    NonExistingTokenId,
+    InvalidRecipient,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type TransferResult = Result<Nat, TransferError>;
+
+// ICRC-37
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveTokenArg {
+    pub token_id: Nat,
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ApproveTokenError {
+    InvalidSpender,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type ApproveTokenResult = Result<Nat, ApproveTokenError>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveCollectionArg {
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum ApproveCollectionError {
+    InvalidSpender,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type ApproveCollectionResult = Result<Nat, ApproveCollectionError>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RevokeTokenApprovalArg {
+    // None revokes every spender
+    pub spender: Option<Account>,
+    pub from_subaccount: Option<Subaccount>,
+    pub token_id: Nat,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum RevokeTokenApprovalError {
+    ApprovalDoesNotExist,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type RevokeTokenApprovalResult = Result<Nat, RevokeTokenApprovalError>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RevokeCollectionApprovalArg {
+    // None revokes every spender
+    pub spender: Option<Account>,
+    pub from_subaccount: Option<Subaccount>,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum RevokeCollectionApprovalError {
+    ApprovalDoesNotExist,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type RevokeCollectionApprovalResult = Result<Nat, RevokeCollectionApprovalError>;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct IsApprovedArg {
+    pub spender: Account,
+    pub from_subaccount: Option<Subaccount>,
+    pub token_id: Nat,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TokenApproval {
+    pub token_id: Nat,
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TransferFromArg {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub token_id: Nat,
+    pub memo: Option<ByteBuf>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum TransferFromError {
+    InvalidRecipient,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    GenericError { error_code: Nat, message: String },
+    GenericBatchError { error_code: Nat, message: String },
+}
+
+pub type TransferFromResult = Result<Nat, TransferFromError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principal(byte: u8) -> Principal {
+        Principal::from_slice(&[byte; 29])
+    }
+
+    // Every key must round-trip and its stored bytes must sort like the keys themselves
+    fn assert_byte_order<K: Storable + Ord + Clone + std::fmt::Debug>(mut keys: Vec<K>) {
+        keys.sort();
+        for key in &keys {
+            assert_eq!(&K::from_bytes(key.to_bytes()), key);
+        }
+        let mut encoded: Vec<Vec<u8>> = keys.iter().map(|key| key.to_bytes().into_owned()).collect();
+        encoded.sort();
+        let decoded: Vec<K> = encoded.into_iter().map(|bytes| K::from_bytes(Cow::Owned(bytes))).collect();
+        assert_eq!(decoded, keys);
+    }
+
+    #[test]
+    fn owner_token_keys_group_by_account() {
+        let mut keys = Vec::new();
+        for owner in [principal(1), principal(2)] {
+            for subaccount in [[0; 32], [1; 32]] {
+                for token_id in [1u64, 255, 256, 70_000] {
+                    keys.push(OwnerTokenKey { owner, subaccount, token_id });
+                }
+            }
+        }
+        assert_byte_order(keys);
+    }
+
+    #[test]
+    fn approval_keys_group_by_token_and_owner() {
+        let mut token_keys = Vec::new();
+        let mut collection_keys = Vec::new();
+        for id in [1u8, 2] {
+            for spender in [principal(3), principal(4)] {
+                for token_id in [1u64, 256] {
+                    token_keys.push(TokenApprovalKey { token_id, spender, spender_subaccount: [id; 32] });
+                }
+                collection_keys.push(CollectionApprovalKey {
+                    owner: principal(id),
+                    owner_subaccount: [0; 32],
+                    spender,
+                    spender_subaccount: [id; 32],
+                });
+            }
+        }
+        assert_byte_order(token_keys);
+        assert_byte_order(collection_keys);
+    }
+}
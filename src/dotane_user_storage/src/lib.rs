@@ -1,8 +1,8 @@
-use std::cell::RefCell;
+use std::{cell::RefCell, ops::Bound, thread::LocalKey};
 
 use candid::{CandidType, Principal};
 use canister_http_router::{CallType, CanisterRouter, CanisterRouterContext, HttpRequest, HttpResponse};
-use dotane_types::{AccessType, ListNotesResponse, Note, PublishedNote, UserProfile};
+use dotane_types::{AccessType, ListNotesPage, ListNotesRequest, ListNotesResponse, Note, NoteSortField, NoteSummary, NoteTimeKey, NoteVisibility, PublishedNote, SortOrder, UserProfile};
 use handlebars::Handlebars;
 // use ic_asset_server::{export_canister_methods, upload_assets::logic::StableState};
 use ic_cdk::{api::{self, canister_self, time}, export_candid, init, management_canister::{self, CanisterInfoArgs}, post_upgrade};
@@ -52,6 +52,29 @@ thread_local! {
 
     static STABLE_ASSET_STATE: RefCell<StableCell<StableState, Memory>> = RefCell::new(StableCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(4))), StableState::default()));
 
+    // Contents of /.well-known/ic-domains, one custom domain per line
+    static IC_DOMAINS: RefCell<StableCell<String, Memory>> = RefCell::new(StableCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(5))), String::new()));
+
+    // Metadata of every note, so pages are listed without loading bodies
+    static NOTE_LISTINGS: RefCell<StableBTreeMap<String, NoteSummary, Memory>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(6))),
+        )
+    );
+
+    // The same notes in updated_at and created_at order
+    static NOTES_BY_UPDATED: RefCell<StableBTreeSet<NoteTimeKey, Memory>> = RefCell::new(
+        StableBTreeSet::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(7))),
+        )
+    );
+
+    static NOTES_BY_CREATED: RefCell<StableBTreeSet<NoteTimeKey, Memory>> = RefCell::new(
+        StableBTreeSet::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+        )
+    );
+
     // static STABLE_ASSET_STATE: RefCell<StableCell<StableState, Memory>> = RefCell::new(StableCell::new(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(3))), StableState::default()).unwrap());
 }
 
@@ -64,6 +87,7 @@ fn init() {
 
 #[post_upgrade]
 fn post_upgrade() {
+  list_existing_notes();
   init();
 }
 
@@ -114,13 +138,15 @@ fn save_note(title: String, content: String) -> Result<(), String> {
     created_at: get_current_time_in_milli(),
     updated_at: get_current_time_in_milli(),
     author: ic_cdk::api::msg_caller().to_string(),
+    tags: None,
   };
   NOTES.with_borrow_mut(|notes| {
     notes.insert(note.id.clone(), note);
   });
   PRIVATE_NOTES.with_borrow_mut(|private_notes| {
-    private_notes.insert(note_id);
+    private_notes.insert(note_id.clone());
   });
+  reindex_note(&note_id);
   Ok(())
 }
 
@@ -154,6 +180,84 @@ fn list_notes() -> ListNotesResponse {
     }
 }
 
+// Paginated, metadata-first variant of list_notes
+#[ic_cdk::query(guard = "is_controller")]
+fn list_notes_page(request: ListNotesRequest) -> Result<ListNotesPage, String> {
+    let order = request.sort_order();
+    let bounds = match request.cursor_position()? {
+        None => (Bound::Unbounded, Bound::Unbounded),
+        Some((timestamp, note_id)) => {
+            let after = NoteTimeKey { timestamp, note_id };
+            match order {
+                SortOrder::Asc => (Bound::Excluded(after), Bound::Unbounded),
+                SortOrder::Desc => (Bound::Unbounded, Bound::Excluded(after)),
+            }
+        }
+    };
+    let published = PUBLISHED_NOTES.with_borrow(|published_notes| published_notes.len());
+    let total = match request.visibility {
+        Some(NoteVisibility::Published) => published,
+        Some(NoteVisibility::Private) => NOTES.with_borrow(|notes| notes.len()).saturating_sub(published),
+        None => NOTES.with_borrow(|notes| notes.len()),
+    };
+
+    let page = note_order(request.sort_field()).with_borrow(|notes_in_order| {
+        let keys = notes_in_order.range(bounds);
+        let keys: Box<dyn Iterator<Item = NoteTimeKey> + '_> = match order {
+            SortOrder::Asc => Box::new(keys),
+            SortOrder::Desc => Box::new(keys.rev()),
+        };
+        let summaries = keys.filter_map(|key| NOTE_LISTINGS.with_borrow(|listings| listings.get(&key.note_id)));
+        request.page(summaries, total, |summary| NOTES.with_borrow(|notes| notes.get(&summary.id)).map(|note| note.content))
+    });
+    Ok(page)
+}
+
+fn note_order(sort_by: NoteSortField) -> &'static LocalKey<RefCell<StableBTreeSet<NoteTimeKey, Memory>>> {
+    match sort_by {
+        NoteSortField::UpdatedAt => &NOTES_BY_UPDATED,
+        NoteSortField::CreatedAt => &NOTES_BY_CREATED,
+    }
+}
+
+// Bring a note's listing in line with what is stored, dropping it once the note is gone
+fn reindex_note(note_id: &String) {
+    let current = NOTES.with_borrow(|notes| notes.get(note_id)).map(|note| {
+        let published = PUBLISHED_NOTES.with_borrow(|published_notes| published_notes.contains_key(note_id));
+        NoteSummary::new(&note, published)
+    });
+    let previous = NOTE_LISTINGS.with_borrow_mut(|listings| match &current {
+        Some(summary) => listings.insert(note_id.clone(), summary.clone()),
+        None => listings.remove(note_id),
+    });
+    for sort_by in [NoteSortField::UpdatedAt, NoteSortField::CreatedAt] {
+        note_order(sort_by).with_borrow_mut(|notes_in_order| {
+            if let Some(previous) = &previous {
+                notes_in_order.remove(&NoteTimeKey { timestamp: previous.sort_timestamp(sort_by), note_id: note_id.clone() });
+            }
+            if let Some(current) = &current {
+                notes_in_order.insert(NoteTimeKey { timestamp: current.sort_timestamp(sort_by), note_id: note_id.clone() });
+            }
+        });
+    }
+}
+
+// List notes stored before the listings existed. A no-op once any note is listed.
+fn list_existing_notes() {
+    if !NOTE_LISTINGS.with_borrow(|listings| listings.is_empty()) {
+        return;
+    }
+    let note_ids: Vec<String> = NOTES.with_borrow(|notes| notes.iter().map(|entry| entry.key().clone()).collect());
+    for note_id in note_ids {
+        reindex_note(&note_id);
+    }
+}
+
+#[ic_cdk::query(guard = "is_controller")]
+fn get_note(note_id: String) -> Result<Note, String> {
+  NOTES.with_borrow(|notes| notes.get(&note_id)).ok_or("Note not found".to_string())
+}
+
 #[ic_cdk::update(guard = "is_controller")]
 fn publish_saved_note(note_id: String, access_type: AccessType) -> Result<(), String> {
   // check if it exists
@@ -183,8 +287,9 @@ fn publish_saved_note(note_id: String, access_type: AccessType) -> Result<(), St
       storage_canister: Some(ic_cdk::api::canister_self().to_text()),
     };
     PUBLISHED_NOTES.with_borrow_mut(|published_notes| {
-      published_notes.insert(note_id, p_note);
+      published_notes.insert(note_id.clone(), p_note);
     });
+    reindex_note(&note_id);
   } else {
     return Err("Note not found".to_string());
   }
@@ -204,6 +309,7 @@ fn publish_note(title: String, content: String, access_type: AccessType) -> Resu
         created_at: current_time,
         updated_at: current_time,
         author: caller.to_string(),
+        tags: None,
     };
 
     PUBLISHED_NOTES.with_borrow_mut(|published| {
@@ -221,6 +327,7 @@ fn publish_note(title: String, content: String, access_type: AccessType) -> Resu
     NOTES.with_borrow_mut(|notes| {
         notes.insert(note_id.clone(), note.clone());
     });
+    reindex_note(&note_id);
 
     Ok(note)
 }
@@ -238,7 +345,7 @@ fn unpublish_note(note_id: String) -> Result<(), String> {
                 
                 
                 PRIVATE_NOTES.with_borrow_mut(|private_notes| {
-                    private_notes.insert(note_id);
+                    private_notes.insert(note_id.clone());
                 });
 
                 Ok(())
@@ -248,7 +355,10 @@ fn unpublish_note(note_id: String) -> Result<(), String> {
         } else {
             Err("Note not found".to_string())
         }
-    })
+    })?;
+    // the listing reads PUBLISHED_NOTES, so it can only be refreshed once it is released
+    reindex_note(&note_id);
+    Ok(())
 }
 
 #[ic_cdk::update(guard = "is_controller")]
@@ -259,6 +369,7 @@ fn delete_saved_note(note_id: String) -> Result<Note, String> {
     });
 
     let note = NOTES.with(|notes| notes.borrow_mut().remove(&note_id));
+    reindex_note(&note_id);
     if let Some(note) = note {
         Ok(note)
     } else {
@@ -294,6 +405,7 @@ fn update_note(
             published.insert(note_id.clone(), published_note);
         }
     });
+    reindex_note(&note_id);
     Ok(())
 }
 
@@ -349,9 +461,33 @@ fn note_handler(cntx: CanisterRouterContext) -> HttpResponse {
   })
 }
 
+fn ic_domains_handler(_cntx: CanisterRouterContext) -> HttpResponse {
+  let domains = IC_DOMAINS.with_borrow(|domains| domains.get().clone());
+  if domains.is_empty() {
+    return HttpResponse::builder()
+      .set_status(404)
+      .build();
+  }
+
+  HttpResponse::builder()
+    .set_body(ByteBuf::from(domains.as_bytes()))
+    .set_headers(vec![("Content-Type".to_string(), "text/plain".to_string()), ("Access-Control-Allow-Origin".to_string(), "*".to_string())])
+    .build()
+}
+
+// Called by dotane_ic_backend whenever a custom domain is attached to or detached from this workspace
+#[ic_cdk::update(guard = "is_controller")]
+fn set_ic_domains(domains: Vec<String>) -> Result<(), String> {
+  IC_DOMAINS.with_borrow_mut(|ic_domains| {
+    ic_domains.set(domains.join("\n"));
+  });
+  Ok(())
+}
+
 fn setup_http_router() {
   ROUTER.with_borrow_mut(|router| {
     router.get("/", home_handler);
+    router.get("/.well-known/ic-domains", ic_domains_handler);
     router.post("/{note_id}", note_handler);
   });
 }
@@ -14,7 +14,7 @@ use crate::{
         CallbackFunc, HttpRequest, HttpResponse, StreamingCallbackHttpResponse,
         StreamingCallbackToken,
     },
-    state_machine::{init_asset_cache, AssetDetails, CertifiedTree, EncodedAsset, State, VMemory},
+    state_machine::{content_keys, init_asset_cache, AssetDetails, CertifiedTree, EncodedAsset, State, VMemory},
     types::*,
 };
 use asset_certification::types::{certification::AssetKey, rc_bytes::RcBytes};
@@ -39,6 +39,15 @@ pub fn authorize(other: Principal) {
     STATE.with(|s| s.borrow_mut().grant_permission(other, &Permission::Commit))
 }
 
+/// Caps the content bytes `other` may store, None lifts the cap.
+pub fn set_byte_quota(other: Principal, max_bytes: Option<u64>) {
+    STATE.with(|s| s.borrow_mut().set_byte_quota(other, max_bytes))
+}
+
+pub fn bytes_used(other: Principal) -> u64 {
+    STATE.with(|s| s.borrow().bytes_used(&other))
+}
+
 pub fn grant_permission(arg: GrantPermissionArguments) {
     STATE.with(|s| {
         s.borrow_mut()
@@ -119,10 +128,14 @@ pub fn retrieve(key: AssetKey) -> RcBytes {
 
 pub fn store(arg: StoreArg) {
     debug_print(&format!("storing asset"));
+    let key = arg.key.clone();
     STATE.with(move |s| {
         if let Err(msg) = s.borrow_mut().store(arg, time()) {
             trap(&msg);
         }
+        if let Err(msg) = s.borrow_mut().claim_content(msg_caller(), &[key]) {
+            trap(&msg);
+        }
         certified_data_set(&s.borrow().root_hash());
     });
 }
@@ -151,10 +164,14 @@ pub fn create_asset(arg: CreateAssetArguments) {
 }
 
 pub fn set_asset_content(arg: SetAssetContentArguments) {
+    let key = arg.key.clone();
     STATE.with(|s| {
         if let Err(msg) = s.borrow_mut().set_asset_content(arg, time()) {
             trap(&msg);
         }
+        if let Err(msg) = s.borrow_mut().claim_content(msg_caller(), &[key]) {
+            trap(&msg);
+        }
         certified_data_set(&s.borrow().root_hash());
     })
 }
@@ -183,10 +200,14 @@ pub fn clear() {
 }
 
 pub fn commit_batch(arg: CommitBatchArguments) {
+    let keys = content_keys(&arg);
     STATE.with(|s| {
         if let Err(msg) = s.borrow_mut().commit_batch(arg, time()) {
             trap(&msg);
         }
+        if let Err(msg) = s.borrow_mut().claim_content(msg_caller(), &keys) {
+            trap(&msg);
+        }
         certified_data_set(&s.borrow().root_hash());
     });
 }
@@ -208,9 +229,13 @@ pub fn compute_evidence(arg: ComputeEvidenceArguments) -> Option<ByteBuf> {
 
 pub fn commit_proposed_batch(arg: CommitProposedBatchArguments) {
     STATE.with(|s| {
+        let keys = s.borrow().proposed_content_keys(&arg.batch_id);
         if let Err(msg) = s.borrow_mut().commit_proposed_batch(arg, time()) {
             trap(&msg);
         }
+        if let Err(msg) = s.borrow_mut().claim_content(msg_caller(), &keys) {
+            trap(&msg);
+        }
         certified_data_set(&s.borrow().root_hash());
     });
 }
@@ -517,6 +542,18 @@ macro_rules! export_canister_methods {
             $crate::authorize(other)
         }
 
+        #[ic_cdk::update(guard = "__ic_certified_assets_is_manager_or_controller")]
+        #[candid::candid_method(update)]
+        fn set_byte_quota(other: candid::Principal, max_bytes: Option<u64>) {
+            $crate::set_byte_quota(other, max_bytes)
+        }
+
+        #[ic_cdk::query]
+        #[candid::candid_method(query)]
+        fn bytes_used(other: candid::Principal) -> u64 {
+            $crate::bytes_used(other)
+        }
+
         #[ic_cdk::update(guard = "__ic_certified_assets_is_manager_or_controller")]
         #[candid::candid_method(update)]
         fn grant_permission(arg: server_types::GrantPermissionArguments) {
@@ -32,7 +32,7 @@ use num_traits::ToPrimitive;
 use serde::Serialize;
 use serde_bytes::ByteBuf;
 use sha2::Digest;
-use std::{borrow::Cow, collections::{BTreeSet, HashMap}, cell::RefCell};
+use std::{borrow::Cow, collections::{BTreeMap, BTreeSet, HashMap}, cell::RefCell};
 use std::convert::TryInto;
 
 /// The amount of time a batch is kept alive. Modifying the batch
@@ -233,6 +233,9 @@ pub struct Asset {
     pub headers: Option<HashMap<String, String>>,
     pub is_aliased: Option<bool>,
     pub allow_raw_access: Option<bool>,
+    // the principal that last wrote content to this asset, counted against its byte quota
+    #[serde(default)]
+    pub owner: Option<Principal>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize)]
@@ -300,6 +303,9 @@ pub struct State {
     prepare_principals: BTreeSet<Principal>,
     manage_permissions_principals: BTreeSet<Principal>,
 
+    // principals without a quota may store any number of bytes
+    byte_quotas: BTreeMap<Principal, u64>,
+
     asset_hashes: CertifiedResponses,
 }
 
@@ -318,6 +324,7 @@ pub struct StableState {
 
     next_batch_id: Option<BatchId>,
     configuration: Option<Configuration>,
+    byte_quotas: Option<BTreeMap<Principal, u64>>,
 }
 
 impl Asset {
@@ -420,6 +427,52 @@ impl State {
         self.commit_principals.insert(controller);
     }
 
+    pub fn set_byte_quota(&mut self, principal: Principal, max_bytes: Option<u64>) {
+        match max_bytes {
+            Some(max_bytes) => {
+                self.byte_quotas.insert(principal, max_bytes);
+            }
+            None => {
+                self.byte_quotas.remove(&principal);
+            }
+        }
+    }
+
+    /// Content bytes, across all encodings, of the assets `principal` last wrote.
+    pub fn bytes_used(&self, principal: &Principal) -> u64 {
+        self.assets
+            .values()
+            .filter(|asset| asset.owner.as_ref() == Some(principal))
+            .flat_map(|asset| asset.encodings.values())
+            .map(|encoding| encoding.total_length as u64)
+            .sum()
+    }
+
+    /// Records `writer` as the owner of `keys` after their content was written, then checks that
+    /// `writer` is still within its byte quota. The canister traps on Err, which undoes the write.
+    pub fn claim_content(&mut self, writer: Principal, keys: &[AssetKey]) -> Result<(), String> {
+        for key in keys {
+            if let Some(asset) = self.assets.get_mut(key) {
+                asset.owner = Some(writer);
+            }
+        }
+        match self.byte_quotas.get(&writer) {
+            Some(max_bytes) if self.bytes_used(&writer) > *max_bytes => {
+                Err(format!("byte quota of {} exceeded", max_bytes))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Keys whose content the proposed commit of `batch_id` would set.
+    pub fn proposed_content_keys(&self, batch_id: &BatchId) -> Vec<AssetKey> {
+        self.batches
+            .get(batch_id)
+            .and_then(|batch| batch.commit_batch_arguments.as_ref())
+            .map(content_keys)
+            .unwrap_or_default()
+    }
+
     pub fn root_hash(&self) -> Hash {
         self.asset_hashes.root_hash()
     }
@@ -437,6 +490,7 @@ impl State {
                 headers: arg.headers,
                 is_aliased: arg.enable_aliasing,
                 allow_raw_access: arg.allow_raw_access,
+                owner: None,
             },
         );
         Ok(())
@@ -1238,6 +1292,7 @@ impl From<State> for StableState {
             stable_assets: state.assets,
             next_batch_id: Some(state.next_batch_id),
             configuration: Some(state.configuration),
+            byte_quotas: Some(state.byte_quotas),
         }
     }
 }
@@ -1267,6 +1322,7 @@ impl From<StableState> for State {
                 .next_batch_id
                 .unwrap_or_else(|| Nat::from(1_u8)),
             configuration: stable_state.configuration.unwrap_or_default(),
+            byte_quotas: stable_state.byte_quotas.unwrap_or_default(),
             ..Self::default()
         };
 
@@ -1315,6 +1371,17 @@ impl Storable for StableState {
         self.to_bytes().into_owned()
     }
 }
+/// Keys whose content `arg` sets.
+pub fn content_keys(arg: &CommitBatchArguments) -> Vec<AssetKey> {
+    arg.operations
+        .iter()
+        .filter_map(|op| match op {
+            BatchOperation::SetAssetContent(arg) => Some(arg.key.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
 fn build_headers(
     custom_headers: Option<impl Iterator<Item = (impl Into<String>, impl Into<String>)>>,
     max_age: &Option<u64>,
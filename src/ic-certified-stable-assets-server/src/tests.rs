@@ -940,6 +940,44 @@ fn preserves_state_on_stable_roundtrip() {
     assert_eq!(response.body.as_ref(), INDEX_BODY);
 }
 
+#[test]
+fn byte_quotas_count_content_written_by_each_principal() {
+    let mut state = State::default();
+    let time_now = 100_000_000_000;
+    let writer = some_principal();
+    let other = Principal::from_text("rdmx6-jaaaa-aaaaa-aaadq-cai").unwrap();
+
+    create_assets(
+        &mut state,
+        time_now,
+        vec![
+            AssetBuilder::new("/a.txt", "text/plain").with_encoding("identity", vec![[0u8; 60]]),
+            AssetBuilder::new("/b.txt", "text/plain").with_encoding("identity", vec![[0u8; 30]]),
+        ],
+    );
+    state.set_byte_quota(writer, Some(100));
+
+    assert!(state.claim_content(writer, &["/a.txt".to_string()]).is_ok());
+    assert_eq!(state.bytes_used(&writer), 60);
+    assert!(state.claim_content(other, &["/b.txt".to_string()]).is_ok());
+    assert_eq!(state.bytes_used(&writer), 60);
+    assert!(state.claim_content(writer, &["/b.txt".to_string()]).is_ok());
+    assert_eq!(state.bytes_used(&writer), 90);
+    assert_eq!(state.bytes_used(&other), 0);
+
+    state.set_byte_quota(writer, Some(80));
+    assert!(state.claim_content(writer, &[]).is_err());
+    state.set_byte_quota(writer, None);
+    assert!(state.claim_content(writer, &[]).is_ok());
+
+    // quotas and owners survive an upgrade
+    state.set_byte_quota(writer, Some(80));
+    let stable_state: StableState = state.into();
+    let mut state: State = stable_state.into();
+    assert_eq!(state.bytes_used(&writer), 90);
+    assert!(state.claim_content(writer, &[]).is_err());
+}
+
 #[test]
 fn uses_streaming_for_multichunk_assets() {
     let mut state = State::default();